@@ -0,0 +1,65 @@
+extern crate flate2;
+
+use std::collections::hash_map::DefaultHasher;
+use std::env;
+use std::fs::{self, File};
+use std::hash::Hasher;
+use std::io::{Read, Write};
+use std::path::{Path, PathBuf};
+
+use flate2::Compression;
+use flate2::write::GzEncoder;
+
+fn collect_files(dir: &Path, files: &mut Vec<PathBuf>) {
+    let mut entries: Vec<PathBuf> = fs::read_dir(dir).unwrap()
+                                                     .map(|e| e.unwrap().path())
+                                                     .collect();
+    entries.sort();
+
+    for path in entries {
+        if path.is_dir() {
+            collect_files(&path, files);
+        } else {
+            files.push(path);
+        }
+    }
+}
+
+fn main() {
+    let static_dir = Path::new(&env::var("CARGO_MANIFEST_DIR").unwrap()).join("static");
+    let out_dir = PathBuf::from(env::var("OUT_DIR").unwrap());
+
+    println!("cargo:rerun-if-changed={}", static_dir.display());
+
+    let mut files = Vec::new();
+    collect_files(&static_dir, &mut files);
+
+    let mut table = String::from("pub static ASSETS: &'static [Asset] = &[\n");
+    for (idx, file) in files.iter().enumerate() {
+        println!("cargo:rerun-if-changed={}", file.display());
+
+        let mut contents = Vec::new();
+        File::open(file).unwrap().read_to_end(&mut contents).unwrap();
+
+        let mut hasher = DefaultHasher::new();
+        hasher.write(&contents);
+        let etag = format!("{:016x}", hasher.finish());
+
+        let gz_path = out_dir.join(format!("asset_{}.gz", idx));
+        let mut encoder = GzEncoder::new(Vec::new(), Compression::best());
+        encoder.write_all(&contents).unwrap();
+        File::create(&gz_path).unwrap().write_all(&encoder.finish().unwrap()).unwrap();
+
+        let url_path = file.strip_prefix(&static_dir).unwrap()
+                           .components()
+                           .map(|c| c.as_os_str().to_string_lossy().into_owned())
+                           .collect::<Vec<String>>()
+                           .join("/");
+
+        table.push_str(&format!("    Asset {{ path: {:?}, etag: {:?}, raw: include_bytes!({:?}), gzip: include_bytes!({:?}) }},\n",
+                                format!("/{}", url_path), etag, file.display().to_string(), gz_path.display().to_string()));
+    }
+    table.push_str("];\n");
+
+    File::create(out_dir.join("assets.rs")).unwrap().write_all(table.as_bytes()).unwrap();
+}
@@ -0,0 +1,297 @@
+extern crate hyper;
+use self::hyper::client::Client;
+use self::hyper::header::ContentType;
+
+use std::cmp;
+use std::collections::{BTreeMap, HashSet};
+use std::sync::mpsc::{channel, Receiver, RecvTimeoutError, Sender};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use serde_json;
+
+use message::{MessageType, NetworkMsg};
+use sink::MessageSink;
+
+const POLL_INTERVAL_SECS: u64 = 1;
+const MAX_BACKOFF_SECS:   u64 = 600;
+
+#[derive(Debug, Clone)]
+pub struct AirCastingConfig {
+    pub url:     String,
+    pub retries: u32
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct Measurement {
+    pub host:     String,
+    pub datetime: String,
+    pub value:    String
+}
+
+#[derive(Debug, Serialize)]
+struct Upload<'a> {
+    session_uuid:     &'a str,
+    sensor_name:      &'static str,
+    measurement_type: &'static str,
+    unit_symbol:      &'static str,
+    measurements:     &'a [Measurement]
+}
+
+fn is_success(http_code: &str) -> bool {
+    match http_code.trim().parse::<i32>() {
+        Ok(code) => code >= 200 && code < 300,
+        Err(_)   => false
+    }
+}
+
+/// Decides which readings the nodes failed to deliver to AirCasting themselves.
+/// The firmware logs the push result before the NTP line of the same cycle, whose
+/// `sent` flag tells whether that reading made it.
+#[derive(Debug, Default)]
+struct Backfill {
+    /// Hosts whose last push failed, for readings lacking a `sent` flag
+    failed: HashSet<String>
+}
+
+impl Backfill {
+    fn observe(&mut self, msg: &NetworkMsg) -> Option<(String, Measurement)> {
+        let vals = &msg.msg.mvals;
+        match msg.msg.mtype {
+            MessageType::Ntp => {
+                let (session, value) = match (vals.get("UUID"), vals.get("pm2.5")) {
+                    (Some(s), Some(v)) => (s.clone(), v.clone()),
+                    _                  => return None
+                };
+
+                let push_failed = self.failed.remove(&msg.host);
+                let unsent = match vals.get("sent") {
+                    Some(s) => s.trim() == "0",
+                    None    => push_failed
+                };
+                if !unsent {
+                    return None;
+                }
+
+                Some((session, Measurement {
+                    host:     msg.host.clone(),
+                    datetime: vals.get("datetime").cloned().unwrap_or_default(),
+                    value:    value
+                }))
+            },
+            MessageType::AirCasting => {
+                if vals.get("command").map(|c| c == "push").unwrap_or(false) {
+                    if vals.get("http_code").map(|c| is_success(c)).unwrap_or(true) {
+                        self.failed.remove(&msg.host);
+                    } else {
+                        self.failed.insert(msg.host.clone());
+                    }
+                }
+                None
+            },
+            _ => None
+        }
+    }
+}
+
+struct PendingSession {
+    measurements: Vec<Measurement>,
+    attempts:     u32,
+    next_attempt: Instant
+}
+
+fn backoff(attempts: u32) -> Duration {
+    Duration::from_secs(cmp::min(2u64.pow(cmp::min(attempts, 16)), MAX_BACKOFF_SECS))
+}
+
+fn upload(client: &Client, url: &str, session: &str, measurements: &[Measurement]) -> Result<(), String> {
+    let body = serde_json::to_string(&Upload {
+        session_uuid:     session,
+        sensor_name:      "PMS3003",
+        measurement_type: "Particulate Matter",
+        unit_symbol:      "µg/m³",
+        measurements:     measurements
+    }).map_err(|e| e.to_string())?;
+
+    match client.post(url).header(ContentType::json()).body(body.as_str()).send() {
+        Ok(ref res) if res.status.is_success() => Ok(()),
+        Ok(res)  => Err(format!("{} answered {}", url, res.status)),
+        Err(err) => Err(format!("{}: {}", url, err))
+    }
+}
+
+fn th_aircasting_uploader(config: AirCastingConfig, rx: Receiver<(String, Measurement)>) {
+    info!("AirCasting uploader thread started: {}", config.url);
+
+    let client = Client::new();
+    let mut queue: BTreeMap<String, PendingSession> = BTreeMap::new();
+
+    loop {
+        match rx.recv_timeout(Duration::from_secs(POLL_INTERVAL_SECS)) {
+            Ok((session, measurement)) => {
+                info!("Queueing AirCasting measurement for session {}: {:?}", session, measurement);
+                queue.entry(session).or_insert(PendingSession {
+                    measurements: Vec::new(),
+                    attempts:     0,
+                    next_attempt: Instant::now()
+                }).measurements.push(measurement);
+            },
+            Err(RecvTimeoutError::Timeout)      => {},
+            Err(RecvTimeoutError::Disconnected) => break
+        }
+
+        let now = Instant::now();
+        let due: Vec<String> = queue.iter()
+                                    .filter(|&(_, p)| p.next_attempt <= now)
+                                    .map(|(s, _)| s.clone())
+                                    .collect();
+
+        for session in due {
+            let done = {
+                let pending = queue.get_mut(&session).unwrap();
+                match upload(&client, &config.url, &session, &pending.measurements) {
+                    Ok(_) => {
+                        info!("Uploaded {} measurements for session {}", pending.measurements.len(), session);
+                        true
+                    },
+                    Err(err) => {
+                        pending.attempts += 1;
+                        if pending.attempts > config.retries {
+                            error!("Giving up on {} measurements for session {} after {} attempts: {}",
+                                   pending.measurements.len(), session, pending.attempts, err);
+                            true
+                        } else {
+                            let delay = backoff(pending.attempts);
+                            warn!("AirCasting upload for session {} failed: {}, retrying in {:?}", session, err, delay);
+                            pending.next_attempt = Instant::now() + delay;
+                            false
+                        }
+                    }
+                }
+            };
+
+            if done {
+                queue.remove(&session);
+            }
+        }
+    }
+}
+
+/// Sink re-uploading readings the nodes failed to push to AirCasting
+pub struct AirCastingSink {
+    backfill: Backfill,
+    tx:       Sender<(String, Measurement)>
+}
+
+impl AirCastingSink {
+    pub fn new(config: AirCastingConfig) -> AirCastingSink {
+        let (tx, rx) = channel();
+
+        let _ = thread::Builder::new().name("AirCastingUploader".to_string()).spawn(move || {
+            th_aircasting_uploader(config, rx);
+        });
+
+        AirCastingSink {
+            backfill: Backfill::default(),
+            tx:       tx
+        }
+    }
+}
+
+impl MessageSink for AirCastingSink {
+    fn name(&self) -> &str {
+        "aircasting"
+    }
+
+    fn handle(&mut self, msg: &NetworkMsg) {
+        if let Some(item) = self.backfill.observe(msg) {
+            if let Err(err) = self.tx.send(item) {
+                error!("AirCasting uploader is gone: {:?}", err);
+            }
+        }
+    }
+}
+
+#[test]
+fn test_backfill() {
+    use message::parse_from_string;
+
+    let mut b = Backfill::default();
+
+    // Push result first, then the reading it was about, as the firmware logs them
+    let ok = parse_from_string(String::from("ESP_D427A9: [11.06000] AC:push: Code 200"));
+    assert_eq!(b.observe(&ok), None);
+    let sent = parse_from_string(String::from("ESP_D427A9: [11.06500] NTP: 2017-05-26T15:27:53.000+01:00 PM2.5: 12 UUID:d687fe3f-2d30-352d-0c21-ff3f2cea2040 sent:1"));
+    assert_eq!(b.observe(&sent), None);
+
+    let failed = parse_from_string(String::from("ESP_D427A9: [11.06000] AC:push: Code 500"));
+    assert_eq!(b.observe(&failed), None);
+    let unsent = parse_from_string(String::from("ESP_D427A9: [11.06500] NTP: 2017-05-26T15:32:53.000+01:00 PM2.5: 15 UUID:d687fe3f-2d30-352d-0c21-ff3f2cea2040 sent:0"));
+    let (session, m) = b.observe(&unsent).unwrap();
+    assert_eq!(session, "d687fe3f-2d30-352d-0c21-ff3f2cea2040");
+    assert_eq!(m, Measurement {
+        host:     String::from("ESP_D427A9"),
+        datetime: String::from("2017-05-26T15:32:53.000+01:00"),
+        value:    String::from("15")
+    });
+
+    // Without a sent flag, the failure reported just before decides
+    assert_eq!(b.observe(&failed), None);
+    let (_, m) = b.observe(&parse_from_string(String::from("ESP_D427A9: [11.06500] NTP: 2017-05-26T15:37:53.000+01:00 PM2.5: 17 UUID:d687fe3f-2d30-352d-0c21-ff3f2cea2040"))).unwrap();
+    assert_eq!(m.value, "17");
+    assert_eq!(b.observe(&parse_from_string(String::from("ESP_D427A9: [11.06500] NTP: 2017-05-26T15:42:53.000+01:00 PM2.5: 18 UUID:d687fe3f-2d30-352d-0c21-ff3f2cea2040"))), None);
+
+    let other_host = parse_from_string(String::from("ESP_000000: [11.06000] AC:push: Code -1"));
+    assert_eq!(b.observe(&other_host), None);
+}
+
+#[test]
+fn test_is_success() {
+    assert!(is_success("200"));
+    assert!(is_success("204"));
+    assert!(!is_success("302"));
+    assert!(!is_success("500"));
+    assert!(!is_success("-1"));
+    assert!(!is_success("garbage"));
+}
+
+#[test]
+fn test_upload_to_mock() {
+    use std::io::{BufRead, BufReader, Read, Write};
+    use std::net::TcpListener;
+
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let url = format!("http://{}/api/measurements.json", listener.local_addr().unwrap());
+
+    let mock = thread::spawn(move || {
+        let (stream, _) = listener.accept().unwrap();
+        let mut reader = BufReader::new(stream);
+        let mut content_length = 0;
+        loop {
+            let mut header = String::new();
+            reader.read_line(&mut header).unwrap();
+            if header.trim().is_empty() {
+                break;
+            }
+            if header.to_lowercase().starts_with("content-length:") {
+                content_length = header[15..].trim().parse::<usize>().unwrap();
+            }
+        }
+
+        let mut body = vec![0; content_length];
+        reader.read_exact(&mut body).unwrap();
+        reader.get_mut().write_all(b"HTTP/1.1 200 OK\r\nContent-Length: 0\r\nConnection: close\r\n\r\n").unwrap();
+        String::from_utf8(body).unwrap()
+    });
+
+    let m = Measurement {
+        host:     String::from("ESP_D427A9"),
+        datetime: String::from("2017-05-26T15:27:53.000+01:00"),
+        value:    String::from("12")
+    };
+    assert_eq!(upload(&Client::new(), &url, "abc", &[m]), Ok(()));
+
+    let body = mock.join().unwrap();
+    assert!(body.contains("\"session_uuid\":\"abc\""));
+    assert!(body.contains("\"value\":\"12\""));
+}
@@ -0,0 +1,633 @@
+extern crate hyper;
+use self::hyper::client::Client;
+use self::hyper::header::ContentType;
+
+use std::collections::{BTreeMap, HashMap};
+use std::fs::File;
+use std::io;
+use std::io::{BufRead, BufReader, Read, Write};
+use std::net::TcpStream;
+use std::path::Path;
+use std::sync::{Arc, Mutex};
+use std::sync::mpsc::{channel, Receiver, Sender};
+use std::thread;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use serde_json;
+
+use message::{MessageType, NetworkMsg};
+use metrics::SharedMetrics;
+use sink::MessageSink;
+
+/// How often rules are re-evaluated without any message, e.g. to notice overdue nodes
+const TICK_INTERVAL_SECS: u64 = 10;
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Condition {
+    /// Last PM2.5 reading above the threshold
+    Pm25Above(f64),
+    /// No message for this many seconds past the announced deepSleep wake-up
+    Overdue(u64),
+    /// At least this many consecutive failed AirCasting pushes
+    PushFailures(u32),
+    /// At least this many NTP errors reported while waiting for the initial sync
+    NtpErrors(u32)
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Rule {
+    pub name:      String,
+    pub condition: Condition,
+    /// Seconds the condition has to hold before the alert fires
+    pub hold:      u64,
+    expr:          String
+}
+
+fn parse_duration(s: &str) -> Option<u64> {
+    let (digits, unit) = match s.char_indices().find(|&(_, c)| !c.is_digit(10)) {
+        Some((idx, _)) => (&s[..idx], &s[idx..]),
+        None           => (s, "s")
+    };
+
+    let factor = match unit {
+        "s" => 1,
+        "m" => 60,
+        "h" => 3600,
+        _   => return None
+    };
+
+    digits.parse::<u64>().ok().map(|v| v * factor)
+}
+
+impl Rule {
+    /// Parses `name: condition [for DURATION]`, where condition is one of
+    /// `pm2.5 > X`, `overdue GRACE`, `push-failures N` or `ntp-errors N`
+    pub fn parse(line: &str) -> Result<Rule, String> {
+        let colon = line.find(':').ok_or(format!("missing rule name in {:?}", line))?;
+        let name = line[..colon].trim();
+        let expr = line[colon + 1..].trim();
+        let words: Vec<&str> = expr.split_whitespace().collect();
+
+        if words.len() < 2 {
+            return Err(format!("unknown condition {:?}", expr));
+        }
+
+        let (cond, rest) = match (words[0], words.len() > 2 && words[1] == ">") {
+            ("pm2.5", true)          => (words[2].parse::<f64>().map(Condition::Pm25Above).map_err(|e| e.to_string()), &words[3..]),
+            ("overdue", false)       => (parse_duration(words[1]).map(Condition::Overdue).ok_or(format!("bad duration {:?}", words[1])), &words[2..]),
+            ("push-failures", false) => (words[1].parse::<u32>().map(Condition::PushFailures).map_err(|e| e.to_string()), &words[2..]),
+            ("ntp-errors", false)    => (words[1].parse::<u32>().map(Condition::NtpErrors).map_err(|e| e.to_string()), &words[2..]),
+            _                        => return Err(format!("unknown condition {:?}", expr))
+        };
+        let cond = cond.map_err(|e| format!("{}: {}", name, e))?;
+
+        let hold = match rest.len() {
+            0                     => 0,
+            2 if rest[0] == "for" => parse_duration(rest[1]).ok_or(format!("{}: bad duration {:?}", name, rest[1]))?,
+            _                     => return Err(format!("{}: trailing {:?}", name, rest.join(" ")))
+        };
+
+        if name.is_empty() {
+            return Err(format!("missing rule name in {:?}", line));
+        }
+
+        Ok(Rule {
+            name:      String::from(name),
+            condition: cond,
+            hold:      hold,
+            expr:      String::from(expr)
+        })
+    }
+
+    /// Loads one rule per line, `#` starting a comment
+    pub fn load(path: &Path) -> Result<Vec<Rule>, String> {
+        let mut contents = String::new();
+        File::open(path).and_then(|mut f| f.read_to_string(&mut contents))
+                        .map_err(|e| format!("{:?}: {}", path, e))?;
+
+        contents.lines()
+                .enumerate()
+                .map(|(lineno, l)| (lineno, l.trim()))
+                .filter(|&(_, l)| !l.is_empty() && !l.starts_with('#'))
+                .map(|(lineno, l)| Rule::parse(l).map_err(|e| format!("{:?} line {}: {}", path, lineno + 1, e)))
+                .collect()
+    }
+}
+
+/// What the engine remembers of each node, taken from the parsed messages
+#[derive(Debug, Default)]
+struct HostState {
+    pm25:          Option<f64>,
+    next_wake:     Option<u64>,
+    push_failures: u32,
+    ntp_errors:    u32
+}
+
+impl Condition {
+    fn holds(&self, state: &HostState, now: u64) -> bool {
+        match *self {
+            Condition::Pm25Above(x)    => state.pm25.map(|v| v > x).unwrap_or(false),
+            Condition::Overdue(grace)  => state.next_wake.map(|w| now > w + grace).unwrap_or(false),
+            Condition::PushFailures(n) => state.push_failures >= n,
+            Condition::NtpErrors(n)    => state.ntp_errors >= n
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize)]
+pub enum AlertState {
+    Firing,
+    Resolved
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct Alert {
+    pub rule:        String,
+    pub host:        String,
+    pub state:       AlertState,
+    /// When the alert started firing, seconds since the epoch
+    pub since:       u64,
+    pub description: String
+}
+
+/// Evaluates rules against every node, reporting each alert once when it fires and once when it resolves
+#[derive(Debug)]
+pub struct AlertEngine {
+    rules:   Vec<Rule>,
+    hosts:   BTreeMap<String, HostState>,
+    pending: HashMap<(usize, String), u64>,
+    firing:  BTreeMap<(usize, String), Alert>
+}
+
+impl AlertEngine {
+    pub fn new(rules: Vec<Rule>) -> AlertEngine {
+        AlertEngine {
+            rules:   rules,
+            hosts:   BTreeMap::new(),
+            pending: HashMap::new(),
+            firing:  BTreeMap::new()
+        }
+    }
+
+    pub fn observe(&mut self, msg: &NetworkMsg, now: u64) -> Vec<Alert> {
+        {
+            let state = self.hosts.entry(msg.host.clone()).or_insert_with(HostState::default);
+            let vals = &msg.msg.mvals;
+
+            // Any message means the node is awake again
+            state.next_wake = None;
+
+            match msg.msg.mtype {
+                MessageType::Ntp => {
+                    if let Some(v) = vals.get("pm2.5").and_then(|v| v.parse::<f64>().ok()).filter(|v| v.is_finite()) {
+                        state.pm25 = Some(v);
+                    }
+                },
+                MessageType::Loop => {
+                    match vals.get("action").map(|a| a.as_str()) {
+                        Some("deepsleep") => {
+                            if let Some(d) = vals.get("deepsleepduration").and_then(|d| d.parse::<f64>().ok()).filter(|d| d.is_finite()) {
+                                state.next_wake = Some(now + d.ceil() as u64);
+                            }
+                        },
+                        Some("waitntp") => {
+                            if let Some(n) = vals.get("ntperrors").and_then(|n| n.parse::<u32>().ok()) {
+                                state.ntp_errors = n;
+                            }
+                        },
+                        _ => {}
+                    }
+                },
+                MessageType::NtpSync => {
+                    if vals.contains_key("ntpdate") {
+                        state.ntp_errors = 0;
+                    }
+                },
+                MessageType::AirCasting => {
+                    if vals.get("command").map(|c| c == "push").unwrap_or(false) {
+                        let ok = vals.get("http_code")
+                                     .and_then(|c| c.trim().parse::<i32>().ok())
+                                     .map(|c| c >= 200 && c < 300)
+                                     .unwrap_or(false);
+                        state.push_failures = if ok { 0 } else { state.push_failures + 1 };
+                    }
+                },
+                _ => {}
+            }
+        }
+
+        let host = msg.host.clone();
+        self.evaluate(&host, now)
+    }
+
+    pub fn tick(&mut self, now: u64) -> Vec<Alert> {
+        let hosts: Vec<String> = self.hosts.keys().cloned().collect();
+        hosts.iter().flat_map(|h| self.evaluate(h, now)).collect()
+    }
+
+    pub fn firing(&self) -> Vec<Alert> {
+        self.firing.values().cloned().collect()
+    }
+
+    fn evaluate(&mut self, host: &str, now: u64) -> Vec<Alert> {
+        let mut transitions = Vec::new();
+        let state = match self.hosts.get(host) {
+            Some(state) => state,
+            None        => return transitions
+        };
+
+        for (idx, rule) in self.rules.iter().enumerate() {
+            let key = (idx, String::from(host));
+
+            if rule.condition.holds(state, now) {
+                let since = *self.pending.entry(key.clone()).or_insert(now);
+                // Wall clock time may step backwards, leaving `since` in the future
+                if now.saturating_sub(since) >= rule.hold && !self.firing.contains_key(&key) {
+                    let alert = Alert {
+                        rule:        rule.name.clone(),
+                        host:        String::from(host),
+                        state:       AlertState::Firing,
+                        since:       now,
+                        description: format!("{} on {}", rule.expr, host)
+                    };
+                    self.firing.insert(key, alert.clone());
+                    transitions.push(alert);
+                }
+            } else {
+                self.pending.remove(&key);
+                if let Some(mut alert) = self.firing.remove(&key) {
+                    alert.state = AlertState::Resolved;
+                    transitions.push(alert);
+                }
+            }
+        }
+
+        transitions
+    }
+}
+
+pub type SharedAlerts = Arc<Mutex<AlertEngine>>;
+
+/// Delivery channel for alert state changes
+pub trait Notifier: Send {
+    fn name(&self) -> &str;
+    fn notify(&mut self, alert: &Alert) -> Result<(), String>;
+}
+
+fn subject(alert: &Alert) -> String {
+    let state = match alert.state {
+        AlertState::Firing   => "FIRING",
+        AlertState::Resolved => "RESOLVED"
+    };
+    format!("[sensorweb] {} {} on {}", state, alert.rule, alert.host)
+}
+
+pub struct StdoutNotifier;
+
+impl Notifier for StdoutNotifier {
+    fn name(&self) -> &str {
+        "stdout"
+    }
+
+    fn notify(&mut self, alert: &Alert) -> Result<(), String> {
+        println!("{}: {}", subject(alert), alert.description);
+        Ok(())
+    }
+}
+
+pub struct WebhookNotifier {
+    url:    String,
+    client: Client
+}
+
+impl WebhookNotifier {
+    pub fn new(url: String) -> WebhookNotifier {
+        WebhookNotifier {
+            url:    url,
+            client: Client::new()
+        }
+    }
+}
+
+impl Notifier for WebhookNotifier {
+    fn name(&self) -> &str {
+        "webhook"
+    }
+
+    fn notify(&mut self, alert: &Alert) -> Result<(), String> {
+        let body = serde_json::to_string(alert).map_err(|e| e.to_string())?;
+        match self.client.post(&self.url).header(ContentType::json()).body(body.as_str()).send() {
+            Ok(ref res) if res.status.is_success() => Ok(()),
+            Ok(res)  => Err(format!("{} answered {}", self.url, res.status)),
+            Err(err) => Err(format!("{}: {}", self.url, err))
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct SmtpConfig {
+    pub server: String,
+    pub from:   String,
+    pub to:     Vec<String>
+}
+
+/// Reads one, possibly multi-line, SMTP reply and checks its code
+fn smtp_expect<R: BufRead>(reader: &mut R, code: &str) -> Result<(), String> {
+    loop {
+        let mut line = String::new();
+        match reader.read_line(&mut line) {
+            Ok(0)    => return Err(String::from("connection closed")),
+            Ok(_)    => {},
+            Err(err) => return Err(err.to_string())
+        }
+
+        if !line.starts_with(code) {
+            return Err(format!("expected {}, got {:?}", code, line.trim()));
+        }
+        if line.as_bytes().get(3) != Some(&b'-') {
+            return Ok(());
+        }
+    }
+}
+
+fn smtp_command(stream: &mut TcpStream, reader: &mut BufReader<TcpStream>, cmd: &str, code: &str) -> Result<(), String> {
+    stream.write_all(format!("{}\r\n", cmd).as_bytes()).map_err(|e| e.to_string())?;
+    smtp_expect(reader, code).map_err(|e| format!("{}: {}", cmd, e))
+}
+
+/// Sends a plain text mail through a local relay, without authentication nor TLS
+pub fn send_mail(config: &SmtpConfig, subject: &str, body: &str) -> Result<(), String> {
+    let mut stream = TcpStream::connect(config.server.as_str()).map_err(|e| e.to_string())?;
+    let _ = stream.set_read_timeout(Some(Duration::from_secs(30)));
+    let mut reader = BufReader::new(stream.try_clone().map_err(|e: io::Error| e.to_string())?);
+
+    smtp_expect(&mut reader, "220")?;
+    smtp_command(&mut stream, &mut reader, "HELO sensorweb", "250")?;
+    smtp_command(&mut stream, &mut reader, &format!("MAIL FROM:<{}>", config.from), "250")?;
+    for to in config.to.iter() {
+        smtp_command(&mut stream, &mut reader, &format!("RCPT TO:<{}>", to), "250")?;
+    }
+    smtp_command(&mut stream, &mut reader, "DATA", "354")?;
+
+    // Lines starting with a dot are escaped by doubling it
+    let body: Vec<String> = body.lines()
+                                .map(|l| if l.starts_with('.') { format!(".{}", l) } else { String::from(l) })
+                                .collect();
+    let message = format!("From: <{}>\r\nTo: {}\r\nSubject: {}\r\n\r\n{}\r\n.",
+                          config.from,
+                          config.to.iter().map(|t| format!("<{}>", t)).collect::<Vec<_>>().join(", "),
+                          subject,
+                          body.join("\r\n"));
+    smtp_command(&mut stream, &mut reader, &message, "250")?;
+    smtp_command(&mut stream, &mut reader, "QUIT", "221")
+}
+
+pub struct SmtpNotifier {
+    config: SmtpConfig
+}
+
+impl SmtpNotifier {
+    pub fn new(config: SmtpConfig) -> SmtpNotifier {
+        SmtpNotifier {
+            config: config
+        }
+    }
+}
+
+impl Notifier for SmtpNotifier {
+    fn name(&self) -> &str {
+        "smtp"
+    }
+
+    fn notify(&mut self, alert: &Alert) -> Result<(), String> {
+        let body = format!("{}\n\nRule: {}\nHost: {}\nSince: {}\n", alert.description, alert.rule, alert.host, alert.since);
+        send_mail(&self.config, &subject(alert), &body)
+    }
+}
+
+fn th_alert_notifier(rx: Receiver<Alert>, mut notifiers: Vec<Box<Notifier>>) {
+    info!("Alert notifier thread started");
+
+    for alert in rx.iter() {
+        for notifier in notifiers.iter_mut() {
+            if let Err(err) = notifier.notify(&alert) {
+                error!("Unable to notify {} through {}: {}", subject(&alert), notifier.name(), err);
+            }
+        }
+    }
+}
+
+fn now() -> u64 {
+    SystemTime::now().duration_since(UNIX_EPOCH).map(|d| d.as_secs()).unwrap_or(0)
+}
+
+/// Hands state changes to the notifiers and refreshes the per-rule firing gauge
+fn dispatch(engine: &AlertEngine, transitions: Vec<Alert>, tx: &Sender<Alert>, metrics: &SharedMetrics) {
+    if transitions.is_empty() {
+        return;
+    }
+
+    for rule in engine.rules.iter() {
+        let count = engine.firing.values().filter(|a| a.rule == rule.name).count();
+        metrics.gauge("sensorweb_alerts_firing", "Alerts currently firing", &[("rule", rule.name.as_str())], count as f64);
+    }
+
+    for alert in transitions {
+        info!("{}", subject(&alert));
+        if let Err(err) = tx.send(alert) {
+            error!("Alert notifier is gone: {:?}", err);
+        }
+    }
+}
+
+fn th_alert_ticker(engine: SharedAlerts, tx: Sender<Alert>, metrics: SharedMetrics) {
+    loop {
+        thread::sleep(Duration::from_secs(TICK_INTERVAL_SECS));
+        let mut engine = engine.lock().unwrap();
+        let transitions = engine.tick(now());
+        dispatch(&engine, transitions, &tx, &metrics);
+    }
+}
+
+/// Sink feeding the alert engine, which also gets re-evaluated periodically
+pub struct AlertSink {
+    engine:  SharedAlerts,
+    tx:      Sender<Alert>,
+    metrics: SharedMetrics
+}
+
+impl AlertSink {
+    pub fn new(rules: Vec<Rule>, notifiers: Vec<Box<Notifier>>, metrics: SharedMetrics) -> AlertSink {
+        let engine = Arc::new(Mutex::new(AlertEngine::new(rules)));
+        let (tx, rx) = channel();
+
+        let _ = thread::Builder::new().name("AlertNotifier".to_string()).spawn(move || {
+            th_alert_notifier(rx, notifiers);
+        });
+
+        let (engine_ticker, tx_ticker, metrics_ticker) = (engine.clone(), tx.clone(), metrics.clone());
+        let _ = thread::Builder::new().name("AlertTicker".to_string()).spawn(move || {
+            th_alert_ticker(engine_ticker, tx_ticker, metrics_ticker);
+        });
+
+        AlertSink {
+            engine:  engine,
+            tx:      tx,
+            metrics: metrics
+        }
+    }
+
+    pub fn engine(&self) -> SharedAlerts {
+        self.engine.clone()
+    }
+}
+
+impl MessageSink for AlertSink {
+    fn name(&self) -> &str {
+        "alert"
+    }
+
+    fn handle(&mut self, msg: &NetworkMsg) {
+        let mut engine = self.engine.lock().unwrap();
+        let transitions = engine.observe(msg, now());
+        dispatch(&engine, transitions, &self.tx, &self.metrics);
+    }
+}
+
+#[test]
+fn test_parse_rules() {
+    assert_eq!(parse_duration("90"), Some(90));
+    assert_eq!(parse_duration("10m"), Some(600));
+    assert_eq!(parse_duration("2h"), Some(7200));
+    assert_eq!(parse_duration("2d"), None);
+
+    let r = Rule::parse("high-pm25: pm2.5 > 35.5 for 10m").unwrap();
+    assert_eq!((r.name.as_str(), r.condition.clone(), r.hold), ("high-pm25", Condition::Pm25Above(35.5), 600));
+    assert_eq!(Rule::parse("late: overdue 2m").unwrap().condition, Condition::Overdue(120));
+    assert_eq!(Rule::parse("ac: push-failures 3").unwrap().condition, Condition::PushFailures(3));
+    assert_eq!(Rule::parse("ntp: ntp-errors 5 for 30s").unwrap().hold, 30);
+
+    assert!(Rule::parse("pm2.5 > 35").is_err());
+    assert!(Rule::parse("x: pm2.5 > abc").is_err());
+    assert!(Rule::parse("x: pm2.5 > 35 during 10m").is_err());
+    assert!(Rule::parse("x: humidity > 80").is_err());
+}
+
+#[test]
+fn test_engine() {
+    use message::parse_from_string;
+
+    let rules = vec![
+        Rule::parse("high-pm25: pm2.5 > 35 for 10m").unwrap(),
+        Rule::parse("late: overdue 60").unwrap(),
+        Rule::parse("ac: push-failures 2").unwrap(),
+        Rule::parse("ntp: ntp-errors 3").unwrap()
+    ];
+    let mut e = AlertEngine::new(rules);
+
+    let high = parse_from_string(String::from("ESP_1: [11.06500] NTP: 2017-05-26T15:27:53.000+01:00 PM2.5: 50 UUID:abc sent:1"));
+    let low = parse_from_string(String::from("ESP_1: [11.06500] NTP: 2017-05-26T15:32:53.000+01:00 PM2.5: 5 UUID:abc sent:1"));
+
+    // Has to stay high for ten minutes, and is only reported once
+    assert!(e.observe(&high, 1000).is_empty());
+    assert!(e.observe(&high, 1300).is_empty());
+    let fired = e.observe(&high, 1600);
+    assert_eq!(fired.len(), 1);
+    assert_eq!((fired[0].rule.as_str(), fired[0].state), ("high-pm25", AlertState::Firing));
+    assert!(e.observe(&high, 1900).is_empty());
+    assert_eq!(e.firing().len(), 1);
+
+    let resolved = e.observe(&low, 2200);
+    assert_eq!(resolved.len(), 1);
+    assert_eq!((resolved[0].state, resolved[0].since), (AlertState::Resolved, 1600));
+    assert!(e.firing().is_empty());
+
+    // Overdue only fires from the ticker, once the announced wake-up is past the grace period
+    let sleep = parse_from_string(String::from("ESP_1: [11.15300] Loop: deepSleep: nextInterval=288.93; executionTime=11.07 ; slowDownFactor=1.04365; deepSleep(301.54)"));
+    assert!(e.observe(&sleep, 3000).is_empty());
+    assert!(e.tick(3300).is_empty());
+    assert_eq!(e.tick(3400)[0].rule, "late");
+    assert_eq!(e.observe(&low, 3410)[0].state, AlertState::Resolved);
+
+    let failed = parse_from_string(String::from("ESP_1: [11.06000] AC:push: Code -1"));
+    let ok = parse_from_string(String::from("ESP_1: [11.06000] AC:push: Code 200"));
+    assert!(e.observe(&failed, 4000).is_empty());
+    assert_eq!(e.observe(&failed, 4300)[0].rule, "ac");
+    assert_eq!(e.observe(&ok, 4600)[0].state, AlertState::Resolved);
+
+    let waiting = parse_from_string(String::from("ESP_2: [4.07500] Loop: no NTP initial sync, waiting ... sleepWakeCycles=1 ntpErrors=3"));
+    let synced = parse_from_string(String::from("ESP_2: [7.97900] NTPSyncEvent: 16:24:59 30/05/2017"));
+    assert_eq!(e.observe(&waiting, 5000)[0].host, "ESP_2");
+    assert_eq!(e.observe(&synced, 5010)[0].state, AlertState::Resolved);
+}
+
+#[test]
+fn test_engine_clock_step() {
+    use message::parse_from_string;
+
+    let mut e = AlertEngine::new(vec![Rule::parse("high-pm25: pm2.5 > 35 for 10m").unwrap()]);
+    let high = parse_from_string(String::from("ESP_1: [11.06500] NTP: 2017-05-26T15:27:53.000+01:00 PM2.5: 50 UUID:abc sent:1"));
+
+    assert!(e.observe(&high, 1000).is_empty());
+    assert!(e.observe(&high, 400).is_empty());
+    assert!(e.observe(&high, 1300).is_empty());
+    assert_eq!(e.observe(&high, 1600).len(), 1);
+}
+
+#[test]
+fn test_send_mail() {
+    use std::net::TcpListener;
+
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let config = SmtpConfig {
+        server: listener.local_addr().unwrap().to_string(),
+        from:   String::from("sensorweb@localhost"),
+        to:     vec![String::from("ops@example.org")]
+    };
+
+    let relay = thread::spawn(move || {
+        let (mut stream, _) = listener.accept().unwrap();
+        let mut reader = BufReader::new(stream.try_clone().unwrap());
+        let mut transcript = Vec::new();
+        let mut in_data = false;
+
+        stream.write_all(b"220 localhost ESMTP\r\n").unwrap();
+        loop {
+            let mut line = String::new();
+            if reader.read_line(&mut line).unwrap() == 0 {
+                break;
+            }
+            let line = String::from(line.trim_end());
+
+            let reply: &[u8] = if in_data {
+                if line == "." {
+                    in_data = false;
+                    b"250 queued\r\n"
+                } else {
+                    b""
+                }
+            } else if line == "DATA" {
+                in_data = true;
+                b"354 go ahead\r\n"
+            } else if line == "QUIT" {
+                b"221 bye\r\n"
+            } else {
+                b"250-localhost\r\n250 OK\r\n"
+            };
+
+            stream.write_all(reply).unwrap();
+            transcript.push(line);
+        }
+        transcript
+    });
+
+    assert_eq!(send_mail(&config, "[sensorweb] FIRING x on ESP_1", "first\n.dot"), Ok(()));
+
+    let transcript = relay.join().unwrap();
+    assert_eq!(transcript[0], "HELO sensorweb");
+    assert_eq!(transcript[1], "MAIL FROM:<sensorweb@localhost>");
+    assert_eq!(transcript[2], "RCPT TO:<ops@example.org>");
+    assert!(transcript.contains(&String::from("Subject: [sensorweb] FIRING x on ESP_1")));
+    assert!(transcript.contains(&String::from("..dot")));
+    assert_eq!(transcript.last().unwrap(), "QUIT");
+}
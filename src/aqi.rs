@@ -0,0 +1,466 @@
+use std::collections::{BTreeMap, HashMap, VecDeque};
+use std::fs::File;
+use std::io::Read;
+use std::path::Path;
+use std::sync::{Arc, Mutex};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use message::{MessageType, NetworkMsg};
+use metrics::SharedMetrics;
+use sink::MessageEnricher;
+
+const HOUR: u64 = 3600;
+const DAY:  u64 = 24 * HOUR;
+
+/// Concentration average an index is defined over
+#[derive(Debug, Eq, PartialEq, Clone, Copy, Serialize)]
+pub enum Averaging {
+    NowCast,
+    Hourly,
+    Daily
+}
+
+#[derive(Debug, Clone, PartialEq)]
+struct Breakpoint {
+    c_lo:     f64,
+    c_hi:     f64,
+    i_lo:     f64,
+    i_hi:     f64,
+    colour:   String,
+    category: String
+}
+
+/// Piecewise linear PM2.5 to index conversion, as published by national agencies
+#[derive(Debug, Clone)]
+pub struct AqiTable {
+    pub name:      String,
+    pub averaging: Averaging,
+    breakpoints:   Vec<Breakpoint>
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct AqiValue {
+    pub index:    String,
+    pub value:    u32,
+    pub category: String,
+    pub colour:   String
+}
+
+fn bp(c_lo: f64, c_hi: f64, i_lo: f64, i_hi: f64, colour: &str, category: &str) -> Breakpoint {
+    Breakpoint {
+        c_lo:     c_lo,
+        c_hi:     c_hi,
+        i_lo:     i_lo,
+        i_hi:     i_hi,
+        colour:   String::from(colour),
+        category: String::from(category)
+    }
+}
+
+impl AqiTable {
+    /// US EPA AQI for PM2.5, 2024 revision, reported from the NowCast
+    pub fn us_epa() -> AqiTable {
+        AqiTable {
+            name:        String::from("us_epa"),
+            averaging:   Averaging::NowCast,
+            breakpoints: vec![
+                bp(0.0,   9.0,   0.0,   50.0,  "#00e400", "Good"),
+                bp(9.1,   35.4,  51.0,  100.0, "#ffff00", "Moderate"),
+                bp(35.5,  55.4,  101.0, 150.0, "#ff7e00", "Unhealthy for Sensitive Groups"),
+                bp(55.5,  125.4, 151.0, 200.0, "#ff0000", "Unhealthy"),
+                bp(125.5, 225.4, 201.0, 300.0, "#8f3f97", "Very Unhealthy"),
+                bp(225.5, 325.4, 301.0, 500.0, "#7e0023", "Hazardous"),
+            ]
+        }
+    }
+
+    /// European Common Air Quality Index, hourly PM2.5 grid
+    pub fn eu_caqi() -> AqiTable {
+        AqiTable {
+            name:        String::from("eu_caqi"),
+            averaging:   Averaging::Hourly,
+            breakpoints: vec![
+                bp(0.0,   15.0,  0.0,   25.0,  "#79bc6a", "Very low"),
+                bp(15.0,  30.0,  25.0,  50.0,  "#bbcf4c", "Low"),
+                bp(30.0,  55.0,  50.0,  75.0,  "#eec20b", "Medium"),
+                bp(55.0,  110.0, 75.0,  100.0, "#f29305", "High"),
+                bp(110.0, 220.0, 100.0, 200.0, "#e8416f", "Very high"),
+            ]
+        }
+    }
+
+    /// Reads a table such as:
+    ///
+    /// ```text
+    /// name = in_naqi
+    /// averaging = 24h
+    /// # c_lo c_hi i_lo i_hi colour category
+    /// 0 30 0 50 #009933 Good
+    /// ```
+    pub fn parse(s: &str) -> Result<AqiTable, String> {
+        let mut name = None;
+        let mut averaging = Averaging::Daily;
+        let mut breakpoints = Vec::new();
+
+        for (lineno, raw) in s.lines().enumerate() {
+            let line = raw.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+
+            if let Some(eq) = line.find('=') {
+                let value = line[eq + 1..].trim();
+                match line[..eq].trim() {
+                    "name"      => name = Some(String::from(value)),
+                    "averaging" => averaging = match value {
+                        "nowcast" => Averaging::NowCast,
+                        "1h"      => Averaging::Hourly,
+                        "24h"     => Averaging::Daily,
+                        _         => return Err(format!("line {}: unknown averaging {:?}", lineno + 1, value))
+                    },
+                    key         => return Err(format!("line {}: unknown key {:?}", lineno + 1, key))
+                }
+                continue;
+            }
+
+            let fields: Vec<&str> = line.splitn(6, char::is_whitespace).map(|f| f.trim()).collect();
+            if fields.len() != 6 {
+                return Err(format!("line {}: expected c_lo c_hi i_lo i_hi colour category", lineno + 1));
+            }
+
+            let mut nums = [0.0; 4];
+            for i in 0..4 {
+                nums[i] = fields[i].parse::<f64>().map_err(|e| format!("line {}: {}", lineno + 1, e))?;
+            }
+            breakpoints.push(bp(nums[0], nums[1], nums[2], nums[3], fields[4], fields[5]));
+        }
+
+        if breakpoints.is_empty() {
+            return Err(String::from("no breakpoints"));
+        }
+
+        Ok(AqiTable {
+            name:        name.ok_or(String::from("missing name"))?,
+            averaging:   averaging,
+            breakpoints: breakpoints
+        })
+    }
+
+    pub fn load(path: &Path) -> Result<AqiTable, String> {
+        let mut contents = String::new();
+        File::open(path).and_then(|mut f| f.read_to_string(&mut contents))
+                        .map_err(|e| format!("{:?}: {}", path, e))?;
+        AqiTable::parse(&contents).map_err(|e| format!("{:?}: {}", path, e))
+    }
+
+    pub fn index(&self, concentration: f64) -> Option<AqiValue> {
+        if concentration < 0.0 || concentration.is_nan() {
+            return None;
+        }
+
+        // Breakpoints are published with one decimal, values in the gaps belong to the lower band
+        let c = (concentration * 10.0).floor() / 10.0;
+        let b = self.breakpoints.iter()
+                                .find(|b| c <= b.c_hi)
+                                .unwrap_or(self.breakpoints.last().unwrap());
+        let c = if c < b.c_lo { b.c_lo } else { c };
+
+        // Past the last band the index keeps growing along its slope
+        let value = (b.i_hi - b.i_lo) / (b.c_hi - b.c_lo) * (c - b.c_lo) + b.i_lo;
+
+        Some(AqiValue {
+            index:    self.name.clone(),
+            value:    value.round() as u32,
+            category: b.category.clone(),
+            colour:   b.colour.clone()
+        })
+    }
+}
+
+/// Timestamped PM2.5 readings of one node over the last 24 hours
+#[derive(Debug, Default)]
+struct Pm25History {
+    readings: VecDeque<(u64, f64)>
+}
+
+impl Pm25History {
+    fn add(&mut self, ts: u64, value: f64) {
+        self.readings.push_back((ts, value));
+        while self.readings.front().map(|&(t, _)| t + DAY <= ts).unwrap_or(false) {
+            self.readings.pop_front();
+        }
+    }
+
+    /// Mean over (now - window, now]
+    fn mean(&self, now: u64, window: u64) -> Option<f64> {
+        let values: Vec<f64> = self.readings.iter()
+                                            .filter(|&&(t, _)| t + window > now && t <= now)
+                                            .map(|&(_, v)| v)
+                                            .collect();
+        if values.is_empty() {
+            None
+        } else {
+            Some(values.iter().sum::<f64>() / values.len() as f64)
+        }
+    }
+
+    /// EPA NowCast over the last 12 hourly means, most recent hour weighted highest
+    fn nowcast(&self, now: u64) -> Option<f64> {
+        let hourly: Vec<Option<f64>> = (0..12).map(|h| self.mean(now.saturating_sub(h * HOUR), HOUR)).collect();
+        if hourly[0..3].iter().filter(|h| h.is_some()).count() < 2 {
+            return None;
+        }
+
+        let available: Vec<f64> = hourly.iter().filter_map(|h| *h).collect();
+        let max = available.iter().cloned().fold(0.0, f64::max);
+        let min = available.iter().cloned().fold(f64::INFINITY, f64::min);
+        if max <= 0.0 {
+            return Some(0.0);
+        }
+
+        let w = (min / max).max(0.5);
+        let (mut num, mut den) = (0.0, 0.0);
+        for (i, h) in hourly.iter().enumerate() {
+            if let Some(c) = *h {
+                num += w.powi(i as i32) * c;
+                den += w.powi(i as i32);
+            }
+        }
+        Some(num / den)
+    }
+}
+
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct HostAqi {
+    pub updated:      u64,
+    pub pm25:         f64,
+    pub pm25_1h:      Option<f64>,
+    pub pm25_24h:     Option<f64>,
+    pub pm25_nowcast: Option<f64>,
+    /// Whether `pm25` was corrected for humidity before computing the indices
+    pub corrected:    bool,
+    pub indices:      Vec<AqiValue>
+}
+
+/// Latest air quality per host, shared with the HTTP API
+pub type SharedAqi = Arc<Mutex<BTreeMap<String, HostAqi>>>;
+
+/// Computes air quality indices from Ntp PM2.5 readings and annotates messages with them
+pub struct AqiEnricher {
+    tables:  Vec<AqiTable>,
+    history: HashMap<String, Pm25History>,
+    latest:  SharedAqi,
+    metrics: SharedMetrics
+}
+
+impl AqiEnricher {
+    pub fn new(tables: Vec<AqiTable>, metrics: SharedMetrics) -> AqiEnricher {
+        AqiEnricher {
+            tables:  tables,
+            history: HashMap::new(),
+            latest:  Arc::new(Mutex::new(BTreeMap::new())),
+            metrics: metrics
+        }
+    }
+
+    pub fn latest(&self) -> SharedAqi {
+        self.latest.clone()
+    }
+
+    fn compute(&mut self, host: &str, now: u64, pm25: f64, corrected: bool) -> HostAqi {
+        let history = self.history.entry(String::from(host)).or_insert(Pm25History::default());
+        history.add(now, pm25);
+
+        let mut rv = HostAqi {
+            updated:      now,
+            pm25:         pm25,
+            pm25_1h:      history.mean(now, HOUR),
+            pm25_24h:     history.mean(now, DAY),
+            pm25_nowcast: history.nowcast(now),
+            corrected:    corrected,
+            indices:      Vec::new()
+        };
+
+        for table in self.tables.iter() {
+            let concentration = match table.averaging {
+                Averaging::NowCast => rv.pm25_nowcast,
+                Averaging::Hourly  => rv.pm25_1h,
+                Averaging::Daily   => rv.pm25_24h
+            };
+
+            if let Some(value) = concentration.and_then(|c| table.index(c)) {
+                rv.indices.push(value);
+            }
+        }
+
+        rv
+    }
+
+    fn export(&self, host: &str, report: &HostAqi) {
+        self.metrics.gauge("sensorweb_pm25", "Last PM2.5 reading in µg/m³", &[("host", host)], report.pm25);
+
+        let averages = [("1h", report.pm25_1h), ("24h", report.pm25_24h), ("nowcast", report.pm25_nowcast)];
+        for &(window, value) in averages.iter() {
+            if let Some(v) = value {
+                self.metrics.gauge("sensorweb_pm25_average", "Rolling PM2.5 average in µg/m³",
+                                   &[("host", host), ("window", window)], v);
+            }
+        }
+
+        for value in report.indices.iter() {
+            self.metrics.gauge("sensorweb_aqi", "Air quality index computed from PM2.5",
+                               &[("host", host), ("index", value.index.as_str())], value.value as f64);
+        }
+    }
+}
+
+fn annotate(msg: &mut NetworkMsg, report: &HostAqi) {
+    let averages = [("pm2.5.1h", report.pm25_1h), ("pm2.5.24h", report.pm25_24h), ("pm2.5.nowcast", report.pm25_nowcast)];
+    for &(key, value) in averages.iter() {
+        if let Some(v) = value {
+            msg.annotations.insert(String::from(key), format!("{:.1}", v));
+        }
+    }
+
+    for value in report.indices.iter() {
+        msg.annotations.insert(format!("aqi.{}", value.index), value.value.to_string());
+        msg.annotations.insert(format!("aqi.{}.category", value.index), value.category.clone());
+        msg.annotations.insert(format!("aqi.{}.colour", value.index), value.colour.clone());
+    }
+}
+
+impl MessageEnricher for AqiEnricher {
+    fn name(&self) -> &str {
+        "aqi"
+    }
+
+    fn enrich(&mut self, msg: &mut NetworkMsg) {
+        if msg.msg.mtype != MessageType::Ntp {
+            return;
+        }
+
+        // Prefer the humidity corrected value when the environment enricher provided one
+        let corrected = msg.annotations.get("pm2.5.corrected").and_then(|v| v.parse::<f64>().ok());
+        let pm25 = match corrected.or_else(|| msg.msg.mvals.get("pm2.5").and_then(|v| v.parse::<f64>().ok())).filter(|v| v.is_finite()) {
+            Some(v) => v,
+            None    => return
+        };
+
+        let now = SystemTime::now().duration_since(UNIX_EPOCH).map(|d| d.as_secs()).unwrap_or(0);
+        let report = self.compute(&msg.host, now, pm25, corrected.is_some());
+        annotate(msg, &report);
+        self.export(&msg.host, &report);
+        self.latest.lock().unwrap().insert(msg.host.clone(), report);
+    }
+}
+
+#[test]
+fn test_us_epa() {
+    let t = AqiTable::us_epa();
+    let check = |c: f64, value: u32, category: &str| {
+        let v = t.index(c).unwrap();
+        assert_eq!((v.value, v.category.as_str()), (value, category));
+    };
+
+    check(0.0,   0,   "Good");
+    check(9.0,   50,  "Good");
+    check(9.05,  50,  "Good");
+    check(9.1,   51,  "Moderate");
+    check(35.4,  100, "Moderate");
+    check(35.5,  101, "Unhealthy for Sensitive Groups");
+    check(55.5,  151, "Unhealthy");
+    check(225.5, 301, "Hazardous");
+    check(325.4, 500, "Hazardous");
+    assert_eq!(t.index(-1.0), None);
+    assert_eq!(t.index(12.0).unwrap().colour, "#ffff00");
+}
+
+#[test]
+fn test_eu_caqi() {
+    let t = AqiTable::eu_caqi();
+    assert_eq!(t.index(7.5).unwrap().value, 13);
+    assert_eq!(t.index(15.0).unwrap().category, "Very low");
+    assert_eq!(t.index(42.5).unwrap().value, 63);
+    assert_eq!(t.index(110.0).unwrap().value, 100);
+    assert_eq!(t.index(330.0).unwrap().value, 300);
+    assert_eq!(t.index(330.0).unwrap().category, "Very high");
+}
+
+#[test]
+fn test_parse_table() {
+    let t = AqiTable::parse("# India NAQI\n\
+                             name = in_naqi\n\
+                             averaging = 24h\n\
+                             0 30 0 50 #009933 Good\n\
+                             31 60 51 100 #58ff09 Satisfactory\n").unwrap();
+    assert_eq!(t.name, "in_naqi");
+    assert_eq!(t.averaging, Averaging::Daily);
+    assert_eq!(t.index(45.5).unwrap(), AqiValue {
+        index:    String::from("in_naqi"),
+        value:    76,
+        category: String::from("Satisfactory"),
+        colour:   String::from("#58ff09")
+    });
+
+    assert!(AqiTable::parse("name = x\n").is_err());
+    assert!(AqiTable::parse("0 30 0 50 #009933 Good\n").is_err());
+    assert!(AqiTable::parse("name = x\naveraging = 8h\n0 30 0 50 #009933 Good\n").is_err());
+    assert!(AqiTable::parse("name = x\n0 thirty 0 50 #009933 Good\n").is_err());
+}
+
+#[test]
+fn test_averages() {
+    let mut h = Pm25History::default();
+    let now = 100 * DAY;
+
+    assert_eq!(h.nowcast(now), None);
+
+    // One reading per hour, the most recent hour being the dirtiest
+    for i in 0..12 {
+        h.add(now - (11 - i) * HOUR, 10.0 + i as f64);
+    }
+    assert_eq!(h.mean(now, HOUR), Some(21.0));
+    assert_eq!(h.mean(now, DAY), Some(15.5));
+
+    // min/max = 10/21 < 0.5, so the weight is clamped at 0.5
+    let expected = (0..12).map(|i| 0.5f64.powi(i) * (21.0 - i as f64)).sum::<f64>()
+                 / (0..12).map(|i| 0.5f64.powi(i)).sum::<f64>();
+    assert!((h.nowcast(now).unwrap() - expected).abs() < 1e-9);
+
+    // Readings older than a day are forgotten
+    h.add(now + DAY, 5.0);
+    assert_eq!(h.readings.len(), 1);
+    assert_eq!(h.nowcast(now + DAY), None);
+}
+
+#[test]
+fn test_enrich() {
+    use message::parse_from_string;
+    use metrics::Metrics;
+
+    let metrics = Metrics::new();
+    let mut e = AqiEnricher::new(vec![AqiTable::us_epa(), AqiTable::eu_caqi()], metrics.clone());
+
+    let mut msg = parse_from_string(String::from("ESP_D427A9: [11.06500] NTP: 2017-05-26T15:27:53.000+01:00 PM2.5: 12 UUID:d687fe3f-2d30-352d-0c21-ff3f2cea2040 sent:1"));
+    e.enrich(&mut msg);
+
+    // A single reading is not enough for a NowCast, but gives an hourly CAQI
+    assert_eq!(msg.annotations.get("aqi.us_epa"), None);
+    assert_eq!(msg.annotations["aqi.eu_caqi"], "20");
+    assert_eq!(msg.annotations["aqi.eu_caqi.category"], "Very low");
+    assert_eq!(msg.annotations["aqi.eu_caqi.colour"], "#79bc6a");
+    assert_eq!(msg.annotations["pm2.5.1h"], "12.0");
+
+    assert!(e.latest().lock().unwrap().contains_key("ESP_D427A9"));
+    assert!(metrics.render().contains("sensorweb_aqi{host=\"ESP_D427A9\",index=\"eu_caqi\"} 20\n"));
+
+    let mut wet = parse_from_string(String::from("ESP_WET: [11.06500] NTP: 2017-05-26T15:27:53.000+01:00 PM2.5: 60 UUID:abc sent:1"));
+    wet.annotations.insert(String::from("pm2.5.corrected"), String::from("12.0"));
+    e.enrich(&mut wet);
+    assert_eq!(wet.annotations["aqi.eu_caqi"], "20");
+    assert!(e.latest().lock().unwrap()["ESP_WET"].corrected);
+
+    let mut other = parse_from_string(String::from("ESP_D427A9: [11.06000] AC:push: Code 200"));
+    e.enrich(&mut other);
+    assert!(other.annotations.is_empty());
+}
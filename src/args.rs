@@ -2,6 +2,7 @@ extern crate clap;
 extern crate simplelog;
 
 use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
+use std::path::PathBuf;
 use std::str::FromStr;
 
 pub type UdpPort = u16;
@@ -25,14 +26,64 @@ impl Into<simplelog::LogLevelFilter> for VerbosityLevel {
     }
 }
 
+#[derive(Debug, Eq, PartialEq, Clone, Copy)]
+pub enum ReplayPacing {
+    RealTime,
+    AsFastAsPossible
+}
+
 #[derive(Debug, Clone)]
 /// Holds the program's runtime configuration
 pub struct RuntimeConfig {
-    pub multicast_group: IpAddr,
-    pub multicast_port:  UdpPort,
-    pub http_bind:       String,
-    pub ws_bind:         String,
-    pub verbosity_level: VerbosityLevel
+    pub multicast_group:    IpAddr,
+    pub multicast_port:     UdpPort,
+    pub udp_binds:          Vec<String>,
+    pub tcp_binds:          Vec<String>,
+    pub syslog_binds:       Vec<String>,
+    pub http_bind:          String,
+    pub ws_bind:            String,
+    pub static_dir:         Option<PathBuf>,
+    pub mqtt_broker:        Option<String>,
+    pub mqtt_prefix:        String,
+    pub mqtt_qos:           u8,
+    pub influx_url:         Option<String>,
+    pub influx_file:        Option<PathBuf>,
+    pub influx_batch:       usize,
+    pub aircasting_url:     Option<String>,
+    pub aircasting_retries: u32,
+    pub capture_file:       Option<PathBuf>,
+    pub capture_max_bytes:  u64,
+    pub capture_files:      usize,
+    pub replay_file:        Option<PathBuf>,
+    pub replay_pacing:      ReplayPacing,
+    pub aqi_tables:         Vec<PathBuf>,
+    pub raw_retention:      u64,
+    pub hourly_retention:   u64,
+    pub daily_retention:    u64,
+    pub alert_rules:        Option<PathBuf>,
+    pub alert_stdout:       bool,
+    pub alert_webhooks:     Vec<String>,
+    pub alert_smtp:         Option<String>,
+    pub alert_mail_from:    String,
+    pub alert_mail_to:      Vec<String>,
+    pub webhooks:           Option<PathBuf>,
+    pub session_export:     Option<PathBuf>,
+    pub parsers:            Option<PathBuf>,
+    pub auth_keys:          Option<PathBuf>,
+    pub auth_optional:      bool,
+    pub allow_sources:      Vec<String>,
+    pub allow_hosts:        Vec<String>,
+    pub check_up_ip:        bool,
+    pub rate_limit:         f64,
+    pub rate_burst:         f64,
+    pub collector_id:       Option<String>,
+    pub relay_to:           Option<String>,
+    pub relay_key:          Option<PathBuf>,
+    pub relay_buffer:       usize,
+    pub relay_listen:       Option<String>,
+    pub relay_keys:         Option<PathBuf>,
+    pub relay_origins:      Option<PathBuf>,
+    pub verbosity_level:    VerbosityLevel
 }
 
 pub struct ArgsParser;
@@ -62,6 +113,87 @@ impl ArgsParser {
         }
     }
 
+    /// QoS 2 is not supported, asking for it is an error rather than a silent downgrade
+    fn validate_qos(s: String) -> Result<(), String> {
+        match s.as_ref() {
+            "0" | "1" => Ok(()),
+            _         => Err(format!("unsupported QoS {:?}, expected 0 or 1", s))
+        }
+    }
+
+    fn to_qos(o: Option<&str>) -> u8 {
+        match o {
+            Some("1") => 1,
+            _         => 0
+        }
+    }
+
+    fn to_batch_size(o: Option<&str>) -> usize {
+        let default_batch = 100;
+        match o.unwrap_or("").parse::<usize>() {
+            Ok(0)  => 1,
+            Ok(rv) => rv,
+            Err(_) => default_batch
+        }
+    }
+
+    fn to_retries(o: Option<&str>) -> u32 {
+        let default_retries = 5;
+        match o.unwrap_or("").parse::<u32>() {
+            Ok(rv) => rv,
+            Err(_) => default_retries
+        }
+    }
+
+    fn to_capture_max_bytes(o: Option<&str>) -> u64 {
+        let default_mb = 10;
+        match o.unwrap_or("").parse::<u64>() {
+            Ok(0)  => 1 << 20,
+            Ok(mb) => mb << 20,
+            Err(_) => default_mb << 20
+        }
+    }
+
+    fn to_capture_files(o: Option<&str>) -> usize {
+        let default_files = 5;
+        match o.unwrap_or("").parse::<usize>() {
+            Ok(rv) => rv,
+            Err(_) => default_files
+        }
+    }
+
+    fn to_replay_pacing(o: Option<&str>) -> ReplayPacing {
+        match o.unwrap_or("realtime") {
+            "fast" => ReplayPacing::AsFastAsPossible,
+            _      => ReplayPacing::RealTime
+        }
+    }
+
+    /// Parses a retention given in `unit`s into seconds
+    fn to_retention(o: Option<&str>, default: u64, unit: u64) -> u64 {
+        match o.unwrap_or("").parse::<u64>() {
+            Ok(rv) => rv * unit,
+            Err(_) => default * unit
+        }
+    }
+
+    fn to_relay_buffer(o: Option<&str>) -> usize {
+        let default_buffer = 10000;
+        match o.unwrap_or("").parse::<usize>() {
+            Ok(0)  => 1,
+            Ok(rv) => rv,
+            Err(_) => default_buffer
+        }
+    }
+
+    /// Parses a non-negative rate, falling back to `default`
+    fn to_rate(o: Option<&str>, default: f64) -> f64 {
+        match o.unwrap_or("").parse::<f64>() {
+            Ok(rv) if rv >= 0.0 => rv,
+            _                   => default
+        }
+    }
+
     fn to_verbosity_level(occ: u64) -> VerbosityLevel {
         match occ {
             0   => VerbosityLevel::ERROR,
@@ -91,6 +223,30 @@ impl ArgsParser {
                                    .help("Multicast port")
                                    .takes_value(true)
                                    .required(false))
+                              .arg(clap::Arg::with_name("udp_bind")
+                                   .long("udp-bind")
+                                   .value_name("IP:PORT")
+                                   .help("Also listen for unicast datagrams on IP:PORT, may be repeated")
+                                   .takes_value(true)
+                                   .multiple(true)
+                                   .number_of_values(1)
+                                   .required(false))
+                              .arg(clap::Arg::with_name("tcp_bind")
+                                   .long("tcp-bind")
+                                   .value_name("IP:PORT")
+                                   .help("Also accept TCP connections sending one message per line on IP:PORT, may be repeated")
+                                   .takes_value(true)
+                                   .multiple(true)
+                                   .number_of_values(1)
+                                   .required(false))
+                              .arg(clap::Arg::with_name("syslog_bind")
+                                   .long("syslog-bind")
+                                   .value_name("IP:PORT")
+                                   .help("Also listen for RFC 3164 or RFC 5424 syslog messages over UDP on IP:PORT, may be repeated")
+                                   .takes_value(true)
+                                   .multiple(true)
+                                   .number_of_values(1)
+                                   .required(false))
                               .arg(clap::Arg::with_name("http_bind")
                                    .short("h")
                                    .long("http_bind")
@@ -105,6 +261,258 @@ impl ArgsParser {
                                    .help("IP:PORT to bind for WebSocket")
                                    .takes_value(true)
                                    .required(false))
+                              .arg(clap::Arg::with_name("static_dir")
+                                   .long("static-dir")
+                                   .value_name("DIR")
+                                   .help("Serve dashboard files from DIR before the embedded copies (development)")
+                                   .takes_value(true)
+                                   .required(false))
+                              .arg(clap::Arg::with_name("mqtt")
+                                   .long("mqtt")
+                                   .value_name("HOST:PORT")
+                                   .help("Publish messages to this MQTT broker")
+                                   .takes_value(true)
+                                   .required(false))
+                              .arg(clap::Arg::with_name("mqtt_prefix")
+                                   .long("mqtt-prefix")
+                                   .value_name("PREFIX")
+                                   .help("MQTT topic prefix")
+                                   .takes_value(true)
+                                   .required(false))
+                              .arg(clap::Arg::with_name("mqtt_qos")
+                                   .long("mqtt-qos")
+                                   .value_name("QOS")
+                                   .help("MQTT QoS level, 0 (at most once) or 1 (at least once)")
+                                   .takes_value(true)
+                                   .validator(ArgsParser::validate_qos)
+                                   .required(false))
+                              .arg(clap::Arg::with_name("influx_url")
+                                   .long("influx-url")
+                                   .value_name("URL")
+                                   .help("POST line protocol to this InfluxDB write endpoint, e.g. http://localhost:8086/write?db=sensorweb")
+                                   .takes_value(true)
+                                   .required(false))
+                              .arg(clap::Arg::with_name("influx_file")
+                                   .long("influx-file")
+                                   .value_name("FILE")
+                                   .help("Append line protocol to FILE for offline import")
+                                   .takes_value(true)
+                                   .required(false))
+                              .arg(clap::Arg::with_name("influx_batch")
+                                   .long("influx-batch")
+                                   .value_name("LINES")
+                                   .help("Number of lines to batch before writing")
+                                   .takes_value(true)
+                                   .required(false))
+                              .arg(clap::Arg::with_name("aircasting_url")
+                                   .long("aircasting-url")
+                                   .value_name("URL")
+                                   .help("Re-upload measurements nodes failed to push to this AirCasting-compatible endpoint")
+                                   .takes_value(true)
+                                   .required(false))
+                              .arg(clap::Arg::with_name("aircasting_retries")
+                                   .long("aircasting-retries")
+                                   .value_name("COUNT")
+                                   .help("Upload attempts before giving up on a session's measurements")
+                                   .takes_value(true)
+                                   .required(false))
+                              .arg(clap::Arg::with_name("capture")
+                                   .long("capture")
+                                   .value_name("FILE")
+                                   .help("Append every raw datagram, with receive time, source and transport, to FILE")
+                                   .takes_value(true)
+                                   .required(false))
+                              .arg(clap::Arg::with_name("capture_size")
+                                   .long("capture-size")
+                                   .value_name("MB")
+                                   .help("Rotate the capture file once it grows past MB megabytes")
+                                   .takes_value(true)
+                                   .required(false))
+                              .arg(clap::Arg::with_name("capture_files")
+                                   .long("capture-files")
+                                   .value_name("COUNT")
+                                   .help("Number of rotated capture files to keep")
+                                   .takes_value(true)
+                                   .required(false))
+                              .arg(clap::Arg::with_name("replay")
+                                   .long("replay")
+                                   .value_name("FILE")
+                                   .help("Feed a capture file through the pipeline instead of listening")
+                                   .takes_value(true)
+                                   .required(false))
+                              .arg(clap::Arg::with_name("replay_pace")
+                                   .long("replay-pace")
+                                   .value_name("PACE")
+                                   .help("Replay pacing")
+                                   .possible_values(&["realtime", "fast"])
+                                   .takes_value(true)
+                                   .required(false))
+                              .arg(clap::Arg::with_name("aqi_table")
+                                   .long("aqi-table")
+                                   .value_name("FILE")
+                                   .help("Additional PM2.5 index breakpoint table, may be repeated")
+                                   .takes_value(true)
+                                   .multiple(true)
+                                   .number_of_values(1)
+                                   .required(false))
+                              .arg(clap::Arg::with_name("raw_retention")
+                                   .long("raw-retention")
+                                   .value_name("HOURS")
+                                   .help("Keep raw readings for HOURS before folding them into hourly aggregates")
+                                   .takes_value(true)
+                                   .required(false))
+                              .arg(clap::Arg::with_name("hourly_retention")
+                                   .long("hourly-retention")
+                                   .value_name("DAYS")
+                                   .help("Keep hourly aggregates for DAYS before folding them into daily aggregates")
+                                   .takes_value(true)
+                                   .required(false))
+                              .arg(clap::Arg::with_name("daily_retention")
+                                   .long("daily-retention")
+                                   .value_name("DAYS")
+                                   .help("Keep daily aggregates for DAYS")
+                                   .takes_value(true)
+                                   .required(false))
+                              .arg(clap::Arg::with_name("alert_rules")
+                                   .long("alert-rules")
+                                   .value_name("FILE")
+                                   .help("Evaluate the alerting rules in FILE")
+                                   .takes_value(true)
+                                   .required(false))
+                              .arg(clap::Arg::with_name("alert_stdout")
+                                   .long("alert-stdout")
+                                   .help("Print alerts on stdout")
+                                   .required(false))
+                              .arg(clap::Arg::with_name("alert_webhook")
+                                   .long("alert-webhook")
+                                   .value_name("URL")
+                                   .help("POST alerts as JSON to URL, may be repeated")
+                                   .takes_value(true)
+                                   .multiple(true)
+                                   .number_of_values(1)
+                                   .required(false))
+                              .arg(clap::Arg::with_name("alert_smtp")
+                                   .long("alert-smtp")
+                                   .value_name("HOST:PORT")
+                                   .help("Mail alerts through this SMTP relay")
+                                   .takes_value(true)
+                                   .required(false))
+                              .arg(clap::Arg::with_name("alert_mail_from")
+                                   .long("alert-mail-from")
+                                   .value_name("ADDRESS")
+                                   .help("Sender address of alert mails")
+                                   .takes_value(true)
+                                   .required(false))
+                              .arg(clap::Arg::with_name("alert_mail_to")
+                                   .long("alert-mail-to")
+                                   .value_name("ADDRESS")
+                                   .help("Recipient of alert mails, may be repeated")
+                                   .takes_value(true)
+                                   .multiple(true)
+                                   .number_of_values(1)
+                                   .required(false))
+                              .arg(clap::Arg::with_name("webhooks")
+                                   .long("webhooks")
+                                   .value_name("FILE")
+                                   .help("POST messages as JSON to the webhooks configured in FILE")
+                                   .takes_value(true)
+                                   .required(false))
+                              .arg(clap::Arg::with_name("session_export")
+                                   .long("session-export")
+                                   .value_name("DIR")
+                                   .help("Write each session to DIR as CSV and GPX once it ends")
+                                   .takes_value(true)
+                                   .required(false))
+                              .arg(clap::Arg::with_name("parsers")
+                                   .long("parsers")
+                                   .value_name("FILE")
+                                   .help("Parse the message kinds declared in FILE with patterns or templates")
+                                   .takes_value(true)
+                                   .required(false))
+                              .arg(clap::Arg::with_name("auth_keys")
+                                   .long("auth-keys")
+                                   .value_name("FILE")
+                                   .help("Only accept datagrams signed with the HMAC keys in FILE, as host = key lines")
+                                   .takes_value(true)
+                                   .required(false))
+                              .arg(clap::Arg::with_name("auth_optional")
+                                   .long("auth-optional")
+                                   .help("Also accept unsigned datagrams, still refusing badly signed ones")
+                                   .required(false))
+                              .arg(clap::Arg::with_name("allow_source")
+                                   .long("allow-source")
+                                   .value_name("CIDR")
+                                   .help("Only accept datagrams sent from this network, may be repeated")
+                                   .takes_value(true)
+                                   .multiple(true)
+                                   .number_of_values(1)
+                                   .required(false))
+                              .arg(clap::Arg::with_name("allow_host")
+                                   .long("allow-host")
+                                   .value_name("NAME")
+                                   .help("Only accept messages of this host, a trailing * matching any suffix, may be repeated")
+                                   .takes_value(true)
+                                   .multiple(true)
+                                   .number_of_values(1)
+                                   .required(false))
+                              .arg(clap::Arg::with_name("check_up_ip")
+                                   .long("check-up-ip")
+                                   .help("Drop messages of a host sent from another address than the one its UP message announced")
+                                   .required(false))
+                              .arg(clap::Arg::with_name("rate_limit")
+                                   .long("rate-limit")
+                                   .value_name("COUNT")
+                                   .help("Datagrams per second accepted from each source, 0 for no limit")
+                                   .takes_value(true)
+                                   .required(false))
+                              .arg(clap::Arg::with_name("rate_burst")
+                                   .long("rate-burst")
+                                   .value_name("COUNT")
+                                   .help("Datagrams a source may send at once before being rate limited")
+                                   .takes_value(true)
+                                   .required(false))
+                              .arg(clap::Arg::with_name("collector_id")
+                                   .long("collector-id")
+                                   .value_name("NAME")
+                                   .help("Name of this collector, tagging the messages it relays")
+                                   .takes_value(true)
+                                   .required(false))
+                              .arg(clap::Arg::with_name("relay_to")
+                                   .long("relay-to")
+                                   .value_name("HOST:PORT")
+                                   .help("Forward every message to the upstream collector listening for relay links on HOST:PORT")
+                                   .takes_value(true)
+                                   .required(false))
+                              .arg(clap::Arg::with_name("relay_key")
+                                   .long("relay-key")
+                                   .value_name("FILE")
+                                   .help("Authenticate to the upstream collector with the key in FILE")
+                                   .takes_value(true)
+                                   .required(false))
+                              .arg(clap::Arg::with_name("relay_buffer")
+                                   .long("relay-buffer")
+                                   .value_name("COUNT")
+                                   .help("Messages kept while the upstream collector is unreachable")
+                                   .takes_value(true)
+                                   .required(false))
+                              .arg(clap::Arg::with_name("relay_listen")
+                                   .long("relay-listen")
+                                   .value_name("IP:PORT")
+                                   .help("Accept relay links from downstream collectors on IP:PORT")
+                                   .takes_value(true)
+                                   .required(false))
+                              .arg(clap::Arg::with_name("relay_keys")
+                                   .long("relay-keys")
+                                   .value_name("FILE")
+                                   .help("Keys of the downstream collectors, as collector = key lines")
+                                   .takes_value(true)
+                                   .required(false))
+                              .arg(clap::Arg::with_name("relay_origins")
+                                   .long("relay-origins")
+                                   .value_name("FILE")
+                                   .help("Other collectors each downstream collector relays for, as collector = origin, origin lines")
+                                   .takes_value(true)
+                                   .required(false))
                               .arg(clap::Arg::with_name("v")
                                    .short("v")
                                    .multiple(true)
@@ -115,8 +523,52 @@ impl ArgsParser {
         RuntimeConfig {
             multicast_group: ArgsParser::to_ip_addr(matches.value_of("mcast")),
             multicast_port:  ArgsParser::to_port(matches.value_of("port")),
+            udp_binds: matches.values_of("udp_bind").map(|v| v.map(String::from).collect()).unwrap_or(Vec::new()),
+            tcp_binds: matches.values_of("tcp_bind").map(|v| v.map(String::from).collect()).unwrap_or(Vec::new()),
+            syslog_binds: matches.values_of("syslog_bind").map(|v| v.map(String::from).collect()).unwrap_or(Vec::new()),
             http_bind: String::from(matches.value_of("http_bind").unwrap_or("0.0.0.0:8000")),
             ws_bind: String::from(matches.value_of("ws_bind").unwrap_or("0.0.0.0:8001")),
+            static_dir: matches.value_of("static_dir").map(PathBuf::from),
+            mqtt_broker: matches.value_of("mqtt").map(String::from),
+            mqtt_prefix: String::from(matches.value_of("mqtt_prefix").unwrap_or("sensorweb")),
+            mqtt_qos: ArgsParser::to_qos(matches.value_of("mqtt_qos")),
+            influx_url: matches.value_of("influx_url").map(String::from),
+            influx_file: matches.value_of("influx_file").map(PathBuf::from),
+            influx_batch: ArgsParser::to_batch_size(matches.value_of("influx_batch")),
+            aircasting_url: matches.value_of("aircasting_url").map(String::from),
+            aircasting_retries: ArgsParser::to_retries(matches.value_of("aircasting_retries")),
+            capture_file: matches.value_of("capture").map(PathBuf::from),
+            capture_max_bytes: ArgsParser::to_capture_max_bytes(matches.value_of("capture_size")),
+            capture_files: ArgsParser::to_capture_files(matches.value_of("capture_files")),
+            replay_file: matches.value_of("replay").map(PathBuf::from),
+            replay_pacing: ArgsParser::to_replay_pacing(matches.value_of("replay_pace")),
+            aqi_tables: matches.values_of("aqi_table").map(|v| v.map(PathBuf::from).collect()).unwrap_or(Vec::new()),
+            raw_retention: ArgsParser::to_retention(matches.value_of("raw_retention"), 48, 3600),
+            hourly_retention: ArgsParser::to_retention(matches.value_of("hourly_retention"), 30, 86400),
+            daily_retention: ArgsParser::to_retention(matches.value_of("daily_retention"), 365, 86400),
+            alert_rules: matches.value_of("alert_rules").map(PathBuf::from),
+            alert_stdout: matches.is_present("alert_stdout"),
+            alert_webhooks: matches.values_of("alert_webhook").map(|v| v.map(String::from).collect()).unwrap_or(Vec::new()),
+            alert_smtp: matches.value_of("alert_smtp").map(String::from),
+            alert_mail_from: String::from(matches.value_of("alert_mail_from").unwrap_or("sensorweb@localhost")),
+            alert_mail_to: matches.values_of("alert_mail_to").map(|v| v.map(String::from).collect()).unwrap_or(Vec::new()),
+            webhooks: matches.value_of("webhooks").map(PathBuf::from),
+            session_export: matches.value_of("session_export").map(PathBuf::from),
+            parsers: matches.value_of("parsers").map(PathBuf::from),
+            auth_keys: matches.value_of("auth_keys").map(PathBuf::from),
+            auth_optional: matches.is_present("auth_optional"),
+            allow_sources: matches.values_of("allow_source").map(|v| v.map(String::from).collect()).unwrap_or(Vec::new()),
+            allow_hosts: matches.values_of("allow_host").map(|v| v.map(String::from).collect()).unwrap_or(Vec::new()),
+            check_up_ip: matches.is_present("check_up_ip"),
+            rate_limit: ArgsParser::to_rate(matches.value_of("rate_limit"), 0.0),
+            rate_burst: ArgsParser::to_rate(matches.value_of("rate_burst"), 20.0),
+            collector_id: matches.value_of("collector_id").map(String::from),
+            relay_to: matches.value_of("relay_to").map(String::from),
+            relay_key: matches.value_of("relay_key").map(PathBuf::from),
+            relay_buffer: ArgsParser::to_relay_buffer(matches.value_of("relay_buffer")),
+            relay_listen: matches.value_of("relay_listen").map(String::from),
+            relay_keys: matches.value_of("relay_keys").map(PathBuf::from),
+            relay_origins: matches.value_of("relay_origins").map(PathBuf::from),
             verbosity_level: ArgsParser::to_verbosity_level(matches.occurrences_of("v"))
         }
     }
@@ -139,6 +591,75 @@ fn test_to_port() {
     assert_eq!(ArgsParser::to_port(Some("1234")), 1234);
 }
 
+#[test]
+fn test_to_qos() {
+    assert_eq!(ArgsParser::to_qos(None), 0);
+    assert_eq!(ArgsParser::to_qos(Some("0")), 0);
+    assert_eq!(ArgsParser::to_qos(Some("1")), 1);
+    assert!(ArgsParser::validate_qos(String::from("1")).is_ok());
+    assert!(ArgsParser::validate_qos(String::from("2")).is_err());
+    assert!(ArgsParser::validate_qos(String::from("xxx")).is_err());
+}
+
+#[test]
+fn test_to_batch_size() {
+    assert_eq!(ArgsParser::to_batch_size(None), 100);
+    assert_eq!(ArgsParser::to_batch_size(Some("xxx")), 100);
+    assert_eq!(ArgsParser::to_batch_size(Some("0")), 1);
+    assert_eq!(ArgsParser::to_batch_size(Some("500")), 500);
+}
+
+#[test]
+fn test_to_retries() {
+    assert_eq!(ArgsParser::to_retries(None), 5);
+    assert_eq!(ArgsParser::to_retries(Some("-1")), 5);
+    assert_eq!(ArgsParser::to_retries(Some("0")), 0);
+    assert_eq!(ArgsParser::to_retries(Some("12")), 12);
+}
+
+#[test]
+fn test_to_capture_max_bytes() {
+    assert_eq!(ArgsParser::to_capture_max_bytes(None), 10 << 20);
+    assert_eq!(ArgsParser::to_capture_max_bytes(Some("0")), 1 << 20);
+    assert_eq!(ArgsParser::to_capture_max_bytes(Some("64")), 64 << 20);
+}
+
+#[test]
+fn test_to_capture_files() {
+    assert_eq!(ArgsParser::to_capture_files(None), 5);
+    assert_eq!(ArgsParser::to_capture_files(Some("0")), 0);
+    assert_eq!(ArgsParser::to_capture_files(Some("xxx")), 5);
+}
+
+#[test]
+fn test_to_replay_pacing() {
+    assert_eq!(ArgsParser::to_replay_pacing(None), ReplayPacing::RealTime);
+    assert_eq!(ArgsParser::to_replay_pacing(Some("realtime")), ReplayPacing::RealTime);
+    assert_eq!(ArgsParser::to_replay_pacing(Some("fast")), ReplayPacing::AsFastAsPossible);
+}
+
+#[test]
+fn test_to_retention() {
+    assert_eq!(ArgsParser::to_retention(None, 48, 3600), 48 * 3600);
+    assert_eq!(ArgsParser::to_retention(Some("xxx"), 30, 86400), 30 * 86400);
+    assert_eq!(ArgsParser::to_retention(Some("7"), 30, 86400), 7 * 86400);
+}
+
+#[test]
+fn test_to_relay_buffer() {
+    assert_eq!(ArgsParser::to_relay_buffer(None), 10000);
+    assert_eq!(ArgsParser::to_relay_buffer(Some("0")), 1);
+    assert_eq!(ArgsParser::to_relay_buffer(Some("500")), 500);
+}
+
+#[test]
+fn test_to_rate() {
+    assert_eq!(ArgsParser::to_rate(None, 0.0), 0.0);
+    assert_eq!(ArgsParser::to_rate(Some("xxx"), 20.0), 20.0);
+    assert_eq!(ArgsParser::to_rate(Some("-1"), 20.0), 20.0);
+    assert_eq!(ArgsParser::to_rate(Some("0.5"), 0.0), 0.5);
+}
+
 #[test]
 fn test_to_verbosity_level() {
     assert_eq!(ArgsParser::to_verbosity_level(0),  VerbosityLevel::ERROR);
@@ -155,5 +676,47 @@ fn test_args() {
 
     assert_eq!(rc.multicast_group.to_string(), "239.0.0.1");
     assert_eq!(rc.multicast_port.to_string(), "8899");
+    assert!(rc.udp_binds.is_empty());
+    assert!(rc.tcp_binds.is_empty());
+    assert!(rc.syslog_binds.is_empty());
+    assert_eq!(rc.static_dir, None);
+    assert_eq!(rc.mqtt_broker, None);
+    assert_eq!(rc.mqtt_prefix, "sensorweb");
+    assert_eq!(rc.mqtt_qos, 0);
+    assert_eq!(rc.influx_url, None);
+    assert_eq!(rc.influx_file, None);
+    assert_eq!(rc.influx_batch, 100);
+    assert_eq!(rc.aircasting_url, None);
+    assert_eq!(rc.aircasting_retries, 5);
+    assert_eq!(rc.capture_file, None);
+    assert_eq!(rc.replay_file, None);
+    assert_eq!(rc.replay_pacing, ReplayPacing::RealTime);
+    assert!(rc.aqi_tables.is_empty());
+    assert_eq!(rc.raw_retention, 48 * 3600);
+    assert_eq!(rc.hourly_retention, 30 * 86400);
+    assert_eq!(rc.daily_retention, 365 * 86400);
+    assert_eq!(rc.alert_rules, None);
+    assert!(!rc.alert_stdout);
+    assert!(rc.alert_webhooks.is_empty());
+    assert_eq!(rc.alert_smtp, None);
+    assert_eq!(rc.alert_mail_from, "sensorweb@localhost");
+    assert!(rc.alert_mail_to.is_empty());
+    assert_eq!(rc.webhooks, None);
+    assert_eq!(rc.session_export, None);
+    assert_eq!(rc.parsers, None);
+    assert_eq!(rc.auth_keys, None);
+    assert!(!rc.auth_optional);
+    assert!(rc.allow_sources.is_empty());
+    assert!(rc.allow_hosts.is_empty());
+    assert!(!rc.check_up_ip);
+    assert_eq!(rc.rate_limit, 0.0);
+    assert_eq!(rc.rate_burst, 20.0);
+    assert_eq!(rc.collector_id, None);
+    assert_eq!(rc.relay_to, None);
+    assert_eq!(rc.relay_key, None);
+    assert_eq!(rc.relay_buffer, 10000);
+    assert_eq!(rc.relay_listen, None);
+    assert_eq!(rc.relay_keys, None);
+    assert_eq!(rc.relay_origins, None);
     assert_eq!(rc.verbosity_level, VerbosityLevel::ERROR);
 }
@@ -0,0 +1,44 @@
+use std::path::Path;
+
+/// A file from `static/`, embedded at compile time by `build.rs`
+pub struct Asset {
+    pub path: &'static str,
+    pub etag: &'static str,
+    pub raw:  &'static [u8],
+    pub gzip: &'static [u8]
+}
+
+include!(concat!(env!("OUT_DIR"), "/assets.rs"));
+
+pub fn lookup(path: &str) -> Option<&'static Asset> {
+    ASSETS.iter().find(|a| a.path == path)
+}
+
+pub fn mime_for(path: &Path) -> &'static str {
+    match path.extension().and_then(|e| e.to_str()).unwrap_or("") {
+        "html" | "htm" => "text/html; charset=utf-8",
+        "css"          => "text/css; charset=utf-8",
+        "js"           => "application/javascript; charset=utf-8",
+        "json"         => "application/json",
+        "svg"          => "image/svg+xml",
+        "png"          => "image/png",
+        "ico"          => "image/x-icon",
+        _              => "application/octet-stream"
+    }
+}
+
+#[test]
+fn test_lookup() {
+    let index = lookup("/index.html").unwrap();
+    assert!(!index.raw.is_empty());
+    assert!(!index.gzip.is_empty());
+    assert_eq!(index.etag.len(), 16);
+    assert!(lookup("/does/not/exist").is_none());
+}
+
+#[test]
+fn test_mime_for() {
+    assert_eq!(mime_for(Path::new("index.html")), "text/html; charset=utf-8");
+    assert_eq!(mime_for(Path::new("js/app.js")), "application/javascript; charset=utf-8");
+    assert_eq!(mime_for(Path::new("blob")), "application/octet-stream");
+}
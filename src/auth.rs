@@ -0,0 +1,271 @@
+use std::collections::HashMap;
+use std::fs::File;
+use std::io::Read;
+use std::path::Path;
+
+use hmac::{Hmac, Mac};
+use metrics::SharedMetrics;
+use sha2::Sha256;
+
+/// Marks the sender timestamp, which the MAC covers along with the line
+const TIMESTAMP_MARK: &'static str = " #t=";
+
+/// Marks the MAC, computed over everything before it
+const HMAC_MARK: &'static str = " #hmac=";
+
+/// Signed datagrams further than this many seconds from the collector clock are refused
+const MAX_CLOCK_SKEW: u64 = 300;
+
+/// Authentication trailer of a datagram: `<line> #t=<unix time> #hmac=<hex>`
+#[derive(Debug, Clone, PartialEq)]
+pub struct Trailer {
+    /// Everything the MAC covers, i.e. the line and its timestamp
+    pub signed:    String,
+    pub timestamp: Option<u64>,
+    pub hmac:      String
+}
+
+/// Separates a datagram from its authentication trailer, if any
+pub fn split_trailer(s: &str) -> (String, Option<Trailer>) {
+    let idx = match s.rfind(HMAC_MARK) {
+        Some(idx) => idx,
+        None      => return (String::from(s), None)
+    };
+
+    let signed = &s[..idx];
+    let (line, timestamp) = match signed.rfind(TIMESTAMP_MARK) {
+        Some(t) => (&signed[..t], signed[t + TIMESTAMP_MARK.len()..].trim().parse::<u64>().ok()),
+        None    => (signed, None)
+    };
+
+    (String::from(line), Some(Trailer {
+        signed:    String::from(signed),
+        timestamp: timestamp,
+        hmac:      s[idx + HMAC_MARK.len()..].trim().to_lowercase()
+    }))
+}
+
+fn hmac_sha256(key: &str, data: &str) -> Hmac<Sha256> {
+    let mut mac = Hmac::<Sha256>::new_from_slice(key.as_bytes()).expect("HMAC takes keys of any length");
+    mac.update(data.as_bytes());
+    mac
+}
+
+fn from_hex(s: &str) -> Option<Vec<u8>> {
+    if s.len() % 2 != 0 || !s.is_ascii() {
+        return None;
+    }
+    (0..s.len()).step_by(2).map(|i| u8::from_str_radix(&s[i..i + 2], 16).ok()).collect()
+}
+
+pub fn to_hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+/// Hex HMAC-SHA256 of `data` under `key`
+pub fn mac_hex(key: &str, data: &str) -> String {
+    to_hex(&hmac_sha256(key, data).finalize().into_bytes())
+}
+
+/// Checks the hex MAC of `data` under `key`, in constant time not to tell how much of a forgery was right
+pub fn verify_mac(key: &str, data: &str, mac: &str) -> bool {
+    match from_hex(mac) {
+        Some(bytes) => hmac_sha256(key, data).verify_slice(&bytes).is_ok(),
+        None        => false
+    }
+}
+
+/// Signs `line` the way nodes are expected to
+#[cfg(test)]
+pub fn sign(key: &str, line: &str, timestamp: u64) -> String {
+    let signed = format!("{}{}{}", line, TIMESTAMP_MARK, timestamp);
+    let mac = mac_hex(key, &signed);
+    format!("{}{}{}", signed, HMAC_MARK, mac)
+}
+
+/// Per-node keys, with an optional key shared by every other node
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct AuthKeys {
+    pub nodes:  HashMap<String, String>,
+    pub shared: Option<String>
+}
+
+impl AuthKeys {
+    /// Reads `host = key` lines, `*` standing for the shared key
+    pub fn parse(s: &str) -> Result<AuthKeys, String> {
+        let mut rv = AuthKeys::default();
+
+        for (lineno, raw) in s.lines().enumerate() {
+            let line = raw.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+
+            let eq = line.find('=').ok_or(format!("line {}: expected host = key", lineno + 1))?;
+            let (host, key) = (line[..eq].trim(), line[eq + 1..].trim());
+            if key.is_empty() {
+                return Err(format!("line {}: empty key for {}", lineno + 1, host));
+            }
+
+            if host == "*" {
+                rv.shared = Some(String::from(key));
+            } else {
+                rv.nodes.insert(String::from(host), String::from(key));
+            }
+        }
+
+        Ok(rv)
+    }
+
+    pub fn load(path: &Path) -> Result<AuthKeys, String> {
+        let mut contents = String::new();
+        File::open(path).and_then(|mut f| f.read_to_string(&mut contents))
+                        .map_err(|e| format!("{:?}: {}", path, e))?;
+        AuthKeys::parse(&contents).map_err(|e| format!("{:?}: {}", path, e))
+    }
+
+    /// Key of `host`, falling back to the shared one
+    pub fn key(&self, host: &str) -> Option<&String> {
+        self.nodes.get(host).or(self.shared.as_ref())
+    }
+}
+
+/// Verifies datagram MACs, refusing replays of previously accepted ones
+pub struct Authenticator {
+    keys:     AuthKeys,
+    /// Accept datagrams without a trailer, while a fleet is being upgraded
+    optional: bool,
+    /// Timestamp and uptime of the last accepted datagram of every host
+    last:     HashMap<String, (u64, f32)>,
+    metrics:  SharedMetrics
+}
+
+impl Authenticator {
+    pub fn new(keys: AuthKeys, optional: bool, metrics: SharedMetrics) -> Authenticator {
+        Authenticator {
+            keys:     keys,
+            optional: optional,
+            last:     HashMap::new(),
+            metrics:  metrics
+        }
+    }
+
+    fn check(&mut self, host: &str, time: f32, trailer: Option<&Trailer>, now: u64) -> Result<(), &'static str> {
+        let trailer = match trailer {
+            Some(t) => t,
+            None    => return if self.optional { Ok(()) } else { Err("unsigned") }
+        };
+
+        let key = self.keys.key(host).ok_or("unknown_host")?;
+        if !verify_mac(key, &trailer.signed, &trailer.hmac) {
+            return Err("bad_signature");
+        }
+
+        let timestamp = trailer.timestamp.ok_or("no_timestamp")?;
+        if timestamp + MAX_CLOCK_SKEW < now || timestamp > now + MAX_CLOCK_SKEW {
+            return Err("stale");
+        }
+
+        // Signed lines of a host come with strictly increasing (timestamp, uptime), the uptime ordering
+        // those sent within the same second; anything older, even from a previous wake, is a replay
+        if let Some(&(last_timestamp, last_uptime)) = self.last.get(host) {
+            if timestamp < last_timestamp || (timestamp == last_timestamp && time <= last_uptime) {
+                return Err("replayed");
+            }
+        }
+        self.last.insert(String::from(host), (timestamp, time));
+        Ok(())
+    }
+
+    /// Whether `line`, sent by `host` at uptime `time` and received at `now`, may go through; rejections are logged and counted
+    pub fn verify(&mut self, host: &str, time: f32, line: &str, trailer: Option<&Trailer>, now: u64) -> bool {
+        match self.check(host, time, trailer, now) {
+            Ok(_)       => true,
+            Err(reason) => {
+                warn!("Rejected datagram from {:?} ({}): {}", host, reason, line);
+                self.metrics.counter_add("sensorweb_datagrams_rejected_total", "Datagrams failing authentication",
+                                         &[("reason", reason)], 1.0);
+                false
+            }
+        }
+    }
+}
+
+#[test]
+fn test_split_trailer() {
+    let line = "ESP_1: [11.06500] NTP: 2017-05-26T15:27:53.000+01:00 PM2.5: 12 UUID:abc sent:1";
+    assert_eq!(split_trailer(line), (String::from(line), None));
+
+    let signed = sign("secret", line, 1495808873);
+    let (stripped, trailer) = split_trailer(&signed);
+    assert_eq!(stripped, line);
+    let trailer = trailer.unwrap();
+    assert_eq!(trailer.timestamp, Some(1495808873));
+    assert_eq!(trailer.signed, format!("{} #t=1495808873", line));
+    assert_eq!(trailer.hmac, mac_hex("secret", &trailer.signed));
+}
+
+#[test]
+fn test_mac() {
+    // RFC 4231 test case 2
+    let mac = "5bdcc146bf60754e6a042426089575c75a003f089d2739839dec58b964ec3843";
+    assert_eq!(mac_hex("Jefe", "what do ya want for nothing?"), mac);
+    assert!(verify_mac("Jefe", "what do ya want for nothing?", mac));
+    assert!(!verify_mac("Jefe", "what do ya want for nothing!", mac));
+    assert!(!verify_mac("Jefe", "what do ya want for nothing?", &mac[..62]));
+    assert!(!verify_mac("Jefe", "what do ya want for nothing?", "zz"));
+}
+
+#[test]
+fn test_parse_keys() {
+    let keys = AuthKeys::parse("# fleet keys\nESP_1 = k1\n* = shared\n").unwrap();
+    assert_eq!(keys.key("ESP_1"), Some(&String::from("k1")));
+    assert_eq!(keys.key("ESP_2"), Some(&String::from("shared")));
+    assert!(AuthKeys::parse("ESP_1\n").is_err());
+    assert!(AuthKeys::parse("ESP_1 =\n").is_err());
+}
+
+#[test]
+fn test_authenticator() {
+    use message::line_sender;
+    use metrics::Metrics;
+
+    let metrics = Metrics::new();
+    let keys = AuthKeys::parse("ESP_1 = k1\n").unwrap();
+    let mut auth = Authenticator::new(keys, false, metrics.clone());
+    let now = 1495808873;
+    let verify = |auth: &mut Authenticator, datagram: &str, now: u64| {
+        let (line, trailer) = split_trailer(datagram);
+        let (host, time) = line_sender(&line);
+        auth.verify(host, time, &line, trailer.as_ref(), now)
+    };
+
+    let first = sign("k1", "ESP_1: [2.89900] UP: 1.0:May 14 2017 01:34:24@192.168.1.29", now);
+    let push = sign("k1", "ESP_1: [11.06000] AC:push: Code 200", now);
+    assert!(verify(&mut auth, &first, now));
+    assert!(verify(&mut auth, &push, now));
+
+    // Replays within the same wake, or of an earlier one, are refused
+    assert!(!verify(&mut auth, &first, now + 1));
+    assert!(!verify(&mut auth, &sign("k1", "ESP_1: [5.00000] AC:push: Code 200", now - 10), now));
+
+    // The next wake restarts the uptime, at a later time
+    assert!(verify(&mut auth, &sign("k1", "ESP_1: [2.89900] UP: 1.0:May 14 2017 01:34:24@192.168.1.29", now + 300), now + 300));
+    // after which lines of the previous wake are refused, their higher uptime notwithstanding
+    assert!(!verify(&mut auth, &push, now + 300));
+
+    assert!(!verify(&mut auth, "ESP_1: [2.89900] UP: 1.0:May 14 2017 01:34:24@192.168.1.29", now));
+    assert!(!verify(&mut auth, &sign("k2", "ESP_1: [20.00000] AC:push: Code 200", now + 301), now + 301));
+    assert!(!verify(&mut auth, &sign("k1", "ESP_2: [20.00000] AC:push: Code 200", now + 301), now + 301));
+    assert!(!verify(&mut auth, &sign("k1", "ESP_1: [20.00000] AC:push: Code 200", now), now + 1000));
+
+    let rendered = metrics.render();
+    assert!(rendered.contains("sensorweb_datagrams_rejected_total{reason=\"replayed\"} 3\n"));
+    assert!(rendered.contains("sensorweb_datagrams_rejected_total{reason=\"unsigned\"} 1\n"));
+    assert!(rendered.contains("sensorweb_datagrams_rejected_total{reason=\"bad_signature\"} 1\n"));
+    assert!(rendered.contains("sensorweb_datagrams_rejected_total{reason=\"unknown_host\"} 1\n"));
+    assert!(rendered.contains("sensorweb_datagrams_rejected_total{reason=\"stale\"} 1\n"));
+
+    let mut optional = Authenticator::new(AuthKeys::default(), true, metrics.clone());
+    assert!(verify(&mut optional, "ESP_3: [2.89900] UP: 1.0:May 14 2017 01:34:24@192.168.1.29", now));
+}
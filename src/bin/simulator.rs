@@ -0,0 +1,415 @@
+//! Simulates a fleet of sensorweb-NodeMCU-PMS3003 nodes, emitting the same log
+//! lines as the firmware so the collector can be exercised without hardware.
+
+extern crate clap;
+
+use std::f64::consts::PI;
+use std::net::{IpAddr, SocketAddr, UdpSocket};
+use std::thread;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+#[derive(Debug, Eq, PartialEq, Clone, Copy)]
+enum Pm25Pattern {
+    Constant,
+    Sine,
+    Random,
+    Spike
+}
+
+#[derive(Debug, Clone)]
+struct SimConfig {
+    target:       SocketAddr,
+    nodes:        usize,
+    interval:     f64,
+    cycles:       u64,
+    pattern:      Pm25Pattern,
+    base:         f64,
+    amplitude:    f64,
+    ntp_failure:  f64,
+    push_failure: f64,
+    seed:         u64
+}
+
+/// xorshift64*, plenty for simulated noise and keeps the simulator dependency-free
+struct Rng(u64);
+
+impl Rng {
+    fn next_u64(&mut self) -> u64 {
+        self.0 ^= self.0 >> 12;
+        self.0 ^= self.0 << 25;
+        self.0 ^= self.0 >> 27;
+        self.0.wrapping_mul(0x2545F4914F6CDD1D)
+    }
+
+    /// Uniform in [0, 1)
+    fn next_f64(&mut self) -> f64 {
+        (self.next_u64() >> 11) as f64 / (1u64 << 53) as f64
+    }
+
+    fn chance(&mut self, p: f64) -> bool {
+        self.next_f64() < p
+    }
+
+    fn uuid(&mut self) -> String {
+        let a = self.next_u64();
+        let b = self.next_u64();
+        format!("{:08x}-{:04x}-{:04x}-{:04x}-{:012x}",
+                a >> 32, (a >> 16) & 0xFFFF, a & 0xFFFF, b >> 48, b & 0xFFFF_FFFF_FFFF)
+    }
+}
+
+/// Days since 1970-01-01 to (year, month, day), proleptic Gregorian calendar
+fn civil_from_days(z: i64) -> (i64, u32, u32) {
+    let z = z + 719468;
+    let era = (if z >= 0 { z } else { z - 146096 }) / 146097;
+    let doe = z - era * 146097;
+    let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146096) / 365;
+    let y = yoe + era * 400;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let d = (doy - (153 * mp + 2) / 5 + 1) as u32;
+    let m = (if mp < 10 { mp + 3 } else { mp - 9 }) as u32;
+    (if m <= 2 { y + 1 } else { y }, m, d)
+}
+
+/// Broken-down UTC time: (year, month, day, hour, minute, second, millisecond)
+fn utc(t: SystemTime) -> (i64, u32, u32, u32, u32, u32, u32) {
+    let d = t.duration_since(UNIX_EPOCH).unwrap_or(Duration::from_secs(0));
+    let secs = d.as_secs() as i64;
+    let (y, mo, day) = civil_from_days(secs / 86400);
+    let rem = (secs % 86400) as u32;
+    (y, mo, day, rem / 3600, (rem / 60) % 60, rem % 60, d.subsec_nanos() / 1_000_000)
+}
+
+fn iso8601(t: SystemTime) -> String {
+    let (y, mo, d, h, mi, s, ms) = utc(t);
+    format!("{:04}-{:02}-{:02}T{:02}:{:02}:{:02}.{:03}+00:00", y, mo, d, h, mi, s, ms)
+}
+
+fn ntp_date(t: SystemTime) -> String {
+    let (y, mo, d, h, mi, s, _) = utc(t);
+    format!("{:02}:{:02}:{:02} {:02}/{:02}/{:04}", h, mi, s, d, mo, y)
+}
+
+struct SimNode {
+    host:        String,
+    ip:          String,
+    phase:       f64,
+    booted:      bool,
+    session:     Option<String>,
+    wake_cycles: u64,
+    ntp_errors:  u64,
+    slowdown:    f64,
+    next_wake:   Instant
+}
+
+impl SimNode {
+    fn new(idx: usize, rng: &mut Rng) -> SimNode {
+        SimNode {
+            host:        format!("ESP_{:06X}", 0xD42700 + idx),
+            ip:          format!("192.168.1.{}", 10 + idx % 240),
+            phase:       rng.next_f64() * 2.0 * PI,
+            booted:      false,
+            session:     None,
+            wake_cycles: 0,
+            ntp_errors:  0,
+            slowdown:    1.0 + rng.next_f64() * 0.05,
+            next_wake:   Instant::now()
+        }
+    }
+
+    fn line(&self, uptime: f64, payload: &str) -> String {
+        format!("{}: [{:.5}] {}", self.host, uptime, payload)
+    }
+
+    fn pm25(&self, config: &SimConfig, elapsed: f64, rng: &mut Rng) -> u32 {
+        let value = match config.pattern {
+            Pm25Pattern::Constant => config.base,
+            Pm25Pattern::Sine     => config.base + config.amplitude * (elapsed / 3600.0 * 2.0 * PI + self.phase).sin(),
+            Pm25Pattern::Random   => config.base + config.amplitude * (rng.next_f64() * 2.0 - 1.0),
+            Pm25Pattern::Spike    => {
+                if rng.chance(0.05) {
+                    config.base + config.amplitude
+                } else {
+                    config.base
+                }
+            }
+        };
+
+        if value < 0.0 { 0 } else { value.round() as u32 }
+    }
+
+    /// Lines emitted during one wake-up, from boot to going back to deep sleep
+    fn wake(&mut self, config: &SimConfig, now: SystemTime, elapsed: f64, rng: &mut Rng) -> Vec<String> {
+        let mut rv = Vec::new();
+        let mut uptime = 2.0 + rng.next_f64();
+        self.wake_cycles += 1;
+
+        if !self.booted {
+            self.booted = true;
+            rv.push(self.line(uptime, &format!("UP: 1.0:May 14 2017 01:34:24@{}", self.ip)));
+        }
+
+        uptime += 2.0 + rng.next_f64() * 3.0;
+        if rng.chance(config.ntp_failure) {
+            self.ntp_errors += 1;
+            rv.push(self.line(uptime, &format!("Loop: no NTP initial sync, waiting ... sleepWakeCycles={} ntpErrors={}",
+                                               self.wake_cycles, self.ntp_errors)));
+            return rv;
+        }
+        rv.push(self.line(uptime, &format!("NTPSyncEvent: {}", ntp_date(now))));
+
+        let session = match self.session.clone() {
+            Some(s) => s,
+            None    => {
+                let s = rng.uuid();
+                uptime += 0.2;
+                rv.push(self.line(uptime, &format!("SessionUUID: {}", s)));
+                self.session = Some(s.clone());
+                s
+            }
+        };
+
+        let pm25 = self.pm25(config, elapsed, rng);
+        uptime += 2.5 + rng.next_f64();
+        let code = if rng.chance(config.push_failure) {
+            if rng.chance(0.5) { -1 } else { 500 }
+        } else {
+            200
+        };
+        rv.push(self.line(uptime, &format!("AC:push: Code {}", code)));
+
+        uptime += 0.005;
+        rv.push(self.line(uptime, &format!("NTP: {} PM2.5: {} UUID:{} sent:{}",
+                                           iso8601(now), pm25, session, if code == 200 { 1 } else { 0 })));
+
+        uptime += 0.09;
+        let execution = uptime;
+        let next_interval = config.interval - execution;
+        rv.push(self.line(uptime, &format!("Loop: deepSleep: nextInterval={:.2}; executionTime={:.2} ; slowDownFactor={:.5}; deepSleep({:.2})",
+                                           next_interval, execution, self.slowdown, next_interval * self.slowdown)));
+        rv
+    }
+}
+
+fn to_pattern(o: Option<&str>) -> Pm25Pattern {
+    match o.unwrap_or("sine") {
+        "constant" => Pm25Pattern::Constant,
+        "random"   => Pm25Pattern::Random,
+        "spike"    => Pm25Pattern::Spike,
+        _          => Pm25Pattern::Sine
+    }
+}
+
+fn to_f64(o: Option<&str>, default: f64) -> f64 {
+    o.and_then(|v| v.parse::<f64>().ok()).unwrap_or(default)
+}
+
+fn to_ratio(o: Option<&str>, default: f64) -> f64 {
+    to_f64(o, default).max(0.0).min(1.0)
+}
+
+fn from_cli() -> SimConfig {
+    let matches = clap::App::new("sensorweb-NodeMCU-simulator")
+                          .version("0.1")
+                          .author("<lissyx@lissyx.dyndns.org>")
+                          .about("Simulates sensorweb-NodeMCU nodes sending their log lines over UDP.")
+                          .arg(clap::Arg::with_name("mcast")
+                               .short("m")
+                               .long("mcast")
+                               .value_name("MCAST")
+                               .help("Multicast address")
+                               .takes_value(true))
+                          .arg(clap::Arg::with_name("port")
+                               .short("p")
+                               .long("port")
+                               .value_name("PORT")
+                               .help("Multicast port")
+                               .takes_value(true))
+                          .arg(clap::Arg::with_name("loopback")
+                               .long("loopback")
+                               .help("Send unicast to 127.0.0.1 instead of the multicast group"))
+                          .arg(clap::Arg::with_name("nodes")
+                               .short("n")
+                               .long("nodes")
+                               .value_name("COUNT")
+                               .help("Number of simulated nodes")
+                               .takes_value(true))
+                          .arg(clap::Arg::with_name("interval")
+                               .long("interval")
+                               .value_name("SECS")
+                               .help("Seconds between two wake-ups of a node")
+                               .takes_value(true))
+                          .arg(clap::Arg::with_name("cycles")
+                               .long("cycles")
+                               .value_name("COUNT")
+                               .help("Stop after COUNT wake-ups per node, 0 runs forever")
+                               .takes_value(true))
+                          .arg(clap::Arg::with_name("pattern")
+                               .long("pattern")
+                               .value_name("PATTERN")
+                               .help("PM2.5 pattern")
+                               .possible_values(&["constant", "sine", "random", "spike"])
+                               .takes_value(true))
+                          .arg(clap::Arg::with_name("base")
+                               .long("base")
+                               .value_name("UG_M3")
+                               .help("Baseline PM2.5 value")
+                               .takes_value(true))
+                          .arg(clap::Arg::with_name("amplitude")
+                               .long("amplitude")
+                               .value_name("UG_M3")
+                               .help("PM2.5 variation around the baseline")
+                               .takes_value(true))
+                          .arg(clap::Arg::with_name("ntp_failure")
+                               .long("ntp-failure")
+                               .value_name("RATIO")
+                               .help("Probability of a wake-up failing its NTP sync")
+                               .takes_value(true))
+                          .arg(clap::Arg::with_name("push_failure")
+                               .long("push-failure")
+                               .value_name("RATIO")
+                               .help("Probability of an AirCasting push failing")
+                               .takes_value(true))
+                          .arg(clap::Arg::with_name("seed")
+                               .long("seed")
+                               .value_name("SEED")
+                               .help("Random seed, for reproducible runs")
+                               .takes_value(true))
+                          .get_matches();
+
+    let port = matches.value_of("port").and_then(|p| p.parse::<u16>().ok()).unwrap_or(8899);
+    let ip = if matches.is_present("loopback") {
+        "127.0.0.1".parse::<IpAddr>().unwrap()
+    } else {
+        matches.value_of("mcast").and_then(|ip| ip.parse::<IpAddr>().ok())
+                                 .unwrap_or("239.0.0.1".parse::<IpAddr>().unwrap())
+    };
+
+    let default_seed = SystemTime::now().duration_since(UNIX_EPOCH).map(|d| d.as_secs()).unwrap_or(42);
+
+    SimConfig {
+        target:       SocketAddr::new(ip, port),
+        nodes:        matches.value_of("nodes").and_then(|n| n.parse::<usize>().ok()).unwrap_or(3),
+        interval:     to_f64(matches.value_of("interval"), 30.0).max(15.0),
+        cycles:       matches.value_of("cycles").and_then(|n| n.parse::<u64>().ok()).unwrap_or(0),
+        pattern:      to_pattern(matches.value_of("pattern")),
+        base:         to_f64(matches.value_of("base"), 12.0),
+        amplitude:    to_f64(matches.value_of("amplitude"), 8.0),
+        ntp_failure:  to_ratio(matches.value_of("ntp_failure"), 0.05),
+        push_failure: to_ratio(matches.value_of("push_failure"), 0.1),
+        // xorshift must not be seeded with 0
+        seed:         matches.value_of("seed").and_then(|s| s.parse::<u64>().ok()).unwrap_or(default_seed) | 1
+    }
+}
+
+fn main() {
+    let config = from_cli();
+    println!("Simulating {} nodes towards {}", config.nodes, config.target);
+
+    let bind = match config.target {
+        SocketAddr::V4(_) => "0.0.0.0:0",
+        SocketAddr::V6(_) => "[::]:0"
+    };
+    let socket = UdpSocket::bind(bind).expect("couldn't bind socket");
+
+    let mut rng = Rng(config.seed);
+    let start = Instant::now();
+    let mut nodes: Vec<SimNode> = (0..config.nodes).map(|idx| SimNode::new(idx, &mut rng)).collect();
+
+    // Spread the first wake-ups over one interval, like a fleet powered on at different times
+    for node in nodes.iter_mut() {
+        let offset = rng.next_f64() * config.interval;
+        node.next_wake = start + Duration::from_millis((offset * 1000.0) as u64);
+    }
+
+    loop {
+        let idx = match nodes.iter().enumerate()
+                             .filter(|&(_, n)| config.cycles == 0 || n.wake_cycles < config.cycles)
+                             .min_by_key(|&(_, n)| n.next_wake) {
+            Some((idx, _)) => idx,
+            None           => break
+        };
+
+        let now = Instant::now();
+        if nodes[idx].next_wake > now {
+            thread::sleep(nodes[idx].next_wake - now);
+        }
+
+        let elapsed = start.elapsed();
+        let elapsed = elapsed.as_secs() as f64 + elapsed.subsec_nanos() as f64 / 1e9;
+        let node = &mut nodes[idx];
+        for line in node.wake(&config, SystemTime::now(), elapsed, &mut rng) {
+            println!("{}", line);
+            if let Err(err) = socket.send_to(line.as_bytes(), config.target) {
+                eprintln!("send to {} failed: {}", config.target, err);
+            }
+        }
+        node.next_wake += Duration::from_millis((config.interval * 1000.0) as u64);
+    }
+}
+
+#[cfg(test)]
+fn test_config(ntp_failure: f64, push_failure: f64) -> SimConfig {
+    SimConfig {
+        target:       "127.0.0.1:8899".parse().unwrap(),
+        nodes:        1,
+        interval:     300.0,
+        cycles:       0,
+        pattern:      Pm25Pattern::Constant,
+        base:         12.0,
+        amplitude:    0.0,
+        ntp_failure:  ntp_failure,
+        push_failure: push_failure,
+        seed:         1
+    }
+}
+
+#[test]
+fn test_dates() {
+    let t = UNIX_EPOCH + Duration::new(1495812473, 0);
+    assert_eq!(iso8601(t), "2017-05-26T15:27:53.000+00:00");
+    assert_eq!(ntp_date(t), "15:27:53 26/05/2017");
+    assert_eq!(civil_from_days(0), (1970, 1, 1));
+    assert_eq!(civil_from_days(11016), (2000, 2, 29));
+}
+
+#[test]
+fn test_wake_cycle() {
+    let config = test_config(0.0, 0.0);
+    let mut rng = Rng(1);
+    let mut node = SimNode::new(0, &mut rng);
+    let t = UNIX_EPOCH + Duration::new(1495812473, 0);
+
+    let lines = node.wake(&config, t, 0.0, &mut rng);
+    assert_eq!(lines.len(), 6);
+    assert!(lines[0].starts_with("ESP_D42700: ["));
+    assert!(lines[0].ends_with("] UP: 1.0:May 14 2017 01:34:24@192.168.1.10"));
+    assert!(lines[1].ends_with("] NTPSyncEvent: 15:27:53 26/05/2017"));
+    assert!(lines[2].contains("] SessionUUID: "));
+    assert!(lines[3].ends_with("] AC:push: Code 200"));
+    assert!(lines[4].contains("] NTP: 2017-05-26T15:27:53.000+00:00 PM2.5: 12 UUID:"));
+    assert!(lines[4].ends_with(" sent:1"));
+    assert!(lines[5].contains("] Loop: deepSleep: nextInterval="));
+
+    // Later wake-ups keep their session and do not announce UP again
+    let lines = node.wake(&config, t, 300.0, &mut rng);
+    assert_eq!(lines.len(), 4);
+    assert!(lines[0].contains("] NTPSyncEvent: "));
+}
+
+#[test]
+fn test_failures() {
+    let mut rng = Rng(1);
+    let t = UNIX_EPOCH + Duration::new(1495812473, 0);
+
+    let mut node = SimNode::new(0, &mut rng);
+    let lines = node.wake(&test_config(1.0, 0.0), t, 0.0, &mut rng);
+    assert_eq!(lines.len(), 2);
+    assert!(lines[1].ends_with("] Loop: no NTP initial sync, waiting ... sleepWakeCycles=1 ntpErrors=1"));
+
+    let mut node = SimNode::new(0, &mut rng);
+    let lines = node.wake(&test_config(0.0, 1.0), t, 0.0, &mut rng);
+    assert!(!lines[3].ends_with("Code 200"));
+    assert!(lines[4].ends_with(" sent:0"));
+}
@@ -0,0 +1,321 @@
+use std::fs;
+use std::fs::{File, OpenOptions};
+use std::io;
+use std::io::{BufRead, BufReader, Write};
+use std::net::SocketAddr;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::sync::mpsc::SyncSender;
+use std::thread;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use args::ReplayPacing;
+use auth::split_trailer;
+use ingest::parse;
+use message::{NetworkMsg, ParserRegistry, Transport};
+
+/// One captured datagram: receive time, source, transport it came over and raw payload
+#[derive(Debug, PartialEq)]
+pub struct CaptureRecord {
+    pub received:  f64,
+    pub source:    String,
+    pub transport: Transport,
+    pub payload:   Vec<u8>
+}
+
+/// Keeps one record per line: tabs, newlines, backslashes and non-printable bytes get escaped
+fn escape(payload: &[u8]) -> String {
+    let mut rv = String::with_capacity(payload.len());
+    for &b in payload {
+        match b {
+            b'\\'          => rv.push_str("\\\\"),
+            b'\t'          => rv.push_str("\\t"),
+            b'\n'          => rv.push_str("\\n"),
+            b'\r'          => rv.push_str("\\r"),
+            0x20..=0x7E    => rv.push(b as char),
+            _              => rv.push_str(&format!("\\x{:02x}", b))
+        }
+    }
+    rv
+}
+
+fn unescape(s: &str) -> Option<Vec<u8>> {
+    let mut rv = Vec::with_capacity(s.len());
+    let bytes = s.as_bytes();
+    let mut i = 0;
+    while i < bytes.len() {
+        if bytes[i] != b'\\' {
+            rv.push(bytes[i]);
+            i += 1;
+            continue;
+        }
+
+        match bytes.get(i + 1) {
+            Some(&b'\\') => rv.push(b'\\'),
+            Some(&b't')  => rv.push(b'\t'),
+            Some(&b'n')  => rv.push(b'\n'),
+            Some(&b'r')  => rv.push(b'\r'),
+            Some(&b'x')  => {
+                let hex = s.get(i + 2..i + 4)?;
+                rv.push(u8::from_str_radix(hex, 16).ok()?);
+                i += 2;
+            },
+            _ => return None
+        }
+        i += 2;
+    }
+    Some(rv)
+}
+
+fn unix_time(t: SystemTime) -> f64 {
+    match t.duration_since(UNIX_EPOCH) {
+        Ok(d)  => d.as_secs() as f64 + d.subsec_nanos() as f64 / 1e9,
+        Err(_) => 0.0
+    }
+}
+
+pub fn format_record(received: SystemTime, source: &SocketAddr, transport: Transport, payload: &[u8]) -> String {
+    format!("{:.6}\t{}\t{}\t{}\n", unix_time(received), source, transport.name(), escape(payload))
+}
+
+/// Parses a record, those of older captures having no transport and coming from multicast
+pub fn parse_record(line: &str) -> Option<CaptureRecord> {
+    let fields: Vec<&str> = line.trim_end_matches(|c| c == '\n' || c == '\r').splitn(4, '\t').collect();
+    let (transport, payload) = match fields.len() {
+        3 => (Transport::Multicast, fields[2]),
+        4 => (Transport::from_name(fields[2])?, fields[3]),
+        _ => return None
+    };
+
+    Some(CaptureRecord {
+        received:  fields[0].parse::<f64>().ok()?,
+        source:    String::from(fields[1]),
+        transport: transport,
+        payload:   unescape(payload)?
+    })
+}
+
+/// Appends raw datagrams to `path`, rotating to `path.1` ... `path.N` once it exceeds `max_bytes`
+pub struct CaptureWriter {
+    path:      PathBuf,
+    max_bytes: u64,
+    max_files: usize,
+    file:      File,
+    written:   u64
+}
+
+impl CaptureWriter {
+    pub fn open(path: &Path, max_bytes: u64, max_files: usize) -> io::Result<CaptureWriter> {
+        let file = OpenOptions::new().create(true).append(true).open(path)?;
+        let written = file.metadata()?.len();
+
+        Ok(CaptureWriter {
+            path:      path.to_path_buf(),
+            max_bytes: max_bytes,
+            max_files: max_files,
+            file:      file,
+            written:   written
+        })
+    }
+
+    fn rotated(&self, idx: usize) -> PathBuf {
+        let mut name = self.path.clone().into_os_string();
+        name.push(format!(".{}", idx));
+        PathBuf::from(name)
+    }
+
+    fn rotate(&mut self) -> io::Result<()> {
+        if self.max_files == 0 {
+            self.file = File::create(&self.path)?;
+        } else {
+            for idx in (1..self.max_files).rev() {
+                let from = self.rotated(idx);
+                if from.exists() {
+                    fs::rename(&from, self.rotated(idx + 1))?;
+                }
+            }
+            fs::rename(&self.path, self.rotated(1))?;
+            self.file = OpenOptions::new().create(true).append(true).open(&self.path)?;
+        }
+
+        self.written = 0;
+        Ok(())
+    }
+
+    pub fn record(&mut self, received: SystemTime, source: &SocketAddr, transport: Transport, payload: &[u8]) {
+        if self.written >= self.max_bytes {
+            info!("Rotating capture file {:?}", self.path);
+            if let Err(err) = self.rotate() {
+                error!("Unable to rotate capture file {:?}: {}", self.path, err);
+            }
+        }
+
+        let line = format_record(received, source, transport, payload);
+        match self.file.write_all(line.as_bytes()) {
+            Ok(_)    => self.written += line.len() as u64,
+            Err(err) => error!("Unable to write capture file {:?}: {}", self.path, err)
+        }
+    }
+}
+
+/// Capture file every listener writes to
+pub type SharedCapture = Arc<Mutex<CaptureWriter>>;
+
+/// Records a datagram received over `transport` to `capture`, if capturing
+pub fn capture(capture: &Option<SharedCapture>, source: &SocketAddr, transport: Transport, payload: &[u8]) {
+    if let Some(ref writer) = *capture {
+        writer.lock().unwrap_or_else(|e| e.into_inner()).record(SystemTime::now(), source, transport, payload);
+    }
+}
+
+/// Feeds a capture file through the parser into `tx`, as the listener each datagram came from would
+pub fn replay(path: PathBuf, pacing: ReplayPacing, tx: SyncSender<NetworkMsg>, parsers: Arc<ParserRegistry>) {
+    info!("Replaying capture {:?} ({:?})", path, pacing);
+
+    let file = match File::open(&path) {
+        Ok(f)    => f,
+        Err(err) => {
+            error!("Unable to open capture {:?}: {}", path, err);
+            return;
+        }
+    };
+
+    let mut previous: Option<f64> = None;
+    let mut count = 0;
+    for (lineno, line) in BufReader::new(file).lines().enumerate() {
+        let line = match line {
+            Ok(l)    => l,
+            Err(err) => {
+                error!("Error reading capture {:?}: {}", path, err);
+                break;
+            }
+        };
+
+        let record = match parse_record(&line) {
+            Some(r) => r,
+            None    => {
+                warn!("Skipping malformed capture line {}: {:?}", lineno + 1, line);
+                continue;
+            }
+        };
+
+        if pacing == ReplayPacing::RealTime {
+            if let Some(prev) = previous {
+                let delay = record.received - prev;
+                if delay > 0.0 {
+                    thread::sleep(Duration::new(delay.trunc() as u64, (delay.fract() * 1e9) as u32));
+                }
+            }
+            previous = Some(record.received);
+        }
+
+        // Captures are trusted, signatures would be stale by now anyway
+        let (s, _) = split_trailer(String::from_utf8_lossy(&record.payload).trim());
+        debug!("replaying {} datagram from {}: {}", record.transport.name(), record.source, s);
+        let ip = match record.source.parse::<SocketAddr>() {
+            Ok(addr) => addr.ip(),
+            Err(_)   => {
+                warn!("Skipping capture line {} with a bad source: {:?}", lineno + 1, record.source);
+                continue;
+            }
+        };
+        let mut msg = parse(&parsers, s, record.transport, &ip);
+        msg.transport = Transport::Replay;
+        msg.source = Some(record.source.clone());
+        if let Err(err) = tx.send(msg) {
+            error!("Error while sending message to thread: {:?}", err);
+            return;
+        }
+        count += 1;
+    }
+
+    info!("Replay of {:?} done, {} datagrams", path, count);
+}
+
+#[test]
+fn test_escape_roundtrip() {
+    let payloads: Vec<&[u8]> = vec![
+        b"ESP_D427A9: [11.06000] AC:push: Code 200",
+        b"tab\there\nnewline\\backslash\r",
+        b"\x00\xff\xc3\x28 binary"
+    ];
+
+    for p in payloads {
+        let escaped = escape(p);
+        assert!(!escaped.contains('\t'));
+        assert!(!escaped.contains('\n'));
+        assert_eq!(unescape(&escaped).unwrap(), p.to_vec());
+    }
+
+    assert_eq!(unescape("bad\\q"), None);
+    assert_eq!(unescape("bad\\x4"), None);
+}
+
+#[test]
+fn test_record_roundtrip() {
+    let src: SocketAddr = "192.168.1.29:4210".parse().unwrap();
+    let when = UNIX_EPOCH + Duration::new(1495805273, 500000000);
+    let line = format_record(when, &src, Transport::Tcp, b"ESP_D427A9: [2.89900] UP: 1.0");
+    assert_eq!(line, "1495805273.500000\t192.168.1.29:4210\ttcp\tESP_D427A9: [2.89900] UP: 1.0\n");
+
+    assert_eq!(parse_record(&line), Some(CaptureRecord {
+        received:  1495805273.5,
+        source:    String::from("192.168.1.29:4210"),
+        transport: Transport::Tcp,
+        payload:   b"ESP_D427A9: [2.89900] UP: 1.0".to_vec()
+    }));
+    assert_eq!(parse_record("garbage"), None);
+    assert_eq!(parse_record("1.0\t192.168.1.29:4210\tcarrier pigeon\tESP_1: [1.0] UP: 1.0"), None);
+
+    // Captures written before transports were recorded only hold multicast datagrams
+    assert_eq!(parse_record("1495805273.500000\t192.168.1.29:4210\tESP_D427A9: [2.89900] UP: 1.0").unwrap().transport,
+               Transport::Multicast);
+}
+
+#[test]
+fn test_replay_transports() {
+    use std::env;
+    use std::sync::mpsc::sync_channel;
+
+    let path = env::temp_dir().join(format!("replay-test-{}.log", unix_time(SystemTime::now())));
+    let src: SocketAddr = "192.168.1.29:514".parse().unwrap();
+    let when = UNIX_EPOCH + Duration::new(1495805273, 0);
+    {
+        let mut f = File::create(&path).unwrap();
+        f.write_all(format_record(when, &src, Transport::Syslog, b"<134>May 26 14:27:53 ESP_D427A9 sensorweb: [11.06000] AC:push: Code 200").as_bytes()).unwrap();
+        f.write_all(format_record(when, &src, Transport::Udp, b"ESP_D427A9: [12.00000] AC:push: Code 200").as_bytes()).unwrap();
+    }
+
+    let (tx, rx) = sync_channel(10);
+    replay(path.clone(), ReplayPacing::AsFastAsPossible, tx, Arc::new(ParserRegistry::builtin()));
+    fs::remove_file(&path).unwrap();
+
+    let msgs: Vec<NetworkMsg> = rx.iter().collect();
+    assert_eq!(msgs.iter().map(|m| (m.host.as_str(), m.time)).collect::<Vec<_>>(),
+               vec![("ESP_D427A9", 11.06), ("ESP_D427A9", 12.0)]);
+    assert!(msgs.iter().all(|m| m.transport == Transport::Replay));
+}
+
+#[test]
+fn test_rotation() {
+    use std::env;
+
+    let dir = env::temp_dir().join(format!("capture-test-{}", unix_time(SystemTime::now())));
+    fs::create_dir_all(&dir).unwrap();
+    let path = dir.join("capture.log");
+    let src: SocketAddr = "127.0.0.1:1".parse().unwrap();
+
+    {
+        let mut w = CaptureWriter::open(&path, 10, 2).unwrap();
+        for _ in 0..4 {
+            w.record(SystemTime::now(), &src, Transport::Multicast, b"0123456789");
+        }
+    }
+
+    assert!(path.exists());
+    assert!(dir.join("capture.log.1").exists());
+    assert!(dir.join("capture.log.2").exists());
+    assert!(!dir.join("capture.log.3").exists());
+
+    fs::remove_dir_all(&dir).unwrap();
+}
@@ -0,0 +1,252 @@
+use std::collections::BTreeMap;
+use std::sync::{Arc, Mutex};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use chrono::FixedOffset;
+
+use message::{MessageType, NetworkMsg, NtpSyncEvent};
+use metrics::SharedMetrics;
+use sink::MessageEnricher;
+
+/// Offsets beyond this many seconds make a device timestamp unreliable
+const MAX_OFFSET_SECS: f64 = 60.0;
+
+/// Device clocks before 2017-01-01 were never set
+const MIN_VALID_EPOCH: f64 = 1483228800.0;
+
+/// Clock and NTP health of a node, as served on `/api/clock`
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct ClockHealth {
+    /// Device minus collector time of the last reading, in seconds
+    pub offset:        Option<f64>,
+    /// Offset change since the last sync, in parts per million
+    pub drift_ppm:     Option<f64>,
+    /// Collector time the device woke up, from its uptime
+    pub awake_since:   Option<f64>,
+    pub last_sync:     Option<f64>,
+    pub synced:        bool,
+    pub ntp_errors:    u32,
+    pub sync_failures: u32,
+    /// Why the last device timestamp should not be trusted
+    pub unreliable:    Option<String>
+}
+
+#[derive(Debug)]
+struct NodeClock {
+    health:    ClockHealth,
+    /// Zone of the last NTP reading, NTPSyncEvent times being printed in it
+    zone:      FixedOffset,
+    /// First (collector time, offset) seen since the last sync, drift is measured from there
+    baseline:  Option<(f64, f64)>
+}
+
+impl NodeClock {
+    fn new() -> NodeClock {
+        NodeClock {
+            health:    ClockHealth {
+                offset:        None,
+                drift_ppm:     None,
+                awake_since:   None,
+                last_sync:     None,
+                // Nodes already running when the collector starts are given the benefit of the doubt
+                synced:        true,
+                ntp_errors:    0,
+                sync_failures: 0,
+                unreliable:    None
+            },
+            zone:      FixedOffset::east(0),
+            baseline:  None
+        }
+    }
+
+    /// Updates the node from a message received at `now`, returning the new sync failures
+    fn observe(&mut self, msg: &NetworkMsg, now: f64) -> u32 {
+        let vals = &msg.msg.mvals;
+        let mut failures = 0;
+
+        if msg.time >= 0.0 {
+            self.health.awake_since = Some(now - msg.time as f64);
+        }
+
+        match msg.msg.mtype {
+            MessageType::NodeUp => {
+                self.health.synced = false;
+                self.health.ntp_errors = 0;
+                self.baseline = None;
+            },
+            MessageType::NtpSync => match msg.msg.ntpsync_event() {
+                Some(NtpSyncEvent::Synced(dt)) => {
+                    self.health.synced = true;
+                    self.health.ntp_errors = 0;
+                    self.health.last_sync = Some(now);
+                    self.health.offset = Some(dt.timestamp() as f64 - now);
+                    self.health.drift_ppm = None;
+                    self.baseline = None;
+                },
+                Some(NtpSyncEvent::Error(_)) => {
+                    failures = 1;
+                    self.health.sync_failures += 1;
+                },
+                Some(NtpSyncEvent::Adjustment { .. }) => {
+                    // The clock stepped, drift has to be measured anew
+                    self.health.drift_ppm = None;
+                    self.baseline = None;
+                },
+                None => {}
+            },
+            MessageType::Loop => {
+                if vals.get("action").map(|a| a == "waitntp").unwrap_or(false) {
+                    self.health.synced = false;
+                    if let Some(n) = vals.get("ntperrors").and_then(|n| n.parse::<u32>().ok()) {
+                        if n > self.health.ntp_errors {
+                            failures = n - self.health.ntp_errors;
+                        }
+                        self.health.ntp_errors = n;
+                    }
+                    self.health.sync_failures += failures;
+                }
+            },
+            MessageType::Ntp => {
+                self.health.unreliable = match msg.msg.datetime {
+                    None     => Some(String::from("unparseable datetime")),
+                    Some(dt) => {
+                        let device = dt.timestamp() as f64 + dt.timestamp_subsec_millis() as f64 / 1000.0;
+                        let offset = device - now;
+                        self.zone = *dt.offset();
+                        self.health.offset = Some(offset);
+
+                        match self.baseline {
+                            Some((t0, o0)) if now > t0 => self.health.drift_ppm = Some((offset - o0) / (now - t0) * 1e6),
+                            Some(_)                    => {},
+                            None                       => self.baseline = Some((now, offset))
+                        }
+
+                        if device < MIN_VALID_EPOCH {
+                            Some(String::from("clock not set"))
+                        } else if !self.health.synced {
+                            Some(String::from("not synced since boot"))
+                        } else if offset.abs() > MAX_OFFSET_SECS {
+                            Some(format!("offset {:.0}s", offset))
+                        } else {
+                            None
+                        }
+                    }
+                };
+            },
+            _ => {}
+        }
+
+        failures
+    }
+}
+
+pub type SharedClocks = Arc<Mutex<BTreeMap<String, ClockHealth>>>;
+
+/// Enricher tracking device clocks, annotating readings with their offset and reliability
+pub struct ClockEnricher {
+    nodes:   BTreeMap<String, NodeClock>,
+    latest:  SharedClocks,
+    metrics: SharedMetrics
+}
+
+impl ClockEnricher {
+    pub fn new(metrics: SharedMetrics) -> ClockEnricher {
+        ClockEnricher {
+            nodes:   BTreeMap::new(),
+            latest:  Arc::new(Mutex::new(BTreeMap::new())),
+            metrics: metrics
+        }
+    }
+
+    pub fn latest(&self) -> SharedClocks {
+        self.latest.clone()
+    }
+
+    fn observe(&mut self, msg: &mut NetworkMsg, now: f64) {
+        let node = self.nodes.entry(msg.host.clone()).or_insert_with(NodeClock::new);
+        msg.msg.set_zone(node.zone);
+        let failures = node.observe(msg, now);
+        let health = node.health.clone();
+        let labels = [("host", msg.host.as_str())];
+
+        if failures > 0 {
+            self.metrics.counter_add("sensorweb_ntp_sync_failures_total", "NTP sync failures reported by the nodes", &labels, failures as f64);
+        }
+        if let Some(offset) = health.offset {
+            self.metrics.gauge("sensorweb_clock_offset_seconds", "Device clock minus collector clock", &labels, offset);
+        }
+        if let Some(drift) = health.drift_ppm {
+            self.metrics.gauge("sensorweb_clock_drift_ppm", "Device clock drift since the last NTP sync", &labels, drift);
+        }
+
+        if msg.msg.mtype == MessageType::Ntp {
+            if let Some(offset) = health.offset {
+                msg.annotations.insert(String::from("clock.offset"), format!("{:.3}", offset));
+            }
+            if let Some(ref reason) = health.unreliable {
+                msg.annotations.insert(String::from("clock.unreliable"), reason.clone());
+            }
+        }
+
+        self.latest.lock().unwrap().insert(msg.host.clone(), health);
+    }
+}
+
+impl MessageEnricher for ClockEnricher {
+    fn name(&self) -> &str {
+        "clock"
+    }
+
+    fn enrich(&mut self, msg: &mut NetworkMsg) {
+        let now = SystemTime::now().duration_since(UNIX_EPOCH)
+                                   .map(|d| d.as_secs() as f64 + d.subsec_nanos() as f64 / 1e9)
+                                   .unwrap_or(0.0);
+        self.observe(msg, now);
+    }
+}
+
+#[test]
+fn test_clock_enricher() {
+    use message::parse_from_string;
+    use metrics::Metrics;
+
+    let metrics = Metrics::new();
+    let mut e = ClockEnricher::new(metrics.clone());
+    let msg = |e: &mut ClockEnricher, s: &str, now: f64| {
+        let mut m = parse_from_string(String::from(s));
+        e.observe(&mut m, now);
+        m
+    };
+
+    // Booted and unable to sync, anything the node timestamps is suspect
+    msg(&mut e, "ESP_1: [2.89900] UP: 1.0:May 14 2017 01:34:24@192.168.1.29", 1495808800.0);
+    msg(&mut e, "ESP_1: [4.07500] Loop: no NTP initial sync, waiting ... sleepWakeCycles=1 ntpErrors=2", 1495808802.0);
+    msg(&mut e, "ESP_1: [4.07500] Loop: no NTP initial sync, waiting ... sleepWakeCycles=2 ntpErrors=3", 1495808803.0);
+    let m = msg(&mut e, "ESP_1: [11.06500] NTP: 1970-01-01T00:00:07.000+01:00 PM2.5: 12 UUID:abc sent:0", 1495808804.0);
+    assert_eq!(m.annotations["clock.unreliable"], "clock not set");
+    assert_eq!(e.latest().lock().unwrap()["ESP_1"].sync_failures, 3);
+
+    // Synced: NTPSyncEvent is in local time, the zone comes from the readings
+    msg(&mut e, "ESP_1: [7.97900] NTPSyncEvent: 16:24:59 30/05/2017", 1496157899.0);
+    assert_eq!(e.latest().lock().unwrap()["ESP_1"].offset, Some(0.0));
+    let m = msg(&mut e, "ESP_1: [7.97900] NTPSyncEvent: 16:24:59 30/05/2017", 1496157899.0);
+    assert_eq!(m.msg.mvals["datetime"], "2017-05-30T16:24:59+01:00");
+    msg(&mut e, "ESP_1: [7.97900] NTPSyncEvent: Time Sync error -- NTP server not reachable", 1496157899.0);
+    assert_eq!(e.latest().lock().unwrap()["ESP_1"].sync_failures, 4);
+
+    let m = msg(&mut e, "ESP_1: [11.06500] NTP: 2017-05-30T16:25:00.000+01:00 PM2.5: 12 UUID:abc sent:1", 1496157900.0);
+    assert_eq!(m.annotations["clock.offset"], "0.000");
+    assert!(!m.annotations.contains_key("clock.unreliable"));
+
+    // Drifting ahead by 1 second over 10000 seconds
+    let m = msg(&mut e, "ESP_1: [11.06500] NTP: 2017-05-30T19:11:41.000+01:00 PM2.5: 12 UUID:abc sent:1", 1496167900.0);
+    assert_eq!(m.annotations["clock.offset"], "1.000");
+    let health = e.latest().lock().unwrap()["ESP_1"].clone();
+    assert_eq!(health.drift_ppm, Some(100.0));
+    assert!((health.awake_since.unwrap() - (1496167900.0 - 11.065)).abs() < 0.001);
+
+    let m = msg(&mut e, "ESP_1: [11.06500] NTP: 2017-05-30T21:00:00.000+01:00 PM2.5: 12 UUID:abc sent:1", 1496167900.0);
+    assert_eq!(m.annotations["clock.unreliable"], "offset 6500s");
+
+    assert!(metrics.render().contains("sensorweb_ntp_sync_failures_total{host=\"ESP_1\"} 4\n"));
+}
@@ -0,0 +1,41 @@
+use chrono::{DateTime, FixedOffset, NaiveDateTime, TimeZone, Utc};
+
+/// Parses the `2017-05-26T15:27:53.000+01:00` NTP datetime
+pub fn parse_datetime(s: &str) -> Result<DateTime<FixedOffset>, String> {
+    DateTime::parse_from_rfc3339(s.trim()).map_err(|e| format!("bad datetime {:?}: {}", s, e))
+}
+
+/// Parses the `16:24:59 30/05/2017` NTPSyncEvent time, device local time carrying no zone
+pub fn parse_ntp_date(s: &str) -> Option<NaiveDateTime> {
+    NaiveDateTime::parse_from_str(s.trim(), "%H:%M:%S %d/%m/%Y").ok()
+}
+
+/// Parses the `May 14 2017 01:34:24` firmware build date, which carries no zone and is taken as UTC
+pub fn parse_builddate(s: &str) -> Result<DateTime<Utc>, String> {
+    // __DATE__ pads single digit days with a space
+    let normalized = s.split_whitespace().collect::<Vec<&str>>().join(" ");
+    NaiveDateTime::parse_from_str(&normalized, "%b %d %Y %H:%M:%S")
+        .map(|n| Utc.from_utc_datetime(&n))
+        .map_err(|e| format!("bad build date {:?}: {}", s, e))
+}
+
+#[test]
+fn test_parse_dates() {
+    use chrono::NaiveDate;
+
+    let dt = parse_datetime("2017-05-26T15:27:53.000+01:00").unwrap();
+    assert_eq!((dt.timestamp(), dt.offset().local_minus_utc()), (1495808873, 3600));
+    assert_eq!(parse_datetime("2017-05-26T14:27:53Z").unwrap().timestamp(), 1495808873);
+    assert_eq!(parse_datetime("2017-05-26T10:57:53-03:30").unwrap().timestamp(), 1495808873);
+    assert_eq!(parse_datetime("1970-01-01T00:00:07.000+00:00").unwrap().timestamp(), 7);
+    assert!(parse_datetime("2017-13-26T15:27:53.000+01:00").is_err());
+    assert!(parse_datetime("garbage").is_err());
+
+    assert_eq!(parse_ntp_date("16:24:59 30/05/2017"), Some(NaiveDate::from_ymd(2017, 5, 30).and_hms(16, 24, 59)));
+    assert_eq!(parse_ntp_date("16:24:59"), None);
+    assert_eq!(parse_ntp_date("16:24 30/05/2017"), None);
+
+    assert_eq!(parse_builddate("May 14 2017 01:34:24"), Ok(Utc.ymd(2017, 5, 14).and_hms(1, 34, 24)));
+    assert_eq!(parse_builddate("Jun  4 2017 12:00:00"), Ok(Utc.ymd(2017, 6, 4).and_hms(12, 0, 0)));
+    assert!(parse_builddate("N/A").is_err());
+}
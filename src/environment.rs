@@ -0,0 +1,150 @@
+use std::collections::BTreeMap;
+use std::sync::{Arc, Mutex};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use message::{MessageType, NetworkMsg};
+use metrics::SharedMetrics;
+use sink::MessageEnricher;
+
+/// Humidity readings older than this many seconds are not used to correct PM2.5
+const HUMIDITY_MAX_AGE: u64 = 1800;
+
+/// Hygroscopic growth factor and density ratio of κ-Köhler theory, as fitted for the PMS sensors
+const KAPPA:   f64 = 0.62;
+const DENSITY: f64 = 1.65;
+
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct EnvReading {
+    pub value:   f64,
+    pub updated: u64
+}
+
+/// Latest temperature, humidity and pressure of every host, keyed by quantity
+pub type SharedEnvironment = Arc<Mutex<BTreeMap<String, BTreeMap<String, EnvReading>>>>;
+
+/// PM2.5 with the water taken up by particles at `humidity` %RH removed
+pub fn correct_pm25(pm25: f64, humidity: f64) -> f64 {
+    if humidity <= 0.0 {
+        return pm25;
+    }
+
+    // Growth diverges at saturation, the sensor is unusable there anyway
+    let rh = humidity.min(99.0) / 100.0;
+    pm25 / (1.0 + (KAPPA / DENSITY) / (1.0 / rh - 1.0))
+}
+
+fn gauge_name(quantity: &str) -> Option<(&'static str, &'static str)> {
+    match quantity {
+        "temperature" => Some(("sensorweb_temperature_celsius", "Last temperature reading in °C")),
+        "humidity"    => Some(("sensorweb_humidity_percent", "Last relative humidity reading in %")),
+        "pressure"    => Some(("sensorweb_pressure_hpa", "Last pressure reading in hPa")),
+        _             => None
+    }
+}
+
+/// Enricher keeping environmental readings, correcting PM2.5 readings for humidity
+pub struct EnvironmentEnricher {
+    latest:  SharedEnvironment,
+    metrics: SharedMetrics
+}
+
+impl EnvironmentEnricher {
+    pub fn new(metrics: SharedMetrics) -> EnvironmentEnricher {
+        EnvironmentEnricher {
+            latest:  Arc::new(Mutex::new(BTreeMap::new())),
+            metrics: metrics
+        }
+    }
+
+    pub fn latest(&self) -> SharedEnvironment {
+        self.latest.clone()
+    }
+
+    fn observe(&mut self, msg: &mut NetworkMsg, now: u64) {
+        let quantity = match msg.msg.mtype.measurement() {
+            Some(q) => q,
+            None    => return
+        };
+        let value = match msg.msg.mvals.get(quantity).and_then(|v| v.parse::<f64>().ok()).filter(|v| v.is_finite()) {
+            Some(v) => v,
+            None    => return
+        };
+        let labels = [("host", msg.host.as_str())];
+
+        if msg.msg.mtype == MessageType::Ntp {
+            let humidity = self.latest.lock().unwrap()
+                               .get(&msg.host)
+                               .and_then(|env| env.get("humidity"))
+                               .and_then(|h| if h.updated + HUMIDITY_MAX_AGE >= now { Some(h.value) } else { None });
+
+            if let Some(humidity) = humidity {
+                let corrected = correct_pm25(value, humidity);
+                msg.annotations.insert(String::from("humidity"), format!("{}", humidity));
+                msg.annotations.insert(String::from("pm2.5.corrected"), format!("{:.1}", corrected));
+                self.metrics.gauge("sensorweb_pm25_corrected", "Last PM2.5 reading corrected for humidity in µg/m³",
+                                   &labels, corrected);
+            }
+            return;
+        }
+
+        if let Some((name, help)) = gauge_name(quantity) {
+            self.metrics.gauge(name, help, &labels, value);
+        }
+        self.latest.lock().unwrap()
+                   .entry(msg.host.clone())
+                   .or_insert_with(BTreeMap::new)
+                   .insert(String::from(quantity), EnvReading { value: value, updated: now });
+    }
+}
+
+impl MessageEnricher for EnvironmentEnricher {
+    fn name(&self) -> &str {
+        "environment"
+    }
+
+    fn enrich(&mut self, msg: &mut NetworkMsg) {
+        let now = SystemTime::now().duration_since(UNIX_EPOCH).map(|d| d.as_secs()).unwrap_or(0);
+        self.observe(msg, now);
+    }
+}
+
+#[test]
+fn test_correct_pm25() {
+    assert_eq!(correct_pm25(20.0, 0.0), 20.0);
+    assert!((correct_pm25(20.0, 50.0) - 20.0 / (1.0 + 0.62 / 1.65)).abs() < 1e-9);
+    assert!(correct_pm25(20.0, 90.0) < correct_pm25(20.0, 50.0));
+    assert!(correct_pm25(20.0, 100.0) > 0.0);
+}
+
+#[test]
+fn test_environment_enricher() {
+    use message::parse_from_string;
+    use metrics::Metrics;
+
+    let metrics = Metrics::new();
+    let mut e = EnvironmentEnricher::new(metrics.clone());
+    let msg = |e: &mut EnvironmentEnricher, s: &str, now: u64| {
+        let mut m = parse_from_string(String::from(s));
+        e.observe(&mut m, now);
+        m
+    };
+
+    // No humidity yet, nothing to correct with
+    let m = msg(&mut e, "ESP_1: [11.06500] NTP: 2017-05-26T15:27:53.000+01:00 PM2.5: 20 UUID:abc sent:1", 1000);
+    assert!(m.annotations.is_empty());
+
+    msg(&mut e, "ESP_1: [11.07000] TEMP: 21.5C sensor:BME280", 1000);
+    msg(&mut e, "ESP_1: [11.07000] HUM: 50% sensor:BME280", 1000);
+    let m = msg(&mut e, "ESP_1: [11.06500] NTP: 2017-05-26T15:27:53.000+01:00 PM2.5: 20 UUID:abc sent:1", 1100);
+    assert_eq!(m.annotations["humidity"], "50");
+    assert_eq!(m.annotations["pm2.5.corrected"], "14.5");
+
+    // Stale humidity is ignored
+    let m = msg(&mut e, "ESP_1: [11.06500] NTP: 2017-05-26T15:27:53.000+01:00 PM2.5: 20 UUID:abc sent:1", 1000 + HUMIDITY_MAX_AGE + 1);
+    assert!(m.annotations.is_empty());
+
+    let latest = e.latest().lock().unwrap()["ESP_1"].clone();
+    assert_eq!(latest["temperature"], EnvReading { value: 21.5, updated: 1000 });
+    assert!(metrics.render().contains("sensorweb_humidity_percent{host=\"ESP_1\"} 50\n"));
+    assert!(metrics.render().contains("sensorweb_pm25_corrected{host=\"ESP_1\"} 14.5"));
+}
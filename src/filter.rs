@@ -0,0 +1,299 @@
+use std::collections::HashMap;
+use std::net::{IpAddr, Ipv4Addr};
+use std::str::FromStr;
+
+use message::{MessageType, NetworkMsg};
+use metrics::SharedMetrics;
+
+/// Seconds a host stays bound to the address of its last UP without being heard from
+const UP_IP_TTL: f64 = 3600.0;
+
+/// Most hosts bound to an address at once, host names being whatever senders claim
+const MAX_UP_IPS: usize = 4096;
+
+/// Address block such as `192.168.1.0/24` or `fd00::/8`, a bare address being a single host
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Cidr {
+    addr:   IpAddr,
+    prefix: u8
+}
+
+/// IPv4 address of an IPv4-mapped IPv6 one, `::ffff:a.b.c.d`, as dual stack sockets report IPv4 peers
+fn unmap(ip: &IpAddr) -> IpAddr {
+    if let IpAddr::V6(a) = *ip {
+        let o = a.octets();
+        if o[..10].iter().all(|&b| b == 0) && o[10] == 0xff && o[11] == 0xff {
+            return IpAddr::V4(Ipv4Addr::new(o[12], o[13], o[14], o[15]));
+        }
+    }
+    *ip
+}
+
+fn octets(ip: &IpAddr) -> Vec<u8> {
+    match *ip {
+        IpAddr::V4(a) => a.octets().to_vec(),
+        IpAddr::V6(a) => a.octets().to_vec()
+    }
+}
+
+impl Cidr {
+    pub fn parse(s: &str) -> Result<Cidr, String> {
+        let mut parts = s.trim().splitn(2, '/');
+        let addr = IpAddr::from_str(parts.next().unwrap_or(""))
+                       .map_err(|e| format!("bad address in {:?}: {}", s, e))?;
+        let width = octets(&addr).len() as u8 * 8;
+        let prefix = match parts.next() {
+            Some(p) => p.parse::<u8>().map_err(|e| format!("bad prefix in {:?}: {}", s, e))?,
+            None    => width
+        };
+        if prefix > width {
+            return Err(format!("prefix of {:?} longer than the address", s));
+        }
+
+        Ok(Cidr {
+            addr:   addr,
+            prefix: prefix
+        })
+    }
+
+    pub fn contains(&self, ip: &IpAddr) -> bool {
+        let (net, other) = (octets(&self.addr), octets(&unmap(ip)));
+        if net.len() != other.len() {
+            return false;
+        }
+
+        let (bytes, bits) = ((self.prefix / 8) as usize, self.prefix % 8);
+        net[..bytes] == other[..bytes] && (bits == 0 || (net[bytes] ^ other[bytes]) & (0xff << (8 - bits)) == 0)
+    }
+}
+
+/// Token bucket refilled at `rate` tokens per second, holding at most `burst`
+#[derive(Debug, Clone, PartialEq)]
+struct TokenBucket {
+    tokens: f64,
+    last:   f64
+}
+
+impl TokenBucket {
+    fn take(&mut self, rate: f64, burst: f64, now: f64) -> bool {
+        self.tokens = (self.tokens + (now - self.last).max(0.0) * rate).min(burst);
+        self.last = now;
+        if self.tokens >= 1.0 {
+            self.tokens -= 1.0;
+            true
+        } else {
+            false
+        }
+    }
+}
+
+/// Host name allowed by `pattern`, which may end with `*` to allow a prefix
+fn host_matches(pattern: &str, host: &str) -> bool {
+    if pattern.ends_with('*') {
+        host.starts_with(&pattern[..pattern.len() - 1])
+    } else {
+        pattern == host
+    }
+}
+
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct FilterConfig {
+    /// Accepted source blocks, any source when empty
+    pub sources:  Vec<Cidr>,
+    /// Accepted host names, any host when empty
+    pub hosts:    Vec<String>,
+    /// Drop messages of a host sent from another address than its last UP announced
+    pub check_ip: bool,
+    /// Datagrams per second allowed from each source, unlimited when zero
+    pub rate:     f64,
+    pub burst:    f64
+}
+
+/// Drops datagrams from unexpected sources or hosts, and sources sending too fast
+pub struct SourceFilter {
+    config:   FilterConfig,
+    buckets:  HashMap<IpAddr, TokenBucket>,
+    /// Last time buckets full again were forgotten
+    swept:    f64,
+    /// Address each host announced in its last UP message, and when the host was last heard from
+    up_ips:   HashMap<String, (IpAddr, f64)>,
+    /// Last time hosts silent for too long were unbound
+    up_swept: f64,
+    metrics:  SharedMetrics
+}
+
+impl SourceFilter {
+    pub fn new(config: FilterConfig, metrics: SharedMetrics) -> SourceFilter {
+        SourceFilter {
+            config:   config,
+            buckets:  HashMap::new(),
+            swept:    0.0,
+            up_ips:   HashMap::new(),
+            up_swept: 0.0,
+            metrics:  metrics
+        }
+    }
+
+    fn reject(&self, reason: &str, src: &IpAddr, what: &str) -> bool {
+        debug!("Dropped datagram from {} ({}): {}", src, reason, what);
+        self.metrics.counter_add("sensorweb_datagrams_dropped_total", "Datagrams dropped by source filtering",
+                                 &[("reason", reason)], 1.0);
+        false
+    }
+
+    /// Whether a datagram from `src`, received at `now` in seconds, may be parsed at all
+    pub fn admit_source(&mut self, src: &IpAddr, now: f64) -> bool {
+        let src = &unmap(src);
+        if !self.config.sources.is_empty() && !self.config.sources.iter().any(|c| c.contains(src)) {
+            return self.reject("source", src, "not in allowed networks");
+        }
+
+        if self.config.rate > 0.0 {
+            let burst = self.config.burst.max(1.0);
+
+            // A bucket idle long enough to be full again is no different from a new one
+            let refill = burst / self.config.rate;
+            if now - self.swept >= refill {
+                self.buckets.retain(|_, b| now - b.last < refill);
+                self.swept = now;
+            }
+
+            let bucket = self.buckets.entry(*src).or_insert(TokenBucket { tokens: burst, last: now });
+            if !bucket.take(self.config.rate, burst, now) {
+                return self.reject("rate_limited", src, "rate limit exceeded");
+            }
+        }
+
+        true
+    }
+
+    /// Whether `msg`, parsed from a datagram sent by `src` and received at `now`, may go through.
+    /// Only `authenticated` UP messages may move a host already bound to another address.
+    pub fn admit(&mut self, msg: &NetworkMsg, src: &IpAddr, authenticated: bool, now: f64) -> bool {
+        let src = &unmap(src);
+        if !self.config.hosts.is_empty() && !self.config.hosts.iter().any(|h| host_matches(h, &msg.host)) {
+            return self.reject("host", src, &msg.raw);
+        }
+
+        if self.config.check_ip {
+            if now - self.up_swept >= UP_IP_TTL {
+                self.up_ips.retain(|_, &mut (_, seen)| now - seen < UP_IP_TTL);
+                self.up_swept = now;
+            }
+
+            let bound = self.up_ips.get(&msg.host).map(|&(ip, _)| ip);
+            if msg.msg.mtype == MessageType::NodeUp {
+                let announced = msg.msg.mvals.get("ip_addr").and_then(|ip| IpAddr::from_str(ip.trim()).ok()).map(|ip| unmap(&ip));
+                match announced {
+                    Some(ref ip) if ip != src                                    => return self.reject("ip_mismatch", src, &msg.raw),
+                    Some(ip) if !authenticated && bound.map_or(false, |b| b != ip) => return self.reject("ip_mismatch", src, &msg.raw),
+                    Some(ip) => {
+                        if bound.is_none() && self.up_ips.len() >= MAX_UP_IPS {
+                            warn!("Too many hosts bound to an address, not binding {} to {}", msg.host, ip);
+                        } else {
+                            self.up_ips.insert(msg.host.clone(), (ip, now));
+                        }
+                    },
+                    None => {}
+                }
+            } else if let Some(ip) = bound {
+                if ip != *src {
+                    return self.reject("ip_mismatch", src, &msg.raw);
+                }
+                self.up_ips.insert(msg.host.clone(), (ip, now));
+            }
+        }
+
+        true
+    }
+}
+
+#[test]
+fn test_cidr() {
+    let lan = Cidr::parse("192.168.1.0/24").unwrap();
+    assert!(lan.contains(&IpAddr::from_str("192.168.1.29").unwrap()));
+    assert!(!lan.contains(&IpAddr::from_str("192.168.2.29").unwrap()));
+    assert!(!lan.contains(&IpAddr::from_str("::1").unwrap()));
+
+    assert!(Cidr::parse("10.0.0.7").unwrap().contains(&IpAddr::from_str("10.0.0.7").unwrap()));
+    assert!(!Cidr::parse("10.0.0.7").unwrap().contains(&IpAddr::from_str("10.0.0.8").unwrap()));
+    assert!(Cidr::parse("0.0.0.0/0").unwrap().contains(&IpAddr::from_str("8.8.8.8").unwrap()));
+    assert!(Cidr::parse("fd00::/8").unwrap().contains(&IpAddr::from_str("fd12::1").unwrap()));
+    assert!(!Cidr::parse("fd00::/8").unwrap().contains(&IpAddr::from_str("fe80::1").unwrap()));
+    // Dual stack sockets see IPv4 peers as IPv4-mapped addresses
+    assert!(lan.contains(&IpAddr::from_str("::ffff:192.168.1.29").unwrap()));
+    assert!(!lan.contains(&IpAddr::from_str("::ffff:192.168.2.29").unwrap()));
+    assert_eq!(unmap(&IpAddr::from_str("::1").unwrap()), IpAddr::from_str("::1").unwrap());
+
+    assert!(Cidr::parse("10.0.0.0/33").is_err());
+    assert!(Cidr::parse("10.0.0/8").is_err());
+    assert!(Cidr::parse("10.0.0.0/x").is_err());
+}
+
+#[test]
+fn test_token_bucket() {
+    let mut b = TokenBucket { tokens: 2.0, last: 0.0 };
+    assert!(b.take(1.0, 2.0, 0.0));
+    assert!(b.take(1.0, 2.0, 0.0));
+    assert!(!b.take(1.0, 2.0, 0.5));
+    assert!(b.take(1.0, 2.0, 1.0));
+    // Idle time never accumulates more than the burst
+    assert!(b.take(1.0, 2.0, 100.0));
+    assert!(b.take(1.0, 2.0, 100.0));
+    assert!(!b.take(1.0, 2.0, 100.0));
+}
+
+#[test]
+fn test_source_filter() {
+    use message::parse_from_string;
+    use metrics::Metrics;
+
+    let metrics = Metrics::new();
+    let mut f = SourceFilter::new(FilterConfig {
+        sources:  vec![Cidr::parse("192.168.1.0/24").unwrap()],
+        hosts:    vec![String::from("ESP_D427*")],
+        check_ip: true,
+        rate:     1.0,
+        burst:    2.0
+    }, metrics.clone());
+    let node = IpAddr::from_str("192.168.1.29").unwrap();
+    let other = IpAddr::from_str("192.168.1.30").unwrap();
+
+    assert!(!f.admit_source(&IpAddr::from_str("10.0.0.1").unwrap(), 0.0));
+    assert!(f.admit_source(&node, 0.0));
+    assert!(f.admit_source(&node, 0.0));
+    assert!(!f.admit_source(&node, 0.0));
+    assert!(f.admit_source(&other, 0.0));
+    // Sources quiet for long enough are forgotten, their buckets being full again
+    assert_eq!(f.buckets.len(), 2);
+    assert!(f.admit_source(&other, 1.5));
+    assert_eq!(f.buckets.len(), 2);
+    assert!(f.admit_source(&other, 3.0));
+    assert_eq!(f.buckets.len(), 1);
+
+    let up = parse_from_string(String::from("ESP_D427A9: [2.89900] UP: 1.0:May 14 2017 01:34:24@192.168.1.29"));
+    assert!(!f.admit(&up, &other, false, 10.0));
+    assert!(f.admit(&up, &node, false, 10.0));
+
+    let ac = parse_from_string(String::from("ESP_D427A9: [11.06000] AC:push: Code 200"));
+    assert!(f.admit(&ac, &node, false, 10.0));
+    assert!(f.admit(&ac, &IpAddr::from_str("::ffff:192.168.1.29").unwrap(), false, 10.0));
+    assert!(!f.admit(&ac, &other, false, 10.0));
+    assert!(!f.admit(&parse_from_string(String::from("ESP_000001: [11.06000] AC:push: Code 200")), &node, false, 10.0));
+
+    // Moving a bound host takes an authenticated UP
+    let moved = parse_from_string(String::from("ESP_D427A9: [2.89900] UP: 1.0:May 14 2017 01:34:24@192.168.1.30"));
+    assert!(!f.admit(&moved, &other, false, 20.0));
+    assert!(f.admit(&moved, &other, true, 20.0));
+    assert!(f.admit(&ac, &other, false, 20.0));
+
+    // unless it has been silent long enough to be forgotten
+    assert!(f.admit(&up, &node, false, 20.0 + UP_IP_TTL));
+    assert!(f.up_ips.len() == 1);
+
+    let rendered = metrics.render();
+    assert!(rendered.contains("sensorweb_datagrams_dropped_total{reason=\"source\"} 1\n"));
+    assert!(rendered.contains("sensorweb_datagrams_dropped_total{reason=\"rate_limited\"} 1\n"));
+    assert!(rendered.contains("sensorweb_datagrams_dropped_total{reason=\"ip_mismatch\"} 3\n"));
+    assert!(rendered.contains("sensorweb_datagrams_dropped_total{reason=\"host\"} 1\n"));
+}
@@ -1,67 +1,240 @@
 extern crate hyper;
-use self::hyper::header::{ContentLength, Location};
+use self::hyper::header::{AcceptEncoding, ContentEncoding, ContentLength, ContentType, Encoding, EntityTag, ETag, IfNoneMatch, Location};
 use self::hyper::method::Method;
-use self::hyper::server::{Server, Request, Response};
+use self::hyper::server::{Handler, Server, Request, Response};
 use self::hyper::status::StatusCode;
 use self::hyper::uri::RequestUri;
 
-use std::path::Path;
+use std::collections::HashMap;
+use std::path::{Component, Path, PathBuf};
 use std::fs::File;
 use std::io::BufReader;
 use std::io::Read;
 use std::io::Write;
+use std::net::SocketAddr;
 
-fn http_handler(req: Request, mut res: Response) {
-    debug!("Received HTTP: {} {}", req.method, req.uri);
+use assets;
 
-    match (req.method, req.uri) {
-        (Method::Get, RequestUri::AbsolutePath(path)) => {
-            let req_path = Path::new(path.as_str());
-            if Path::new("/") == req_path {
-                *res.status_mut() = StatusCode::PermanentRedirect;
-                res.headers_mut().set(Location("/index.html".to_owned()));
-                return;
-            }
+fn ws_port(ws_bind: &str) -> u16 {
+    match ws_bind.parse::<SocketAddr>() {
+        Ok(addr) => addr.port(),
+        Err(_)   => ws_bind.rsplit(':').next().and_then(|p| p.parse::<u16>().ok()).unwrap_or(8001)
+    }
+}
 
-            let uri_path = if req_path.has_root() {
-                let static_p = Path::new("static");
-                match req_path.strip_prefix("/") {
-                    Ok(clean) => static_p.join(clean),
-                    Err(err)  => static_p.join(""),
-                }
-            } else {
-                Path::new("static").join(path.to_string())
-            };
-
-            debug!("Trying to open path: {:?} ({})", uri_path.to_str(), path.to_string());
-            match File::open(uri_path.clone()) {
-                Ok(file) => {
-                    let mut buf_reader = BufReader::new(file);
-                    let mut contents = String::new();
-                    match buf_reader.read_to_string(&mut contents) {
-                        Ok(read_bytes) => {
-                            *res.status_mut() = StatusCode::Ok;
-                            res.headers_mut().set(ContentLength(contents.len() as u64));
-
-                            let mut res = res.start().unwrap();
-                            res.write_all(contents.as_bytes()).unwrap();
-                        },
-                        Err(err)       => {}
+fn accepts_gzip(req: &Request) -> bool {
+    match req.headers.get::<AcceptEncoding>() {
+        Some(&AcceptEncoding(ref encodings)) => {
+            encodings.iter().any(|q| q.item == Encoding::Gzip && q.quality.0 > 0)
+        },
+        None => false
+    }
+}
+
+fn etag_matches(req: &Request, etag: &EntityTag) -> bool {
+    match req.headers.get::<IfNoneMatch>() {
+        Some(&IfNoneMatch::Any)             => true,
+        Some(&IfNoneMatch::Items(ref tags)) => tags.iter().any(|t| t.weak_eq(etag)),
+        None                                => false
+    }
+}
+
+/// Maps a request path onto `static_dir`, refusing anything escaping it
+fn disk_path(static_dir: &Path, req_path: &Path) -> Option<PathBuf> {
+    let mut rv = static_dir.to_path_buf();
+    for component in req_path.components() {
+        match component {
+            Component::RootDir   => {},
+            Component::CurDir    => {},
+            Component::Normal(c) => rv.push(c),
+            _                    => return None
+        }
+    }
+    Some(rv)
+}
+
+fn send_body(mut res: Response, content_type: ContentType, body: &[u8]) {
+    *res.status_mut() = StatusCode::Ok;
+    res.headers_mut().set(ContentLength(body.len() as u64));
+    res.headers_mut().set(content_type);
+
+    let mut res = res.start().unwrap();
+    res.write_all(body).unwrap();
+}
+
+/// Dynamic endpoint, given the part of the path following its registered prefix
+struct Route {
+    content_type: &'static str,
+    handler:      Box<Fn(&str) -> Option<String> + Send + Sync>
+}
+
+pub struct HttpService {
+    ws_bind:    String,
+    static_dir: Option<PathBuf>,
+    routes:     HashMap<String, Route>
+}
+
+impl HttpService {
+    pub fn new(ws_bind: String, static_dir: Option<PathBuf>) -> HttpService {
+        HttpService {
+            ws_bind:    ws_bind,
+            static_dir: static_dir,
+            routes:     HashMap::new()
+        }
+    }
+
+    /// Registers `handler` for `path`, or for everything below it when `path` ends with `/`.
+    /// Returning `None` from the handler answers 404.
+    pub fn route<F>(&mut self, path: &str, content_type: &'static str, handler: F)
+        where F: Fn(&str) -> Option<String> + Send + Sync + 'static {
+        self.routes.insert(String::from(path), Route {
+            content_type: content_type,
+            handler:      Box::new(handler)
+        });
+    }
+
+    fn find_route<'a>(&self, path: &'a str) -> Option<(&Route, &'a str)> {
+        if let Some(route) = self.routes.get(path) {
+            return Some((route, ""));
+        }
+
+        self.routes.iter()
+                   .filter(|&(prefix, _)| prefix.ends_with('/') && path.starts_with(prefix.as_str()))
+                   .max_by_key(|&(prefix, _)| prefix.len())
+                   .map(|(prefix, route)| (route, &path[prefix.len()..]))
+    }
+
+    fn send_embedded(&self, req: &Request, mut res: Response, asset: &assets::Asset) {
+        let etag = EntityTag::new(false, asset.etag.to_owned());
+        res.headers_mut().set(ETag(etag.clone()));
+        res.headers_mut().set_raw("Vary", vec![b"Accept-Encoding".to_vec()]);
+
+        if etag_matches(req, &etag) {
+            *res.status_mut() = StatusCode::NotModified;
+            return;
+        }
+
+        let content_type = assets::mime_for(Path::new(asset.path)).parse().unwrap();
+        if accepts_gzip(req) {
+            res.headers_mut().set(ContentEncoding(vec![Encoding::Gzip]));
+            send_body(res, ContentType(content_type), asset.gzip);
+        } else {
+            send_body(res, ContentType(content_type), asset.raw);
+        }
+    }
+
+    /// Serves `req_path` from `--static-dir`, handing `res` back when the file is not there
+    fn send_from_disk<'a>(&self, req_path: &Path, res: Response<'a>) -> Option<Response<'a>> {
+        let uri_path = match self.static_dir.as_ref().and_then(|d| disk_path(d, req_path)) {
+            Some(p) => p,
+            None    => return Some(res)
+        };
+
+        debug!("Trying to open path: {:?}", uri_path.to_str());
+        match File::open(uri_path.clone()) {
+            Ok(file) => {
+                let mut buf_reader = BufReader::new(file);
+                let mut contents = Vec::new();
+                match buf_reader.read_to_end(&mut contents) {
+                    Ok(_)    => {
+                        let content_type = assets::mime_for(&uri_path).parse().unwrap();
+                        send_body(res, ContentType(content_type), &contents);
+                        None
+                    },
+                    Err(err) => {
+                        error!("Error reading resource {:?}: {}", uri_path.to_str(), err);
+                        Some(res)
                     }
-                },
-                Err(err) => {
-                    debug!("Error on resource {:?}: {}", uri_path.to_str(), err);
-                    *res.status_mut() = StatusCode::NotFound;
                 }
+            },
+            Err(err) => {
+                debug!("Error on resource {:?}: {}", uri_path.to_str(), err);
+                Some(res)
             }
-        },
-        _           => {
-            *res.status_mut() = StatusCode::MethodNotAllowed;
         }
     }
 }
 
-pub fn th_http_listener(http_bind: String) {
+impl Handler for HttpService {
+    fn handle(&self, req: Request, mut res: Response) {
+        debug!("Received HTTP: {} {}", req.method, req.uri);
+
+        let path = match (&req.method, &req.uri) {
+            (&Method::Get, &RequestUri::AbsolutePath(ref path)) => path.splitn(2, '?').next().unwrap_or("").to_string(),
+            _ => {
+                *res.status_mut() = StatusCode::MethodNotAllowed;
+                return;
+            }
+        };
+
+        let req_path = Path::new(path.as_str());
+        if Path::new("/") == req_path {
+            *res.status_mut() = StatusCode::PermanentRedirect;
+            res.headers_mut().set(Location("/index.html".to_owned()));
+            return;
+        }
+
+        if Path::new("/config.json") == req_path {
+            let config = format!("{{\"ws_port\": {}}}", ws_port(&self.ws_bind));
+            send_body(res, ContentType::json(), config.as_bytes());
+            return;
+        }
+
+        if let Some((route, rest)) = self.find_route(path.as_str()) {
+            match (route.handler)(rest) {
+                Some(body) => send_body(res, ContentType(route.content_type.parse().unwrap()), body.as_bytes()),
+                None       => *res.status_mut() = StatusCode::NotFound
+            }
+            return;
+        }
+
+        let mut res = match self.send_from_disk(req_path, res) {
+            Some(res) => res,
+            None      => return
+        };
+
+        match assets::lookup(path.as_str()) {
+            Some(asset) => self.send_embedded(&req, res, asset),
+            None        => {
+                debug!("No such resource: {}", path);
+                *res.status_mut() = StatusCode::NotFound;
+            }
+        }
+    }
+}
+
+pub fn th_http_listener(http_bind: String, service: HttpService) {
     info!("Http thread started: {}", http_bind);
-    Server::http(http_bind).unwrap().handle(http_handler).unwrap();
+    Server::http(http_bind).unwrap().handle(service).unwrap();
+}
+
+#[test]
+fn test_ws_port() {
+    assert_eq!(ws_port("0.0.0.0:8001"), 8001);
+    assert_eq!(ws_port("[::]:9001"), 9001);
+    assert_eq!(ws_port("localhost:1234"), 1234);
+    assert_eq!(ws_port("garbage"), 8001);
+}
+
+#[test]
+fn test_find_route() {
+    let mut service = HttpService::new(String::from("0.0.0.0:8001"), None);
+    service.route("/metrics", "text/plain", |_| Some(String::from("metrics")));
+    service.route("/api/", "application/json", |rest| Some(format!("api {}", rest)));
+    service.route("/api/aqi", "application/json", |_| Some(String::from("aqi")));
+
+    let call = |path: &str| service.find_route(path).and_then(|(route, rest)| (route.handler)(rest));
+    assert_eq!(call("/metrics"), Some(String::from("metrics")));
+    assert_eq!(call("/api/aqi"), Some(String::from("aqi")));
+    assert_eq!(call("/api/sessions/abc"), Some(String::from("api sessions/abc")));
+    assert_eq!(call("/metrics/x"), None);
+    assert_eq!(call("/index.html"), None);
+}
+
+#[test]
+fn test_disk_path() {
+    let root = Path::new("static");
+    assert_eq!(disk_path(root, Path::new("/index.html")), Some(root.join("index.html")));
+    assert_eq!(disk_path(root, Path::new("/js/./app.js")), Some(root.join("js").join("app.js")));
+    assert_eq!(disk_path(root, Path::new("/../Cargo.toml")), None);
 }
@@ -0,0 +1,365 @@
+extern crate hyper;
+use self::hyper::client::Client;
+
+use std::collections::HashMap;
+use std::fs::{File, OpenOptions};
+use std::io::Write;
+use std::path::PathBuf;
+use std::sync::mpsc::{sync_channel, Receiver, RecvTimeoutError, SyncSender, TrySendError};
+use std::thread;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+use message::{MessageType, NetworkMsg};
+use sink::MessageSink;
+
+const FLUSH_INTERVAL_SECS: u64 = 10;
+const QUEUE_SIZE:          usize = 4096;
+const MAX_BUFFERED_LINES:  usize = 100000;
+
+#[derive(Debug, Clone)]
+pub struct InfluxConfig {
+    pub url:   Option<String>,
+    pub file:  Option<PathBuf>,
+    pub batch: usize
+}
+
+/// Line breaks would end the line early and let a value inject records of its own, so they are dropped
+fn strip_newlines(s: &str) -> String {
+    s.chars().filter(|&c| c != '\n' && c != '\r').collect()
+}
+
+fn escape_measurement(s: &str) -> String {
+    strip_newlines(s).replace("\\", "\\\\").replace(",", "\\,").replace(" ", "\\ ")
+}
+
+fn escape_tag(s: &str) -> String {
+    strip_newlines(s).replace("\\", "\\\\").replace(",", "\\,").replace("=", "\\=").replace(" ", "\\ ")
+}
+
+fn line(measurement: &str, tags: &[(&str, &str)], fields: &[(String, String)], ts_ns: u64) -> String {
+    let mut rv = escape_measurement(measurement);
+    for &(key, value) in tags {
+        if !value.is_empty() {
+            rv.push_str(&format!(",{}={}", escape_tag(key), escape_tag(value)));
+        }
+    }
+
+    let fields: Vec<String> = fields.iter().map(|&(ref k, ref v)| format!("{}={}", escape_tag(k), v)).collect();
+    rv.push_str(&format!(" {} {}", fields.join(","), ts_ns));
+    rv
+}
+
+/// Field value as float, or None when the device sent something unparseable or not finite
+fn float_field(vals: &HashMap<String, String>, key: &str) -> Option<(String, String)> {
+    vals.get(key).and_then(|v| v.parse::<f64>().ok()).filter(|v| v.is_finite()).map(|v| (String::from(key), format!("{}", v)))
+}
+
+fn int_field(vals: &HashMap<String, String>, key: &str) -> Option<(String, String)> {
+    vals.get(key).and_then(|v| v.parse::<i64>().ok()).map(|v| (String::from(key), format!("{}i", v)))
+}
+
+/// Converts a message to line protocol, `session` being the last UUID known for its host
+fn to_lines(msg: &NetworkMsg, session: &str, ts_ns: u64) -> Vec<String> {
+    let tags = [("host", msg.host.as_str()), ("session", session)];
+    let vals = &msg.msg.mvals;
+
+    let fields: Vec<(String, String)> = match msg.msg.mtype {
+        MessageType::Ntp  => {
+            float_field(vals, "pm2.5").map(|(_, v)| (String::from("value"), v)).into_iter().collect()
+        },
+        MessageType::Loop => {
+            vec![float_field(vals, "slowdownfactor"),
+                 float_field(vals, "deepsleepduration"),
+                 int_field(vals, "ntperrors"),
+                 int_field(vals, "sleepwakecycles")].into_iter().filter_map(|f| f).collect()
+        },
+        _ => Vec::new()
+    };
+
+    if fields.is_empty() {
+        return Vec::new();
+    }
+
+    let measurement = match msg.msg.mtype {
+        MessageType::Ntp => "pm25",
+        _                => "loop"
+    };
+
+    vec![line(measurement, &tags, &fields, ts_ns)]
+}
+
+fn now_ns() -> u64 {
+    match SystemTime::now().duration_since(UNIX_EPOCH) {
+        Ok(d)  => d.as_secs() * 1_000_000_000 + d.subsec_nanos() as u64,
+        Err(_) => 0
+    }
+}
+
+/// Why a batch was not written: `Retry` keeps it for the next flush, `Rejected` drops it
+#[derive(Debug, PartialEq)]
+enum WriteError {
+    Retry(String),
+    Rejected(String)
+}
+
+trait LineOutput: Send {
+    fn write_batch(&mut self, lines: &[String]) -> Result<(), WriteError>;
+}
+
+struct HttpOutput {
+    client: Client,
+    url:    String
+}
+
+impl LineOutput for HttpOutput {
+    fn write_batch(&mut self, lines: &[String]) -> Result<(), WriteError> {
+        let body = lines.join("\n");
+        match self.client.post(self.url.as_str()).body(body.as_str()).send() {
+            Ok(ref res) if res.status.is_success() => Ok(()),
+            // 4xx means the server will never accept this batch, resending it only blocks the lines after it
+            Ok(ref res) if res.status.is_client_error() => Err(WriteError::Rejected(format!("{} answered {}", self.url, res.status))),
+            Ok(res)  => Err(WriteError::Retry(format!("{} answered {}", self.url, res.status))),
+            Err(err) => Err(WriteError::Retry(format!("{}: {}", self.url, err)))
+        }
+    }
+}
+
+struct FileOutput {
+    file: File
+}
+
+impl LineOutput for FileOutput {
+    fn write_batch(&mut self, lines: &[String]) -> Result<(), WriteError> {
+        let mut buf = String::new();
+        for l in lines {
+            buf.push_str(l);
+            buf.push('\n');
+        }
+        self.file.write_all(buf.as_bytes()).map_err(|e| WriteError::Retry(e.to_string()))
+    }
+}
+
+/// An output and the lines it still has to write, kept across failures
+struct PendingOutput {
+    output:  Box<LineOutput>,
+    pending: Vec<String>
+}
+
+impl PendingOutput {
+    fn flush(&mut self) {
+        if self.pending.is_empty() {
+            return;
+        }
+
+        match self.output.write_batch(&self.pending) {
+            Ok(_)    => {
+                debug!("Wrote {} lines to InfluxDB output", self.pending.len());
+                self.pending.clear();
+            },
+            Err(WriteError::Rejected(err)) => {
+                error!("InfluxDB output rejected {} lines, dropping them: {}", self.pending.len(), err);
+                for l in self.pending.drain(..) {
+                    warn!("Dropped InfluxDB line: {}", l);
+                }
+            },
+            Err(WriteError::Retry(err)) => {
+                warn!("Unable to write {} lines to InfluxDB output: {}", self.pending.len(), err);
+                if self.pending.len() > MAX_BUFFERED_LINES {
+                    let excess = self.pending.len() - MAX_BUFFERED_LINES;
+                    warn!("Dropping {} oldest InfluxDB lines", excess);
+                    self.pending.drain(0..excess);
+                }
+            }
+        }
+    }
+}
+
+fn th_influx_writer(mut outputs: Vec<PendingOutput>, batch: usize, rx: Receiver<String>) {
+    info!("InfluxDB writer thread started");
+
+    let interval = Duration::from_secs(FLUSH_INTERVAL_SECS);
+    let mut last_flush = Instant::now();
+    let mut buffered = 0;
+
+    loop {
+        let timeout = interval.checked_sub(last_flush.elapsed()).unwrap_or(Duration::from_secs(0));
+        let disconnected = match rx.recv_timeout(timeout) {
+            Ok(l) => {
+                for o in outputs.iter_mut() {
+                    o.pending.push(l.clone());
+                }
+                buffered += 1;
+                false
+            },
+            Err(RecvTimeoutError::Timeout)      => false,
+            Err(RecvTimeoutError::Disconnected) => true
+        };
+
+        if disconnected || buffered >= batch || last_flush.elapsed() >= interval {
+            for o in outputs.iter_mut() {
+                o.flush();
+            }
+            buffered = 0;
+            last_flush = Instant::now();
+        }
+
+        if disconnected {
+            break;
+        }
+    }
+}
+
+/// Sink exporting PM2.5 readings and Loop telemetry as InfluxDB line protocol
+pub struct InfluxSink {
+    sessions: HashMap<String, String>,
+    tx:       SyncSender<String>
+}
+
+impl InfluxSink {
+    pub fn new(config: InfluxConfig) -> InfluxSink {
+        let mut outputs = Vec::new();
+
+        if let Some(url) = config.url {
+            info!("Writing line protocol to {}", url);
+            outputs.push(PendingOutput {
+                output:  Box::new(HttpOutput { client: Client::new(), url: url }),
+                pending: Vec::new()
+            });
+        }
+
+        if let Some(path) = config.file {
+            match OpenOptions::new().create(true).append(true).open(&path) {
+                Ok(file) => {
+                    info!("Writing line protocol to {:?}", path);
+                    outputs.push(PendingOutput {
+                        output:  Box::new(FileOutput { file: file }),
+                        pending: Vec::new()
+                    });
+                },
+                Err(err) => error!("Unable to open {:?}: {}", path, err)
+            }
+        }
+
+        let (tx, rx) = sync_channel(QUEUE_SIZE);
+        let batch = config.batch;
+        let _ = thread::Builder::new().name("InfluxWriter".to_string()).spawn(move || {
+            th_influx_writer(outputs, batch, rx);
+        });
+
+        InfluxSink {
+            sessions: HashMap::new(),
+            tx:       tx
+        }
+    }
+}
+
+impl MessageSink for InfluxSink {
+    fn name(&self) -> &str {
+        "influxdb"
+    }
+
+    fn handle(&mut self, msg: &NetworkMsg) {
+        match msg.msg.mtype {
+            MessageType::Session | MessageType::Ntp => {
+                if let Some(uuid) = msg.msg.mvals.get("UUID") {
+                    self.sessions.insert(msg.host.clone(), uuid.clone());
+                }
+            },
+            MessageType::NodeUp => {
+                self.sessions.remove(&msg.host);
+            },
+            _ => {}
+        }
+
+        let session = self.sessions.get(&msg.host).map(|s| s.as_str()).unwrap_or("");
+        for l in to_lines(msg, session, now_ns()) {
+            match self.tx.try_send(l) {
+                Ok(_)                           => {},
+                Err(TrySendError::Full(_))      => warn!("InfluxDB queue full, dropping line"),
+                Err(TrySendError::Disconnected(_)) => error!("InfluxDB writer is gone, dropping line")
+            }
+        }
+    }
+}
+
+#[test]
+fn test_to_lines() {
+    use message::parse_from_string;
+
+    let n = parse_from_string(String::from("ESP_D427A9: [11.06500] NTP: 2017-05-26T15:27:53.000+01:00 PM2.5: 12 UUID:d687fe3f-2d30-352d-0c21-ff3f2cea2040 sent:1"));
+    assert_eq!(to_lines(&n, "d687fe3f-2d30-352d-0c21-ff3f2cea2040", 42),
+               vec!["pm25,host=ESP_D427A9,session=d687fe3f-2d30-352d-0c21-ff3f2cea2040 value=12 42"]);
+
+    let n = parse_from_string(String::from("ESP_D427A9: [11.15300] Loop: deepSleep: nextInterval=288.93; executionTime=11.07 ; slowDownFactor=1.04365; deepSleep(301.54)"));
+    assert_eq!(to_lines(&n, "", 42),
+               vec!["loop,host=ESP_D427A9 slowdownfactor=1.04365,deepsleepduration=301.54 42"]);
+
+    let n = parse_from_string(String::from("ESP_D427A9: [4.07500] Loop: no NTP initial sync, waiting ... sleepWakeCycles=1 ntpErrors=2"));
+    assert_eq!(to_lines(&n, "abc", 42),
+               vec!["loop,host=ESP_D427A9,session=abc ntperrors=2i,sleepwakecycles=1i 42"]);
+
+    let n = parse_from_string(String::from("ESP_D427A9: [11.06000] AC:push: Code 200"));
+    assert!(to_lines(&n, "abc", 42).is_empty());
+}
+
+#[test]
+fn test_escaping() {
+    let fields = vec![(String::from("value"), String::from("1"))];
+    assert_eq!(line("pm 25", &[("host", "a,b c=d")], &fields, 1), "pm\\ 25,host=a\\,b\\ c\\=d value=1 1");
+    assert_eq!(line("pm25", &[("host", "a\\")], &fields, 1), "pm25,host=a\\\\ value=1 1");
+    assert_eq!(line("pm25", &[("host", "a\n\rb value=9 9\npm25")], &fields, 1), "pm25,host=ab\\ value\\=9\\ 9pm25 value=1 1");
+}
+
+#[test]
+fn test_non_finite() {
+    let mut vals = HashMap::new();
+    for v in &["nan", "inf", "-inf", "NaN"] {
+        vals.insert(String::from("x"), String::from(*v));
+        assert_eq!(float_field(&vals, "x"), None);
+    }
+    vals.insert(String::from("x"), String::from("1.5"));
+    assert_eq!(float_field(&vals, "x"), Some((String::from("x"), String::from("1.5"))));
+}
+
+#[test]
+fn test_rejected_batch_dropped() {
+    struct Rejecting;
+    impl LineOutput for Rejecting {
+        fn write_batch(&mut self, _lines: &[String]) -> Result<(), WriteError> {
+            Err(WriteError::Rejected(String::from("400 Bad Request")))
+        }
+    }
+    struct Failing;
+    impl LineOutput for Failing {
+        fn write_batch(&mut self, _lines: &[String]) -> Result<(), WriteError> {
+            Err(WriteError::Retry(String::from("503 Service Unavailable")))
+        }
+    }
+
+    let mut o = PendingOutput { output: Box::new(Rejecting), pending: vec![String::from("a value=1 1")] };
+    o.flush();
+    assert!(o.pending.is_empty());
+
+    let mut o = PendingOutput { output: Box::new(Failing), pending: vec![String::from("a value=1 1")] };
+    o.flush();
+    assert_eq!(o.pending.len(), 1);
+}
+
+#[test]
+fn test_file_output() {
+    use std::env;
+    use std::fs;
+    use std::io::Read;
+
+    let path = env::temp_dir().join(format!("influx-test-{}.lp", now_ns()));
+    {
+        let file = OpenOptions::new().create(true).append(true).open(&path).unwrap();
+        let mut output = FileOutput { file: file };
+        output.write_batch(&[String::from("a value=1 1"), String::from("b value=2 2")]).unwrap();
+    }
+
+    let mut contents = String::new();
+    File::open(&path).unwrap().read_to_string(&mut contents).unwrap();
+    fs::remove_file(&path).unwrap();
+    assert_eq!(contents, "a value=1 1\nb value=2 2\n");
+}
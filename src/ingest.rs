@@ -0,0 +1,140 @@
+use std::net::{IpAddr, SocketAddr};
+use std::sync::{Arc, Mutex};
+use std::sync::mpsc::SyncSender;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use auth::{Authenticator, split_trailer};
+use filter::SourceFilter;
+use message::{NetworkMsg, ParserRegistry, Transport, line_sender, parse_with};
+use syslog::parse_syslog;
+
+/// What every received datagram goes through before reaching the message manager
+pub struct Ingest {
+    parsers: Arc<ParserRegistry>,
+    auth:    Option<Authenticator>,
+    filter:  Option<SourceFilter>
+}
+
+/// Ingest shared by every listener, so that replay and rate state is kept across transports
+pub type SharedIngest = Arc<Mutex<Ingest>>;
+
+/// Parses a line the way the listener of `transport` expects it, `ip` standing for syslog senders naming no host
+pub fn parse(parsers: &ParserRegistry, line: String, transport: Transport, ip: &IpAddr) -> NetworkMsg {
+    match transport {
+        Transport::Syslog => parse_syslog(parsers, &line, &ip.to_string()),
+        _                 => parse_with(parsers, line)
+    }
+}
+
+impl Ingest {
+    pub fn new(parsers: Arc<ParserRegistry>, auth: Option<Authenticator>, filter: Option<SourceFilter>) -> Ingest {
+        Ingest {
+            parsers: parsers,
+            auth:    auth,
+            filter:  filter
+        }
+    }
+
+    /// Parses a datagram received from `src` over `transport` at `now`, None when it has to be dropped
+    pub fn process(&mut self, datagram: &str, src: &SocketAddr, transport: Transport, now: SystemTime) -> Option<NetworkMsg> {
+        let now = now.duration_since(UNIX_EPOCH)
+                     .map(|d| d.as_secs() as f64 + d.subsec_nanos() as f64 / 1e9)
+                     .unwrap_or(0.0);
+        let ip = src.ip();
+
+        if let Some(ref mut filter) = self.filter {
+            if !filter.admit_source(&ip, now) {
+                return None;
+            }
+        }
+
+        let (line, trailer) = split_trailer(datagram);
+        // Unsigned messages go through when signatures are optional, without counting as authenticated
+        let authenticated = self.auth.is_some() && trailer.is_some();
+
+        // Text lines are authenticated before being parsed, from the host and uptime they start with;
+        // syslog and JSON datagrams only tell theirs once parsed
+        let mut msg = if transport != Transport::Syslog && !line.trim_left().starts_with('{') {
+            if let Some(ref mut auth) = self.auth {
+                let (host, time) = line_sender(&line);
+                if !auth.verify(host, time, &line, trailer.as_ref(), now as u64) {
+                    return None;
+                }
+            }
+            parse(&self.parsers, line, transport, &ip)
+        } else {
+            let msg = parse(&self.parsers, line, transport, &ip);
+            if let Some(ref mut auth) = self.auth {
+                if !auth.verify(&msg.host, msg.time, &msg.raw, trailer.as_ref(), now as u64) {
+                    return None;
+                }
+            }
+            msg
+        };
+        msg.transport = transport;
+        msg.source = Some(src.to_string());
+
+        // Only authenticated UP messages get to move a host to another address
+        if let Some(ref mut filter) = self.filter {
+            if !filter.admit(&msg, &ip, authenticated, now) {
+                return None;
+            }
+        }
+
+        Some(msg)
+    }
+}
+
+/// Runs a datagram through `ingest` and hands the resulting message to the message manager
+pub fn forward(ingest: &SharedIngest, datagram: &str, src: &SocketAddr, transport: Transport, tx: &SyncSender<NetworkMsg>) {
+    // One datagram panicking a listener must not stop every other listener from ingesting
+    let msg = match ingest.lock().unwrap_or_else(|e| e.into_inner()).process(datagram, src, transport, SystemTime::now()) {
+        Some(msg) => msg,
+        None      => return
+    };
+
+    info!("Sending parsed message: {:?}", msg);
+    match tx.send(msg) {
+        Ok(_)    => debug!("Successfully sent message to thread"),
+        Err(err) => error!("Error while sending message to thread: {:?}", err)
+    }
+}
+
+#[test]
+fn test_ingest() {
+    use std::str::FromStr;
+    use std::time::Duration;
+    use auth::{AuthKeys, sign};
+    use filter::{Cidr, FilterConfig};
+    use metrics::Metrics;
+
+    let metrics = Metrics::new();
+    let filter = SourceFilter::new(FilterConfig {
+        sources:  vec![Cidr::parse("192.168.1.0/24").unwrap()],
+        hosts:    Vec::new(),
+        check_ip: true,
+        rate:     0.0,
+        burst:    0.0
+    }, metrics.clone());
+    let auth = Authenticator::new(AuthKeys::parse("* = fleet\n").unwrap(), false, metrics.clone());
+    let mut ingest = Ingest::new(Arc::new(ParserRegistry::builtin()), Some(auth), Some(filter));
+
+    let now = UNIX_EPOCH + Duration::from_secs(1495808873);
+    let node = SocketAddr::from_str("192.168.1.29:4210").unwrap();
+    let up = "ESP_1: [2.89900] UP: 1.0:May 14 2017 01:34:24@192.168.1.29";
+
+    let msg = ingest.process(&sign("fleet", up, 1495808873), &node, Transport::Tcp, now).unwrap();
+    assert_eq!(msg.raw, up);
+    assert_eq!(msg.transport, Transport::Tcp);
+    assert_eq!(msg.source, Some(String::from("192.168.1.29:4210")));
+    assert_eq!(msg.msg.mvals["ip_addr"], "192.168.1.29");
+
+    assert!(ingest.process(&sign("fleet", "ESP_1: [3.00000] AC:push: Code 200", 1495808873), &node, Transport::Udp, now).is_some());
+    assert!(ingest.process(&sign("fleet", "ESP_1: [3.10000] AC:push: Code 200", 1495808873),
+                           &SocketAddr::from_str("192.168.1.30:4210").unwrap(), Transport::Udp, now).is_none());
+    assert!(ingest.process(&sign("fleet", "ESP_1: [3.20000] AC:push: Code 200", 1495808873),
+                           &SocketAddr::from_str("10.0.0.1:4210").unwrap(), Transport::Udp, now).is_none());
+    assert!(ingest.process("ESP_1: [3.30000] AC:push: Code 200", &node, Transport::Udp, now).is_none());
+    // Unsigned lines are dropped before the parser ever sees them
+    assert!(ingest.process("X: [abc] y", &node, Transport::Udp, now).is_none());
+}
@@ -1,20 +1,92 @@
+mod aircasting;
+use aircasting::{AirCastingConfig, AirCastingSink};
+
+mod alert;
+use alert::{AlertSink, Notifier, Rule, SmtpConfig, SmtpNotifier, StdoutNotifier, WebhookNotifier};
+
+mod aqi;
+use aqi::{AqiEnricher, AqiTable};
+
 mod args;
 use args::ArgsParser;
 
+mod assets;
+
+mod auth;
+use auth::{AuthKeys, Authenticator};
+
+mod capture;
+use capture::{CaptureWriter, replay};
+
+mod clock;
+use clock::ClockEnricher;
+
+mod datetime;
+
+mod environment;
+use environment::EnvironmentEnricher;
+
+mod filter;
+use filter::{Cidr, FilterConfig, SourceFilter};
+
 mod http;
-use http::th_http_listener;
+use http::{HttpService, th_http_listener};
+
+mod influx;
+use influx::{InfluxConfig, InfluxSink};
+
+mod ingest;
+use ingest::Ingest;
 
 mod mcast;
 use mcast::bind_mcast;
 
 mod message;
+use message::{ParserRegistry, Transport};
 
 mod message_manager;
 use message_manager::th_message_manager;
 
+mod metrics;
+use metrics::Metrics;
+
+mod mqtt;
+use mqtt::{MqttConfig, MqttSink};
+
+mod pattern;
+use pattern::PatternParser;
+
+mod relay;
+use relay::{RelayConfig, RelayOrigins, RelayServer, RelaySink, bind_relay, load_key};
+
+mod session;
+use session::SessionSink;
+
+mod sink;
+use sink::{MessageEnricher, MessageSink};
+
+mod stats;
+use stats::StatsSink;
+
+mod store;
+use store::{Retention, StoreSink, th_store_downsampler};
+
+mod syslog;
+
+mod unicast;
+use unicast::{bind_tcp, bind_udp};
+
+mod unknown;
+use unknown::UnknownSink;
+
+mod webhook;
+use webhook::{WebhookConfig, WebhookSink};
+
 mod ws;
-use ws::th_ws_listener;
+use ws::{WsFeed, th_ws_listener};
 
+use std::process;
+use std::sync::{Arc, Mutex};
 use std::sync::mpsc::sync_channel;
 use std::thread;
 
@@ -22,6 +94,18 @@ use std::thread;
 extern crate log;
 extern crate simplelog;
 
+extern crate serde;
+#[macro_use]
+extern crate serde_derive;
+extern crate serde_json;
+
+extern crate chrono;
+extern crate regex;
+
+extern crate hmac;
+extern crate rand;
+extern crate sha2;
+
 fn main() {
     let rc = ArgsParser::from_cli();
 
@@ -32,27 +116,332 @@ fn main() {
 
     let (tx, rx) = sync_channel(0);
 
+    let mut parsers = ParserRegistry::builtin();
+    if let Some(ref path) = rc.parsers {
+        if let Err(err) = PatternParser::register_all(path, &mut parsers) {
+            error!("Unable to load parsers: {}", err);
+            process::exit(1);
+        }
+    }
+    let parsers = Arc::new(parsers);
+
+    let metrics = Metrics::new();
+    let mut http_service = HttpService::new(rc.ws_bind.clone(), rc.static_dir.clone());
+
+    let metrics_http = metrics.clone();
+    http_service.route("/metrics", "text/plain; version=0.0.4", move |_| Some(metrics_http.render()));
+
+    let mut aqi_tables = vec![AqiTable::us_epa(), AqiTable::eu_caqi()];
+    for path in rc.aqi_tables.iter() {
+        match AqiTable::load(path) {
+            Ok(table) => aqi_tables.push(table),
+            Err(err)  => error!("Unable to load AQI table: {}", err)
+        }
+    }
+
+    let aqi = AqiEnricher::new(aqi_tables, metrics.clone());
+    let aqi_latest = aqi.latest();
+    http_service.route("/api/aqi", "application/json", move |_| {
+        serde_json::to_string(&*aqi_latest.lock().unwrap()).ok()
+    });
+
+    let clock = ClockEnricher::new(metrics.clone());
+    let clock_latest = clock.latest();
+    http_service.route("/api/clock", "application/json", move |_| {
+        serde_json::to_string(&*clock_latest.lock().unwrap()).ok()
+    });
+
+    let environment = EnvironmentEnricher::new(metrics.clone());
+    let environment_latest = environment.latest();
+    http_service.route("/api/environment", "application/json", move |_| {
+        serde_json::to_string(&*environment_latest.lock().unwrap()).ok()
+    });
+
+    let mut enrichers: Vec<Box<MessageEnricher>> = Vec::new();
+    enrichers.push(Box::new(clock));
+    // Humidity correction has to happen before the AQI is computed
+    enrichers.push(Box::new(environment));
+    enrichers.push(Box::new(aqi));
+
+    let ws_feed = WsFeed::new();
+    let ws_clients = ws_feed.clients();
+
+    let mut sinks: Vec<Box<MessageSink>> = Vec::new();
+    sinks.push(Box::new(ws_feed));
+
+    let stats = StatsSink::new(metrics.clone());
+    let stats_latest = stats.latest();
+    http_service.route("/api/stats", "application/json", move |_| {
+        serde_json::to_string(&*stats_latest.lock().unwrap()).ok()
+    });
+    sinks.push(Box::new(stats));
+
+    let store_sink = StoreSink::new(Retention {
+        raw:    rc.raw_retention,
+        hourly: rc.hourly_retention,
+        daily:  rc.daily_retention
+    });
+    let store = store_sink.store();
+    let store_http = store.clone();
+    http_service.route("/api/readings/", "application/json", move |path| {
+        let mut parts = path.splitn(2, '/');
+        let host = parts.next().unwrap_or("");
+        let quantity = parts.next().unwrap_or("pm2.5");
+        store_http.lock().unwrap().readings(host, quantity).and_then(|r| serde_json::to_string(&r).ok())
+    });
+    sinks.push(Box::new(store_sink));
+
+    let sessions = SessionSink::new(rc.session_export.clone());
+    let sessions_list = sessions.tracker();
+    http_service.route("/api/sessions", "application/json", move |_| {
+        serde_json::to_string(&sessions_list.lock().unwrap().summaries()).ok()
+    });
+    let sessions_json = sessions.tracker();
+    http_service.route("/api/sessions/", "application/json", move |uuid| {
+        sessions_json.lock().unwrap().get(uuid).and_then(|s| serde_json::to_string(s).ok())
+    });
+    let sessions_csv = sessions.tracker();
+    http_service.route("/export/csv/", "text/csv", move |uuid| {
+        sessions_csv.lock().unwrap().get(uuid).map(|s| s.to_csv())
+    });
+    let sessions_gpx = sessions.tracker();
+    http_service.route("/export/gpx/", "application/gpx+xml", move |uuid| {
+        sessions_gpx.lock().unwrap().get(uuid).map(|s| s.to_gpx())
+    });
+    sinks.push(Box::new(sessions));
+
+    let unknown = UnknownSink::new(metrics.clone());
+    let unknown_seen = unknown.seen();
+    http_service.route("/api/unknown", "application/json", move |_| {
+        serde_json::to_string(&*unknown_seen.lock().unwrap()).ok()
+    });
+    sinks.push(Box::new(unknown));
+
+    if let Some(ref broker) = rc.mqtt_broker {
+        sinks.push(Box::new(MqttSink::new(MqttConfig {
+            broker:    broker.clone(),
+            client_id: format!("sensorweb-collector-{}", process::id()),
+            prefix:    rc.mqtt_prefix.clone(),
+            qos:       rc.mqtt_qos
+        })));
+    }
+
+    if rc.influx_url.is_some() || rc.influx_file.is_some() {
+        sinks.push(Box::new(InfluxSink::new(InfluxConfig {
+            url:   rc.influx_url.clone(),
+            file:  rc.influx_file.clone(),
+            batch: rc.influx_batch
+        })));
+    }
+
+    if let Some(ref url) = rc.aircasting_url {
+        sinks.push(Box::new(AirCastingSink::new(AirCastingConfig {
+            url:     url.clone(),
+            retries: rc.aircasting_retries
+        })));
+    }
+
+    if let Some(ref path) = rc.webhooks {
+        match WebhookConfig::load(path) {
+            Ok(configs) => {
+                for config in configs {
+                    sinks.push(Box::new(WebhookSink::new(config)));
+                }
+            },
+            Err(err) => error!("Unable to load webhooks: {}", err)
+        }
+    }
+
+    if let Some(ref upstream) = rc.relay_to {
+        let collector_id = match rc.collector_id {
+            Some(ref id) => id.clone(),
+            None         => {
+                error!("Relaying needs a --collector-id");
+                process::exit(1);
+            }
+        };
+        let key = match rc.relay_key.as_ref().map(|path| load_key(path)) {
+            Some(Ok(key))  => key,
+            Some(Err(err)) => {
+                error!("Unable to load relay key: {}", err);
+                process::exit(1);
+            },
+            None           => {
+                error!("Relaying needs a --relay-key");
+                process::exit(1);
+            }
+        };
+
+        sinks.push(Box::new(RelaySink::new(RelayConfig {
+            upstream:     upstream.clone(),
+            collector_id: collector_id,
+            key:          key,
+            buffer:       rc.relay_buffer
+        }, metrics.clone())));
+    }
+
+    if let Some(ref path) = rc.alert_rules {
+        match Rule::load(path) {
+            Ok(rules) => {
+                let mut notifiers: Vec<Box<Notifier>> = Vec::new();
+                for url in rc.alert_webhooks.iter() {
+                    notifiers.push(Box::new(WebhookNotifier::new(url.clone())));
+                }
+                if let Some(ref server) = rc.alert_smtp {
+                    if rc.alert_mail_to.is_empty() {
+                        warn!("No --alert-mail-to recipient, not mailing alerts");
+                    } else {
+                        notifiers.push(Box::new(SmtpNotifier::new(SmtpConfig {
+                            server: server.clone(),
+                            from:   rc.alert_mail_from.clone(),
+                            to:     rc.alert_mail_to.clone()
+                        })));
+                    }
+                }
+                if rc.alert_stdout || notifiers.is_empty() {
+                    notifiers.push(Box::new(StdoutNotifier));
+                }
+
+                let alerts = AlertSink::new(rules, notifiers, metrics.clone());
+                let alerts_engine = alerts.engine();
+                http_service.route("/api/alerts", "application/json", move |_| {
+                    serde_json::to_string(&alerts_engine.lock().unwrap().firing()).ok()
+                });
+                sinks.push(Box::new(alerts));
+            },
+            Err(err) => error!("Unable to load alerting rules: {}", err)
+        }
+    }
+
     let mut threads = Vec::new();
     let thread_messages = thread::Builder::new().name("MessageManager".to_string()).spawn(move || {
-        th_message_manager(rx);
+        th_message_manager(rx, enrichers, sinks);
     });
     threads.push(thread_messages);
 
     let rc_http = rc.clone();
     let thread_http = thread::Builder::new().name("HttpService".to_string()).spawn(move || {
-        th_http_listener(rc_http.http_bind);
+        th_http_listener(rc_http.http_bind, http_service);
     });
     threads.push(thread_http);
 
+    let thread_store = thread::Builder::new().name("StoreDownsampler".to_string()).spawn(move || {
+        th_store_downsampler(store);
+    });
+    threads.push(thread_store);
+
 	let rc_ws = rc.clone();
     let thread_ws = thread::Builder::new().name("WebSocketService".to_string()).spawn(move || {
-        th_ws_listener(rc_ws.ws_bind);
+        th_ws_listener(rc_ws.ws_bind, ws_clients);
     });
     threads.push(thread_ws);
 
-    let thread_network = thread::Builder::new().name("MulticastListener".to_string()).spawn(move || {
-        bind_mcast(rc.multicast_group.clone(), rc.multicast_port.clone(), tx);
-    });
+    let thread_network = if let Some(replay_file) = rc.replay_file.clone() {
+        thread::Builder::new().name("Replay".to_string()).spawn(move || {
+            replay(replay_file, rc.replay_pacing, tx, parsers);
+        })
+    } else {
+        let capture = rc.capture_file.as_ref().and_then(|path| {
+            match CaptureWriter::open(path, rc.capture_max_bytes, rc.capture_files) {
+                Ok(w)    => Some(Arc::new(Mutex::new(w))),
+                Err(err) => {
+                    error!("Unable to open capture file {:?}: {}", path, err);
+                    None
+                }
+            }
+        });
+
+        let auth = rc.auth_keys.as_ref().and_then(|path| {
+            match AuthKeys::load(path) {
+                Ok(keys) => Some(Authenticator::new(keys, rc.auth_optional, metrics.clone())),
+                Err(err) => {
+                    // Better not to listen at all than to trust anyone
+                    error!("Unable to load authentication keys: {}", err);
+                    process::exit(1);
+                }
+            }
+        });
+
+        let mut sources = Vec::new();
+        for s in &rc.allow_sources {
+            match Cidr::parse(s) {
+                Ok(cidr) => sources.push(cidr),
+                Err(err) => {
+                    error!("Unable to parse allowed source: {}", err);
+                    process::exit(1);
+                }
+            }
+        }
+
+        let filter_config = FilterConfig {
+            sources:  sources,
+            hosts:    rc.allow_hosts.clone(),
+            check_ip: rc.check_up_ip,
+            rate:     rc.rate_limit,
+            burst:    rc.rate_burst
+        };
+        let filtering = !filter_config.sources.is_empty() || !filter_config.hosts.is_empty()
+                        || filter_config.check_ip || filter_config.rate > 0.0;
+        let filter = if filtering {
+            Some(SourceFilter::new(filter_config, metrics.clone()))
+        } else {
+            None
+        };
+        let ingest = Arc::new(Mutex::new(Ingest::new(parsers, auth, filter)));
+
+        for bind in rc.udp_binds.clone() {
+            let (tx_udp, ingest_udp, capture_udp) = (tx.clone(), ingest.clone(), capture.clone());
+            threads.push(thread::Builder::new().name("UdpListener".to_string()).spawn(move || {
+                bind_udp(bind, Transport::Udp, tx_udp, ingest_udp, capture_udp);
+            }));
+        }
+
+        for bind in rc.syslog_binds.clone() {
+            let (tx_syslog, ingest_syslog, capture_syslog) = (tx.clone(), ingest.clone(), capture.clone());
+            threads.push(thread::Builder::new().name("SyslogListener".to_string()).spawn(move || {
+                bind_udp(bind, Transport::Syslog, tx_syslog, ingest_syslog, capture_syslog);
+            }));
+        }
+
+        for bind in rc.tcp_binds.clone() {
+            let (tx_tcp, ingest_tcp, capture_tcp) = (tx.clone(), ingest.clone(), capture.clone());
+            threads.push(thread::Builder::new().name("TcpListener".to_string()).spawn(move || {
+                bind_tcp(bind, tx_tcp, ingest_tcp, capture_tcp);
+            }));
+        }
+
+        if let Some(bind) = rc.relay_listen.clone() {
+            let keys = match rc.relay_keys.as_ref().map(|path| AuthKeys::load(path)) {
+                Some(Ok(keys)) => keys,
+                Some(Err(err)) => {
+                    error!("Unable to load relay keys: {}", err);
+                    process::exit(1);
+                },
+                None           => {
+                    // Anyone could inject messages otherwise
+                    error!("Accepting relay links needs --relay-keys");
+                    process::exit(1);
+                }
+            };
+            // Without it, downstream collectors only relay their own messages
+            let origins = match rc.relay_origins.as_ref().map(|path| RelayOrigins::load(path)) {
+                Some(Ok(origins)) => origins,
+                Some(Err(err))    => {
+                    error!("Unable to load relay origins: {}", err);
+                    process::exit(1);
+                },
+                None              => RelayOrigins::default()
+            };
+            let (tx_relay, server) = (tx.clone(), RelayServer::new(keys, origins, metrics.clone()));
+            threads.push(thread::Builder::new().name("RelayListener".to_string()).spawn(move || {
+                bind_relay(bind, server, tx_relay);
+            }));
+        }
+
+        thread::Builder::new().name("MulticastListener".to_string()).spawn(move || {
+            bind_mcast(rc.multicast_group.clone(), rc.multicast_port.clone(), tx, capture, ingest);
+        })
+    };
     threads.push(thread_network);
 
     for hdl in threads {
@@ -1,11 +1,13 @@
 use std::str;
 use std::str::FromStr;
-use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, UdpSocket};
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr, UdpSocket};
 
 use std::sync::mpsc::SyncSender;
 
 use args::UdpPort;
-use message::{NetworkMsg, parse_from_string};
+use capture::{SharedCapture, capture};
+use ingest::{SharedIngest, forward};
+use message::{NetworkMsg, Transport};
 
 fn bind_and_join(ip: IpAddr, port: UdpPort) -> UdpSocket {
     let bind_addr = format!("{}:{}", "0.0.0.0", port);
@@ -44,33 +46,33 @@ fn leave(ip: IpAddr, port: UdpPort, socket: UdpSocket) {
     drop(socket)
 }
 
-fn read_to_string(socket: &UdpSocket) -> String {
+/// Receives one datagram over `transport`, recording it to `writer` if any
+pub fn read_to_string(socket: &UdpSocket, transport: Transport, writer: &Option<SharedCapture>) -> Option<(String, SocketAddr)> {
     let mut buf = [0; 1024];
 
     match socket.recv_from(&mut buf) {
         Ok((received, src)) => {
+            capture(writer, &src, transport, &buf[0..received]);
+
             let s = String::from_str(str::from_utf8(&buf[0..received]).unwrap_or("").trim()).unwrap();
             debug!("received {} bytes from {}: {}", received, src, s);
-            s
+            Some((s, src))
         }
         Err(e) => {
             debug!("recv function failed: {:?}", e);
-            String::from("")
+            None
         }
     }
 }
 
-pub fn bind_mcast(ip: IpAddr, port: UdpPort, tx: SyncSender<NetworkMsg>) {
+pub fn bind_mcast(ip: IpAddr, port: UdpPort, tx: SyncSender<NetworkMsg>, capture: Option<SharedCapture>, ingest: SharedIngest) {
     info!("Network thread started");
 
     let socket = bind_and_join(ip, port);
 
     loop {
-        let msg = parse_from_string(read_to_string(&socket));
-        info!("Sending parsed message: {:?}", msg);
-        match tx.send(msg) {
-            Ok(_)    => debug!("Successfully sent message to thread"),
-            Err(err) => error!("Error while sending message to thread: {:?}", err)
+        if let Some((s, src)) = read_to_string(&socket, Transport::Multicast, &capture) {
+            forward(&ingest, &s, &src, Transport::Multicast, &tx);
         }
     }
 
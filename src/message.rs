@@ -1,20 +1,77 @@
 use std::collections::HashMap;
 
-#[derive(Debug)]
+use chrono::{DateTime, FixedOffset, TimeZone, Utc};
+use serde_json::{self, Value};
+
+use datetime::{parse_builddate, parse_datetime, parse_ntp_date};
+
+#[derive(Debug, Serialize, Deserialize)]
 pub struct NetworkMsg {
-    host: String,
-    time: f32,
-    msg:  MessageContent
+    pub host: String,
+    pub time: f32,
+    pub msg:  MessageContent,
+    /// Values derived by the collector itself, e.g. air quality indices
+    pub annotations: HashMap<String, String>,
+    /// Line as received, kept verbatim
+    pub raw:  String,
+    /// How the line reached the collector, set by the listener that received it
+    pub transport: Transport,
+    /// Address the line was sent from, when received from the network
+    pub source: Option<String>,
+    /// Collector that received the line, when relayed from another one
+    pub origin: Option<String>
 }
 
-#[derive(Debug, Eq, PartialEq)]
-struct MessageContent {
-    mtype: MessageType,
-    mvals: HashMap<String, String>
+#[derive(Debug, Eq, PartialEq, Clone, Copy, Serialize, Deserialize)]
+pub enum Transport {
+    Multicast,
+    Udp,
+    Tcp,
+    Syslog,
+    Replay
 }
 
-#[derive(Debug, Eq, PartialEq, Clone, Copy)]
-enum MessageType {
+impl Transport {
+    pub fn name(&self) -> &'static str {
+        match *self {
+            Transport::Multicast => "multicast",
+            Transport::Udp       => "udp",
+            Transport::Tcp       => "tcp",
+            Transport::Syslog    => "syslog",
+            Transport::Replay    => "replay",
+        }
+    }
+
+    pub fn from_name(s: &str) -> Option<Transport> {
+        match s {
+            "multicast" => Some(Transport::Multicast),
+            "udp"       => Some(Transport::Udp),
+            "tcp"       => Some(Transport::Tcp),
+            "syslog"    => Some(Transport::Syslog),
+            "replay"    => Some(Transport::Replay),
+            _           => None
+        }
+    }
+}
+
+#[derive(Debug, Eq, PartialEq, Serialize, Deserialize)]
+pub struct MessageContent {
+    pub mtype: MessageType,
+    pub mvals: HashMap<String, String>,
+    /// Identifier as sent, before the first colon, even when not recognised
+    pub identifier: String,
+    /// Everything after the identifier, trimmed
+    pub payload: String,
+    /// Device time of an Ntp reading, or of the sync an NTPSyncEvent reports
+    pub datetime: Option<DateTime<FixedOffset>>,
+    /// Firmware build time announced by NodeUp
+    pub builddate: Option<DateTime<Utc>>,
+    /// Fields dropped from `mvals` because they did not parse
+    pub errors: Vec<String>
+}
+
+#[derive(Debug, Eq, PartialEq, Clone, Copy, Serialize, Deserialize)]
+pub enum MessageType {
     UnknownMessage,
     NodeUp,
     Ntp,
@@ -22,108 +79,405 @@ enum MessageType {
     NtpSync,
     Session,
     AirCasting,
+    Temperature,
+    Humidity,
+    Pressure,
+    /// Declared in the parsers configuration rather than built in
+    Custom,
 }
 
-pub fn parse_from_string(s: String) -> NetworkMsg {
+impl MessageType {
+    /// Short lowercase name, suitable for topics, measurements and filters
+    pub fn name(&self) -> &'static str {
+        match *self {
+            MessageType::UnknownMessage => "unknown",
+            MessageType::NodeUp         => "up",
+            MessageType::Ntp            => "ntp",
+            MessageType::Loop           => "loop",
+            MessageType::NtpSync        => "ntpsync",
+            MessageType::Session        => "session",
+            MessageType::AirCasting     => "aircasting",
+            MessageType::Temperature    => "temperature",
+            MessageType::Humidity       => "humidity",
+            MessageType::Pressure       => "pressure",
+            MessageType::Custom         => "custom",
+        }
+    }
+
+    /// Type named `name`, as used by structured datagrams
+    pub fn from_name(name: &str) -> Option<MessageType> {
+        match name.to_lowercase().as_ref() {
+            "up"          => Some(MessageType::NodeUp),
+            "ntp"         => Some(MessageType::Ntp),
+            "loop"        => Some(MessageType::Loop),
+            "ntpsync"     => Some(MessageType::NtpSync),
+            "session"     => Some(MessageType::Session),
+            "aircasting"  => Some(MessageType::AirCasting),
+            "temperature" => Some(MessageType::Temperature),
+            "humidity"    => Some(MessageType::Humidity),
+            "pressure"    => Some(MessageType::Pressure),
+            _             => None
+        }
+    }
+
+    /// `mvals` key of the reading carried by this type, if any
+    pub fn measurement(&self) -> Option<&'static str> {
+        match *self {
+            MessageType::Ntp         => Some("pm2.5"),
+            MessageType::Temperature => Some("temperature"),
+            MessageType::Humidity    => Some("humidity"),
+            MessageType::Pressure    => Some("pressure"),
+            _                        => None
+        }
+    }
+}
+
+/// What an NTPSyncEvent line reports, its text being kept in `mvals["raw"]`
+#[derive(Debug, Clone, PartialEq)]
+pub enum NtpSyncEvent {
+    /// Successful sync at this device time; the node prints no zone, so it is UTC until `set_zone` applies that of its NTP readings
+    Synced(DateTime<FixedOffset>),
+    /// Failed sync, with the reason the node gave after ` -- `
+    Error(String),
+    /// Clock stepped from one time to the other, `offset` seconds apart when both parse
+    Adjustment { from: String, to: String, offset: Option<i64> }
+}
+
+impl MessageContent {
+    /// Fills the typed timestamps, keeping in `mvals` only the strings that parsed
+    fn parse_dates(&mut self) {
+        if let Some(raw) = self.mvals.remove("datetime") {
+            match parse_datetime(&raw) {
+                Ok(dt)   => {
+                    self.datetime = Some(dt);
+                    self.mvals.insert(String::from("datetime"), raw);
+                },
+                Err(err) => {
+                    warn!("{}", err);
+                    self.errors.push(err);
+                }
+            }
+        }
+
+        if let Some(raw) = self.mvals.remove("builddate") {
+            match parse_builddate(&raw) {
+                Ok(dt)   => {
+                    self.builddate = Some(dt);
+                    self.mvals.insert(String::from("builddate"), raw);
+                },
+                Err(err) => {
+                    warn!("{}", err);
+                    self.errors.push(err);
+                }
+            }
+        }
+    }
+
+    pub fn ntpsync_event(&self) -> Option<NtpSyncEvent> {
+        if self.mtype != MessageType::NtpSync {
+            return None;
+        }
+        match self.mvals.get("raw").and_then(|raw| parse_ntpsync_event(raw)) {
+            Some(NtpSyncEvent::Synced(dt)) => Some(NtpSyncEvent::Synced(self.datetime.unwrap_or(dt))),
+            other                          => other
+        }
+    }
+
+    /// Reads the local time of an NTPSyncEvent as being in `zone`, keeping `mvals["datetime"]` in step
+    pub fn set_zone(&mut self, zone: FixedOffset) {
+        if self.mtype != MessageType::NtpSync {
+            return;
+        }
+        if let Some(dt) = self.datetime.and_then(|dt| zone.from_local_datetime(&dt.naive_local()).single()) {
+            self.mvals.insert(String::from("datetime"), dt.to_rfc3339());
+            self.datetime = Some(dt);
+        }
+    }
+}
+
+pub fn parse_ntpsync_event(s: &str) -> Option<NtpSyncEvent> {
+    let s = s.trim();
+
+    // 16:24:59 30/05/2017 -- NTP server not reachable
+    if let Some(idx) = s.find(" -- ") {
+        return Some(NtpSyncEvent::Error(String::from(s[idx + 4..].trim())));
+    }
+
+    // 16:24:59 30/05/2017 => 16:25:02 30/05/2017
+    if let Some(idx) = s.find(" => ") {
+        let (from, to) = (s[..idx].trim(), s[idx + 4..].trim());
+        let offset = match (parse_ntp_date(from), parse_ntp_date(to)) {
+            (Some(f), Some(t)) => Some(t.signed_duration_since(f).num_seconds()),
+            _                  => None
+        };
+        return Some(NtpSyncEvent::Adjustment { from: String::from(from), to: String::from(to), offset: offset });
+    }
+
+    parse_ntp_date(s).and_then(|n| FixedOffset::east(0).from_local_datetime(&n).single()).map(NtpSyncEvent::Synced)
+}
+
+/// Turns the payload of one kind of message, what follows its `IDENTIFIER:`, into values
+pub trait MessageParser: Send + Sync {
+    fn mtype(&self) -> MessageType;
+    fn parse(&self, payload: &str, h: &mut HashMap<String, String>);
+}
+
+/// Parser of a message kind the firmware always sent
+struct BuiltinParser {
+    mtype: MessageType,
+    parse: fn(&str, &mut HashMap<String, String>)
+}
+
+impl MessageParser for BuiltinParser {
+    fn mtype(&self) -> MessageType {
+        self.mtype
+    }
+
+    fn parse(&self, payload: &str, h: &mut HashMap<String, String>) {
+        (self.parse)(payload, h)
+    }
+}
+
+/// Message parsers keyed by lowercase identifier
+pub struct ParserRegistry {
+    parsers: HashMap<String, Box<MessageParser>>
+}
+
+impl ParserRegistry {
+    pub fn new() -> ParserRegistry {
+        ParserRegistry {
+            parsers: HashMap::new()
+        }
+    }
+
+    /// Registry knowing every message kind the collector handles out of the box
+    pub fn builtin() -> ParserRegistry {
+        let mut r = ParserRegistry::new();
+        let builtins: [(&str, MessageType, fn(&str, &mut HashMap<String, String>)); 12] = [
+            ("up",           MessageType::NodeUp,      parse_nodeup),
+            ("ntp",          MessageType::Ntp,         parse_ntp),
+            ("loop",         MessageType::Loop,        parse_loop),
+            ("ntpsyncevent", MessageType::NtpSync,     parse_ntpsync),
+            ("sessionuuid",  MessageType::Session,     parse_session),
+            ("ac",           MessageType::AirCasting,  parse_aircasting),
+            ("temp",         MessageType::Temperature, parse_temperature),
+            ("temperature",  MessageType::Temperature, parse_temperature),
+            ("hum",          MessageType::Humidity,    parse_humidity),
+            ("humidity",     MessageType::Humidity,    parse_humidity),
+            ("press",        MessageType::Pressure,    parse_pressure),
+            ("pressure",     MessageType::Pressure,    parse_pressure),
+        ];
+
+        for &(identifier, mtype, parse) in builtins.iter() {
+            r.register(identifier, Box::new(BuiltinParser { mtype: mtype, parse: parse }));
+        }
+        r
+    }
+
+    /// Whether some parser handles `identifier`
+    pub fn handles(&self, identifier: &str) -> bool {
+        self.parsers.contains_key(&identifier.trim().to_lowercase())
+    }
+
+    /// Makes `parser` handle `identifier`, case insensitively, replacing any previous one
+    pub fn register(&mut self, identifier: &str, parser: Box<MessageParser>) {
+        if self.parsers.insert(identifier.to_lowercase(), parser).is_some() {
+            info!("Parser for {:?} replaced", identifier);
+        }
+    }
+}
+
+/// Highest structured datagram version understood
+pub const DATAGRAM_VERSION: u64 = 1;
+
+/// Payload value as the legacy parsers would have stored it, flags being `1` or `0`
+fn datagram_value(v: &Value) -> Option<String> {
+    match *v {
+        Value::Null          => None,
+        Value::Bool(b)       => Some(String::from(if b { "1" } else { "0" })),
+        Value::Number(ref n) => Some(n.to_string()),
+        Value::String(ref s) => Some(s.clone()),
+        _                    => Some(v.to_string())
+    }
+}
+
+/// Parses a structured datagram, e.g.
+/// `{"v":1,"host":"ESP_D427A9","uptime":11.065,"type":"ntp","payload":{"pm2.5":12,"UUID":"...","sent":true}}`,
+/// payload keys being those of the matching text line and `type` resolving like its identifier
+fn parse_datagram(registry: &ParserRegistry, s: &str) -> NetworkMsg {
+    let content = MessageContent {
+        mtype: MessageType::UnknownMessage,
+        mvals: HashMap::new(),
+        identifier: String::new(),
+        payload: String::new(),
+        datetime: None,
+        builddate: None,
+        errors: Vec::new()
+    };
+    let mut rv = NetworkMsg {
+        host: String::new(),
+        time: -1.0,
+        msg:  content,
+        annotations: HashMap::new(),
+        raw:  String::from(s),
+        transport: Transport::Multicast,
+        source: None,
+        origin: None
+    };
+
+    let datagram: Value = match serde_json::from_str(s) {
+        Ok(v)    => v,
+        Err(err) => {
+            warn!("Unable to parse datagram {:?}: {}", s, err);
+            rv.msg.errors.push(format!("invalid datagram: {}", err));
+            return rv;
+        }
+    };
+
+    match datagram.get("v").and_then(|v| v.as_u64()) {
+        Some(v) if v >= 1 && v <= DATAGRAM_VERSION => {},
+        v => {
+            warn!("Unsupported datagram version {:?}: {}", v, s);
+            rv.msg.errors.push(format!("unsupported datagram version {:?}", v));
+            return rv;
+        }
+    }
+
+    rv.host = String::from(datagram.get("host").and_then(|h| h.as_str()).unwrap_or(""));
+    rv.time = datagram.get("uptime").and_then(|t| t.as_f64()).map(|t| t as f32).unwrap_or(-1.0);
+
+    let mtype = datagram.get("type").and_then(|t| t.as_str()).unwrap_or("");
+    rv.msg.identifier = String::from(mtype);
+    // Configured parsers come first, like for text lines, then the type names of the builtin kinds
+    rv.msg.mtype = registry.parsers.get(&mtype.to_lowercase()).map(|p| p.mtype())
+                           .or(MessageType::from_name(mtype))
+                           .unwrap_or(MessageType::UnknownMessage);
+
+    if let Some(payload) = datagram.get("payload") {
+        rv.msg.payload = payload.to_string();
+        if let Some(fields) = payload.as_object() {
+            for (key, value) in fields.iter() {
+                if let Some(v) = datagram_value(value) {
+                    debug!("read {}={}", key, v);
+                    rv.msg.mvals.insert(normalize_key(key), v);
+                }
+            }
+        }
+    }
+
+    rv.msg.parse_dates();
+    rv
+}
+
+/// Host and uptime a text line starts with, `HOST: [uptime] ...`, the uptime being -1 when missing or malformed
+pub fn line_sender(s: &str) -> (&str, f32) {
+    let mut elements = s.splitn(2, ':');
+    let host = elements.next().unwrap_or("");
+    let time = elements.next().and_then(|rest| {
+        let start = rest.find('[')?;
+        let stop = start + rest[start..].find(']')?;
+        rest[start + 1..stop].parse::<f32>().ok()
+    });
+    (host, time.unwrap_or(-1.0))
+}
+
+/// Parses a line, dispatching its payload to the parser registered for its identifier
+pub fn parse_with(registry: &ParserRegistry, s: String) -> NetworkMsg {
+    // Structured datagrams from newer firmware coexist with the text lines
+    if s.trim_left().starts_with('{') {
+        return parse_datagram(registry, s.trim());
+    }
+
     let empty_rv = NetworkMsg {
         host: String::from(""),
         time: 0.0,
-        msg:  parse_msg_content("")
+        msg:  parse_msg_content(registry, ""),
+        annotations: HashMap::new(),
+        raw:  s.clone(),
+        transport: Transport::Multicast,
+        source: None,
+        origin: None
     };
 
     let mut elements = s.splitn(2, ":");
     if let Some(msg_host) = elements.next() {
         debug!("msg_host={}", msg_host);
-        let msg_time = {
-            if let Some(next_element) = elements.next() {
-                let start_time   = next_element.find("[");
-                debug!("start_time={:?}", start_time);
-                let stop_time    = next_element.find("]");
-                debug!("stop_time={:?}", stop_time);
-                if let Some(start_time_idx) = start_time {
-                    if let Some(stop_time_idx) = stop_time {
-                        let substr = &next_element[start_time_idx+1..stop_time_idx];
-                        let time_as_f32 = substr.parse::<f32>().unwrap();
-                        debug!("extract: start_time={:?} stop_time={:?}", start_time, stop_time);
-                        debug!("extract: substr={}", time_as_f32);
-                        time_as_f32
-                    } else {
-                        -1.0
-                    }
-                } else {
-                    -1.0
-                }
-            } else {
-                -1.0
-            }
-        };
+        // A malformed uptime, `[abc]` or a `]` before the `[`, reads as none rather than failing the line
+        let (_, msg_time) = line_sender(&s);
+        debug!("extract: time={}", msg_time);
 
         let mut end_payload = s.splitn(3, " ");
         let msg_msg = if let Some(end_str) = end_payload.last() {
-                parse_msg_content(end_str)
+                parse_msg_content(registry, end_str)
             } else {
-                parse_msg_content("")
+                parse_msg_content(registry, "")
             };
 
         return NetworkMsg {
             host: msg_host.into(),
             time: msg_time,
-            msg:  msg_msg.into()
+            msg:  msg_msg.into(),
+            annotations: HashMap::new(),
+            raw:  s.clone(),
+            transport: Transport::Multicast,
+            source: None,
+            origin: None
         };
     }
 
     empty_rv
 }
 
-fn parse_msg_content(s: &str) -> MessageContent {
+/// Parses `IDENTIFIER: payload`, the part of a line after the host and uptime
+pub fn parse_msg_content(registry: &ParserRegistry, s: &str) -> MessageContent {
     let default_rv = MessageContent {
         mtype: MessageType::UnknownMessage,
-        mvals: HashMap::new()
+        mvals: HashMap::new(),
+        identifier: String::new(),
+        payload: String::new(),
+        datetime: None,
+        builddate: None,
+        errors: Vec::new()
     };
 
     let mut elements = s.splitn(2, ":");
     if let Some(identifier) = elements.next() {
-        let msg_identifier = match identifier.to_lowercase().as_ref() {
-            "up"      => MessageType::NodeUp,
-            "ntp"     => MessageType::Ntp,
-            "loop"    => MessageType::Loop,
-            "ntpsyncevent" => MessageType::NtpSync,
-            "sessionuuid"  => MessageType::Session,
-            "ac"      => MessageType::AirCasting,
-            _         => MessageType::UnknownMessage
-        };
-
-        let mut hashmap = if let Some(end_str) = elements.last() {
-            parse_to_hashmap(msg_identifier, end_str)
-        } else {
-            HashMap::new()
+        let payload = elements.last().unwrap_or("");
+        let mut hashmap = HashMap::new();
+        let msg_identifier = match registry.parsers.get(&identifier.trim().to_lowercase()) {
+            Some(parser) => {
+                debug!("end of string: {:?}", payload.trim());
+                parser.parse(payload.trim(), &mut hashmap);
+                parser.mtype()
+            },
+            None => {
+                if !identifier.is_empty() {
+                    debug!("unknown identifier {:?}, keeping payload {:?}", identifier, payload);
+                }
+                MessageType::UnknownMessage
+            }
         };
 
-        MessageContent {
+        let mut content = MessageContent {
             mtype: msg_identifier,
-            mvals: hashmap
-        }
+            mvals: hashmap,
+            identifier: String::from(identifier.trim()),
+            payload: String::from(payload.trim()),
+            datetime: None,
+            builddate: None,
+            errors: Vec::new()
+        };
+        content.parse_dates();
+        content
     } else {
         default_rv
     }
 }
 
-fn parse_to_hashmap(msg_identifier: MessageType, end_str: &str) -> HashMap<String, String> {
-    let mut hashmap = HashMap::new();
-    let end_str_clean = end_str.trim();
-    debug!("end of string: {:?}", end_str_clean);
-
-    match msg_identifier {
-        MessageType::NodeUp         => parse_nodeup(end_str_clean, &mut hashmap),
-        MessageType::Ntp            => parse_ntp(end_str_clean, &mut hashmap),
-        MessageType::Loop           => parse_loop(end_str_clean, &mut hashmap),
-        MessageType::NtpSync        => parse_ntpsync(end_str_clean, &mut hashmap),
-        MessageType::Session        => parse_session(end_str_clean, &mut hashmap),
-        MessageType::AirCasting     => parse_aircasting(end_str_clean, &mut hashmap),
-        MessageType::UnknownMessage => {}
-    }
-
-    hashmap
+/// Parses a line with the built in parsers only
+#[cfg(test)]
+pub fn parse_from_string(s: String) -> NetworkMsg {
+    parse_with(&ParserRegistry::builtin(), s)
 }
 
 fn parse_nodeup(s: &str, h: &mut HashMap<String, String>) {
@@ -181,41 +535,114 @@ fn parse_aircasting(s: &str, h: &mut HashMap<String, String>) {
 }
 
 fn parse_ntp(s: &str, h: &mut HashMap<String, String>) {
-    if s.find("PM2.5").is_some() {
-        // 2017-05-26T15:27:53.000+01:00 PM2.5: 12 UUID:d687fe3f-2d30-352d-0c21-ff3f2cea2040 sent:1
-        let mut split_ntp = s.splitn(5, " ");
-
-        if let Some(date) = split_ntp.next() {
-            let date_iso8601 = date.trim();
-            debug!("read date_iso8601={}", date_iso8601);
-            h.insert(String::from("datetime"), String::from(date_iso8601));
-        }
+    // 2017-05-26T15:27:53.000+01:00 PM2.5: 12 UUID:d687fe3f-2d30-352d-0c21-ff3f2cea2040 sent:1
+    // 2017-05-26T15:27:53.000+01:00 PM1.0: 8 PM2.5: 12 PM10: 15 CNT0.3: 1530 UUID:d687fe3f-2d30-352d-0c21-ff3f2cea2040 sent:1
+    let mut tokens = s.split_whitespace().peekable();
 
-        if let Some(pm25_head) = split_ntp.next() {
-            if let Some(pm25_val) = split_ntp.next() {
-                debug!("read pm25_val={}", pm25_val);
-                h.insert(String::from("pm2.5"), String::from(pm25_val));
-            }
+    if let Some(date) = tokens.peek().cloned() {
+        if !date.ends_with(':') {
+            debug!("read date_iso8601={}", date);
+            h.insert(String::from("datetime"), String::from(date));
+            tokens.next();
         }
+    }
+
+    parse_pairs(tokens, h);
+}
 
-        if let Some(uuid) = split_ntp.next() {
-            let mut uuid_all = uuid.splitn(2, ":");
-            let _ = uuid_all.next();
-            if let Some(uuid_val) = uuid_all.next() {
-                debug!("read uuid_val={}", uuid_val);
-                h.insert(String::from("UUID"), String::from(uuid_val));
+/// Reads `KEY: value` or `KEY:value` pairs, so new firmware fields need no change here
+fn parse_pairs<'a, I: Iterator<Item = &'a str>>(mut tokens: I, h: &mut HashMap<String, String>) {
+    while let Some(token) = tokens.next() {
+        let (key, value) = if token.ends_with(':') {
+            match tokens.next() {
+                Some(value) => (&token[..token.len() - 1], value),
+                None        => break
             }
+        } else if let Some(idx) = token.find(':') {
+            (&token[..idx], &token[idx + 1..])
+        } else {
+            debug!("ignoring stray token {:?}", token);
+            continue;
+        };
+
+        let key = normalize_key(key);
+        debug!("read {}={}", key, value);
+        h.insert(key, String::from(value));
+    }
+}
+
+/// Session UUIDs keep their historical upper case key, measurements are lower case
+fn normalize_key(key: &str) -> String {
+    if key == "UUID" { String::from(key) } else { key.to_lowercase() }
+}
+
+/// Converts a reading to °C, %RH or hPa according to its unit suffix
+fn to_canonical_unit(quantity: &str, token: &str) -> Option<f64> {
+    let idx = token.find(|c: char| !(c.is_digit(10) || c == '.' || c == '-' || c == '+')).unwrap_or(token.len());
+    let value = match token[..idx].parse::<f64>() {
+        Ok(v)  => v,
+        Err(_) => return None
+    };
+
+    let unit = token[idx..].trim_left_matches('°').to_lowercase();
+    let converted = match quantity {
+        "temperature" => match unit.as_ref() {
+            "" | "c"  => value,
+            "f"       => (value - 32.0) * 5.0 / 9.0,
+            "k"       => value - 273.15,
+            _         => return None
+        },
+        "humidity" => match unit.as_ref() {
+            "" | "%" | "%rh" => value,
+            _                => return None
+        },
+        "pressure" => match unit.as_ref() {
+            "" | "hpa" | "mbar" => value,
+            "pa"                => value / 100.0,
+            "kpa"               => value * 10.0,
+            _                   => return None
+        },
+        _ => return None
+    };
+    Some((converted * 100.0).round() / 100.0)
+}
+
+fn parse_temperature(s: &str, h: &mut HashMap<String, String>) {
+    parse_environment(s, "temperature", h)
+}
+
+fn parse_humidity(s: &str, h: &mut HashMap<String, String>) {
+    parse_environment(s, "humidity", h)
+}
+
+fn parse_pressure(s: &str, h: &mut HashMap<String, String>) {
+    parse_environment(s, "pressure", h)
+}
+
+fn parse_environment(s: &str, quantity: &str, h: &mut HashMap<String, String>) {
+    // 2017-05-26T15:27:53.000+01:00 21.5C sensor:BME280
+    // 48.2% sensor:DHT22
+    let mut tokens = s.split_whitespace().peekable();
+
+    if let Some(date) = tokens.peek().cloned() {
+        if date.len() > 10 && date.as_bytes()[4] == b'-' {
+            debug!("read date_iso8601={}", date);
+            h.insert(String::from("datetime"), String::from(date));
+            tokens.next();
         }
+    }
 
-        if let Some(sent) = split_ntp.next() {
-            let mut sent_all = sent.splitn(2, ":");
-            let _ = sent_all.next();
-            if let Some(sent_val) = sent_all.next() {
-                debug!("read sent_val={}", sent_val);
-                h.insert(String::from("sent"), String::from(sent_val));
-            }
+    if let Some(token) = tokens.next() {
+        match to_canonical_unit(quantity, token) {
+            Some(value) => {
+                debug!("read {}={}", quantity, value);
+                h.insert(String::from(quantity), value.to_string());
+            },
+            None => warn!("Unable to read {} from {:?}", quantity, token)
         }
     }
+
+    parse_pairs(tokens, h);
 }
 
 fn parse_loop(s: &str, h: &mut HashMap<String, String>) {
@@ -283,17 +710,47 @@ fn parse_loop(s: &str, h: &mut HashMap<String, String>) {
 }
 
 fn parse_ntpsync(s: &str, h: &mut HashMap<String, String>) {
-    // We want no " -- " AND no " => "
-    if s.find(" -- ").is_none() && s.find(" => ").is_none() {
-        let ntpdate = s.trim();
-        debug!("read ntpdate={}", ntpdate);
-        h.insert(String::from("ntpdate"), String::from(ntpdate));
+    let raw = s.trim();
+    debug!("read raw={}", raw);
+    h.insert(String::from("raw"), String::from(raw));
+
+    match parse_ntpsync_event(raw) {
+        Some(NtpSyncEvent::Synced(dt)) => {
+            h.insert(String::from("event"), String::from("synced"));
+            h.insert(String::from("ntpdate"), String::from(raw));
+            h.insert(String::from("datetime"), dt.to_rfc3339());
+        },
+        Some(NtpSyncEvent::Error(reason)) => {
+            debug!("read error={}", reason);
+            h.insert(String::from("event"), String::from("error"));
+            h.insert(String::from("error"), reason);
+        },
+        Some(NtpSyncEvent::Adjustment { from, to, offset }) => {
+            debug!("read from={} to={}", from, to);
+            h.insert(String::from("event"), String::from("adjustment"));
+            h.insert(String::from("from"), from);
+            h.insert(String::from("to"), to);
+            if let Some(offset) = offset {
+                h.insert(String::from("adjustment"), offset.to_string());
+            }
+        },
+        None => {
+            // Not a date we understand, still a sync as far as the node is concerned
+            h.insert(String::from("ntpdate"), String::from(raw));
+        }
     }
 }
 
+/// Whether `s` is a UUID in its canonical `8-4-4-4-12` hex digits form
+pub fn is_uuid(s: &str) -> bool {
+    let groups: Vec<&str> = s.split('-').collect();
+    groups.len() == 5
+        && groups.iter().zip([8, 4, 4, 4, 12].iter()).all(|(g, &len)| g.len() == len && g.chars().all(|c| c.is_digit(16)))
+}
+
 fn parse_session(s: &str, h: &mut HashMap<String, String>) {
-    if s.find("-").is_some() {
-        let uuid = s.trim();
+    let uuid = s.trim();
+    if is_uuid(uuid) {
         debug!("read uuid={}", uuid);
         h.insert(String::from("UUID"), String::from(uuid));
     }
@@ -301,6 +758,8 @@ fn parse_session(s: &str, h: &mut HashMap<String, String>) {
 
 #[test]
 fn test_parse_msgs() {
+    use chrono::TimeZone;
+
     let msg0 = String::from("");
     let n0 = parse_from_string(msg0);
     assert_eq!(n0.host, String::from(""));
@@ -316,6 +775,7 @@ fn test_parse_msgs() {
     assert_eq!(n1.msg.mvals[&String::from("version")], String::from("1.0"));
     assert_eq!(n1.msg.mvals[&String::from("builddate")], String::from("May 14 2017 01:34:24"));
     assert_eq!(n1.msg.mvals[&String::from("ip_addr")], String::from("192.168.1.29"));
+    assert_eq!(n1.msg.builddate, Some(Utc.ymd(2017, 5, 14).and_hms(1, 34, 24)));
 
     let msg2 = String::from("ESP_D427A9: [11.06000] AC:push: Code 200");
     let n2 = parse_from_string(msg2);
@@ -331,6 +791,7 @@ fn test_parse_msgs() {
     assert_eq!(n3.time, 11.065);
     assert_eq!(n3.msg.mtype, MessageType::Ntp);
     assert_eq!(n3.msg.mvals[&String::from("datetime")], String::from("2017-05-26T15:27:53.000+01:00"));
+    assert_eq!(n3.msg.datetime, Some(FixedOffset::east(3600).ymd(2017, 5, 26).and_hms(15, 27, 53)));
     assert_eq!(n3.msg.mvals[&String::from("pm2.5")], String::from("12"));
     assert_eq!(n3.msg.mvals[&String::from("UUID")], String::from("d687fe3f-2d30-352d-0c21-ff3f2cea2040"));
     assert_eq!(n3.msg.mvals[&String::from("sent")], String::from("1"));
@@ -357,6 +818,9 @@ fn test_parse_msgs() {
     assert_eq!(n6.time, 8.146);
     assert_eq!(n6.msg.mtype, MessageType::Session);
     assert_eq!(n6.msg.mvals[&String::from("UUID")], String::from("d687fe3f-2d30-352d-0c21-ff3f2cea2040"));
+    assert!(parse_from_string(String::from("ESP_D427A9: [8.14600] SessionUUID: ../../etc/cron.d/x")).msg.mvals.is_empty());
+    assert!(!is_uuid("d687fe3f-2d30-352d-0c21-ff3f2cea204"));
+    assert!(!is_uuid("d687fe3f-2d30-352d-0c21-ff3f2cea204/"));
 
     let msg7 = String::from("ESP_D427A9: [4.07500] Loop: no NTP initial sync, waiting ... sleepWakeCycles=1 ntpErrors=2");
     let n7 = parse_from_string(msg7);
@@ -367,3 +831,201 @@ fn test_parse_msgs() {
     assert_eq!(n7.msg.mvals[&String::from("sleepwakecycles")], String::from("1"));
     assert_eq!(n7.msg.mvals[&String::from("ntperrors")], String::from("2"));
 }
+
+#[test]
+fn test_malformed_uptime() {
+    let n = parse_from_string(String::from("X: [abc] y"));
+    assert_eq!((n.host.as_str(), n.time), ("X", -1.0));
+
+    let n = parse_from_string(String::from("a:]x["));
+    assert_eq!((n.host.as_str(), n.time), ("a", -1.0));
+
+    assert_eq!(line_sender("ESP_1: [11.06500] NTP: x"), ("ESP_1", 11.065));
+    assert_eq!(line_sender("ESP_1"), ("ESP_1", -1.0));
+}
+
+#[test]
+fn test_parse_ntpsync() {
+    let mut n = parse_from_string(String::from("ESP_D427A9: [7.97900] NTPSyncEvent: 16:24:59 30/05/2017"));
+    assert_eq!(n.msg.mvals["event"], "synced");
+    assert_eq!(n.msg.mvals["datetime"], "2017-05-30T16:24:59+00:00");
+    assert_eq!(n.msg.mvals["raw"], "16:24:59 30/05/2017");
+    assert_eq!(n.msg.ntpsync_event(), Some(NtpSyncEvent::Synced(FixedOffset::east(0).ymd(2017, 5, 30).and_hms(16, 24, 59))));
+
+    // In the zone of the node's readings, the same local time is an hour earlier in UTC
+    n.msg.set_zone(FixedOffset::east(3600));
+    assert_eq!(n.msg.mvals["datetime"], "2017-05-30T16:24:59+01:00");
+    match n.msg.ntpsync_event() {
+        Some(NtpSyncEvent::Synced(dt)) => assert_eq!(dt.with_timezone(&Utc), Utc.ymd(2017, 5, 30).and_hms(15, 24, 59)),
+        other                          => panic!("{:?}", other)
+    }
+
+    let n = parse_from_string(String::from("ESP_D427A9: [7.97900] NTPSyncEvent: Time Sync error -- NTP server not reachable"));
+    assert_eq!(n.msg.mtype, MessageType::NtpSync);
+    assert!(!n.msg.mvals.contains_key("ntpdate"));
+    assert_eq!(n.msg.mvals["event"], "error");
+    assert_eq!(n.msg.mvals["error"], "NTP server not reachable");
+    assert_eq!(n.msg.mvals["raw"], "Time Sync error -- NTP server not reachable");
+    assert_eq!(n.msg.ntpsync_event(), Some(NtpSyncEvent::Error(String::from("NTP server not reachable"))));
+
+    let n = parse_from_string(String::from("ESP_D427A9: [7.97900] NTPSyncEvent: 16:24:59 30/05/2017 => 16:25:02 30/05/2017"));
+    assert_eq!(n.msg.mvals["event"], "adjustment");
+    assert_eq!(n.msg.mvals["from"], "16:24:59 30/05/2017");
+    assert_eq!(n.msg.mvals["to"], "16:25:02 30/05/2017");
+    assert_eq!(n.msg.mvals["adjustment"], "3");
+
+    let n = parse_from_string(String::from("ESP_D427A9: [7.97900] NTPSyncEvent: 16:24:59 => soon"));
+    assert_eq!(n.msg.ntpsync_event(), Some(NtpSyncEvent::Adjustment {
+        from:   String::from("16:24:59"),
+        to:     String::from("soon"),
+        offset: None
+    }));
+    assert!(!n.msg.mvals.contains_key("adjustment"));
+}
+
+#[test]
+fn test_parse_date_errors() {
+    let n = parse_from_string(String::from("ESP_D427A9: [11.06500] NTP: 1970-01-01T00:00:07 PM2.5: 12 UUID:abc sent:1"));
+    assert_eq!(n.msg.mtype, MessageType::Ntp);
+    assert_eq!(n.msg.datetime, None);
+    assert!(!n.msg.mvals.contains_key("datetime"));
+    assert_eq!(n.msg.mvals["pm2.5"], "12");
+    assert_eq!(n.msg.errors.len(), 1);
+    assert!(n.msg.errors[0].contains("1970-01-01T00:00:07"));
+
+    let n = parse_from_string(String::from("ESP_D427A9: [2.89900] UP: 1.0:Smarch 14 2017 01:34:24@192.168.1.29"));
+    assert_eq!(n.msg.builddate, None);
+    assert!(!n.msg.mvals.contains_key("builddate"));
+    assert_eq!(n.msg.mvals["ip_addr"], "192.168.1.29");
+    assert_eq!(n.msg.errors.len(), 1);
+}
+
+#[test]
+fn test_parse_ntp_fields() {
+    let mut old = HashMap::new();
+    parse_ntp("2017-05-26T15:27:53.000+01:00 PM2.5: 12 UUID:d687fe3f-2d30-352d-0c21-ff3f2cea2040 sent:1", &mut old);
+    assert_eq!(old.len(), 4);
+    assert_eq!(old["datetime"], "2017-05-26T15:27:53.000+01:00");
+    assert_eq!(old["pm2.5"], "12");
+    assert_eq!(old["UUID"], "d687fe3f-2d30-352d-0c21-ff3f2cea2040");
+    assert_eq!(old["sent"], "1");
+
+    let mut new = HashMap::new();
+    parse_ntp("2017-05-26T15:27:53.000+01:00 PM1.0: 8 PM2.5: 12 PM10: 15 CNT0.3: 1530 CNT0.5: 420 CNT1.0: 80 \
+               CNT2.5: 12 CNT5.0: 3 CNT10: 1 UUID:d687fe3f-2d30-352d-0c21-ff3f2cea2040 sent:0", &mut new);
+    assert_eq!(new.len(), 12);
+    assert_eq!(new["pm1.0"], "8");
+    assert_eq!(new["pm2.5"], "12");
+    assert_eq!(new["pm10"], "15");
+    assert_eq!(new["cnt0.3"], "1530");
+    assert_eq!(new["cnt10"], "1");
+    assert_eq!(new["UUID"], "d687fe3f-2d30-352d-0c21-ff3f2cea2040");
+    assert_eq!(new["sent"], "0");
+
+    // No device time, a dangling key and a stray word
+    let mut odd = HashMap::new();
+    parse_ntp("PM2.5: 12 garbage PM10:", &mut odd);
+    assert_eq!(odd.len(), 1);
+    assert_eq!(odd["pm2.5"], "12");
+
+    let n = parse_from_string(String::from("ESP_D427A9: [11.06500] NTP: 2017-05-26T15:27:53.000+01:00 PM1.0: 8 PM2.5: 12 PM10: 15 UUID:abc sent:1"));
+    assert_eq!(n.msg.mtype, MessageType::Ntp);
+    assert_eq!(n.msg.mvals["pm10"], "15");
+    assert!(n.msg.datetime.is_some());
+}
+
+#[test]
+fn test_parse_environment() {
+    let t = parse_from_string(String::from("ESP_1: [11.07000] TEMP: 2017-05-26T15:27:53.000+01:00 21.5C sensor:BME280"));
+    assert_eq!(t.msg.mtype, MessageType::Temperature);
+    assert_eq!(t.msg.mvals["temperature"], "21.5");
+    assert_eq!(t.msg.mvals["sensor"], "BME280");
+    assert!(t.msg.datetime.is_some());
+
+    let h = parse_from_string(String::from("ESP_1: [11.07000] Humidity: 48.2% sensor:DHT22"));
+    assert_eq!(h.msg.mtype, MessageType::Humidity);
+    assert_eq!(h.msg.mvals["humidity"], "48.2");
+    assert_eq!(h.msg.datetime, None);
+
+    let p = parse_from_string(String::from("ESP_1: [11.07000] PRESS: 101325Pa"));
+    assert_eq!(p.msg.mtype, MessageType::Pressure);
+    assert_eq!(p.msg.mvals["pressure"], "1013.25");
+    assert_eq!(p.msg.mtype.measurement(), Some("pressure"));
+
+    assert_eq!(to_canonical_unit("temperature", "70.7°F"), Some(21.5));
+    assert_eq!(to_canonical_unit("temperature", "294.65K"), Some(21.5));
+    assert_eq!(to_canonical_unit("temperature", "-3.25"), Some(-3.25));
+    assert_eq!(to_canonical_unit("pressure", "101.3kPa"), Some(1013.0));
+    assert_eq!(to_canonical_unit("humidity", "48hPa"), None);
+    assert_eq!(to_canonical_unit("humidity", "n/a"), None);
+
+    let bad = parse_from_string(String::from("ESP_1: [11.07000] TEMP: n/a sensor:BME280"));
+    assert!(!bad.msg.mvals.contains_key("temperature"));
+    assert_eq!(bad.msg.mvals["sensor"], "BME280");
+}
+
+#[test]
+fn test_parse_unknown() {
+    let line = "ESP_D427A9: [12.50000] BATT: 3.71V low";
+    let n = parse_from_string(String::from(line));
+    assert_eq!(n.host, "ESP_D427A9");
+    assert_eq!(n.msg.mtype, MessageType::UnknownMessage);
+    assert!(n.msg.mvals.is_empty());
+    assert_eq!(n.msg.identifier, "BATT");
+    assert_eq!(n.msg.payload, "3.71V low");
+    assert_eq!(n.raw, line);
+
+    // Known messages keep them as well
+    let n = parse_from_string(String::from("ESP_D427A9: [8.14600] SessionUUID: d687fe3f-2d30-352d-0c21-ff3f2cea2040"));
+    assert_eq!(n.msg.identifier, "SessionUUID");
+    assert_eq!(n.msg.payload, "d687fe3f-2d30-352d-0c21-ff3f2cea2040");
+
+    let n = parse_from_string(String::from("garbage"));
+    assert_eq!(n.msg.mtype, MessageType::UnknownMessage);
+    assert_eq!(n.raw, "garbage");
+}
+
+#[test]
+fn test_parse_datagram() {
+    use chrono::TimeZone;
+
+    let n = parse_from_string(String::from("{\"v\":1,\"host\":\"ESP_D427A9\",\"uptime\":11.065,\"type\":\"ntp\",\
+                                            \"payload\":{\"datetime\":\"2017-05-26T15:27:53.000+01:00\",\"pm2.5\":12,\
+                                            \"pm10\":15.5,\"UUID\":\"d687fe3f-2d30-352d-0c21-ff3f2cea2040\",\"sent\":true,\"extra\":null}}"));
+    assert_eq!(n.host, "ESP_D427A9");
+    assert_eq!(n.time, 11.065);
+    assert_eq!(n.msg.mtype, MessageType::Ntp);
+    assert_eq!(n.msg.identifier, "ntp");
+    assert_eq!(n.msg.mvals["pm2.5"], "12");
+    assert_eq!(n.msg.mvals["pm10"], "15.5");
+    assert_eq!(n.msg.mvals["sent"], "1");
+    assert!(!n.msg.mvals.contains_key("extra"));
+    assert_eq!(n.msg.datetime, Some(FixedOffset::east(3600).ymd(2017, 5, 26).and_hms(15, 27, 53)));
+    assert!(n.raw.starts_with("{\"v\":1,"));
+
+    // Same reading as a text line, both fleets end up alike
+    let t = parse_from_string(String::from("ESP_D427A9: [11.06500] NTP: 2017-05-26T15:27:53.000+01:00 PM2.5: 12 PM10: 15.5 UUID:d687fe3f-2d30-352d-0c21-ff3f2cea2040 sent:1"));
+    assert_eq!(t.msg.mvals, n.msg.mvals);
+
+    let n = parse_from_string(String::from("{\"v\":1,\"host\":\"ESP_1\",\"type\":\"battery\",\"payload\":{\"voltage\":3.71}}"));
+    assert_eq!(n.msg.mtype, MessageType::UnknownMessage);
+    assert_eq!(n.msg.identifier, "battery");
+    assert_eq!(n.msg.payload, "{\"voltage\":3.71}");
+    assert_eq!(n.time, -1.0);
+
+    // Keys are normalized like on text lines, and types resolve through the registry
+    let n = parse_from_string(String::from("{\"v\":1,\"host\":\"ESP_1\",\"type\":\"NTP\",\"payload\":{\"PM2.5\":12,\"UUID\":\"abc\"}}"));
+    assert_eq!((n.msg.mtype, n.msg.mvals["pm2.5"].as_str(), n.msg.mvals["UUID"].as_str()), (MessageType::Ntp, "12", "abc"));
+    let mut r = ParserRegistry::builtin();
+    r.register("battery", Box::new(BuiltinParser { mtype: MessageType::Custom, parse: parse_session }));
+    let n = parse_with(&r, String::from("{\"v\":1,\"host\":\"ESP_1\",\"type\":\"battery\",\"payload\":{\"Voltage\":3.71}}"));
+    assert_eq!((n.msg.mtype, n.msg.mvals["voltage"].as_str()), (MessageType::Custom, "3.71"));
+
+    let n = parse_from_string(String::from("{\"v\":2,\"host\":\"ESP_1\",\"type\":\"ntp\"}"));
+    assert_eq!(n.msg.mtype, MessageType::UnknownMessage);
+    assert_eq!(n.msg.errors, vec![String::from("unsupported datagram version Some(2)")]);
+
+    let n = parse_from_string(String::from("{\"v\":1,"));
+    assert_eq!(n.msg.mtype, MessageType::UnknownMessage);
+    assert_eq!(n.msg.errors.len(), 1);
+}
@@ -1,15 +1,31 @@
 use std::sync::mpsc::Receiver;
 
 use message::NetworkMsg;
+use sink::{MessageEnricher, MessageSink};
 
-pub fn th_message_manager(rx: Receiver<NetworkMsg>) {
+pub fn th_message_manager(rx: Receiver<NetworkMsg>, mut enrichers: Vec<Box<MessageEnricher>>, mut sinks: Vec<Box<MessageSink>>) {
     info!("Message thread started");
 
     loop {
         info!("Waiting ...");
         match rx.recv() {
-            Ok(parsed_msg) => info!("Received message: {:?}", parsed_msg),
-            Err(err_recv)  => error!("Error trying to rx.recv(): {:?}", err_recv)
+            Ok(mut parsed_msg) => {
+                for enricher in enrichers.iter_mut() {
+                    debug!("Enriching message with {}", enricher.name());
+                    enricher.enrich(&mut parsed_msg);
+                }
+
+                info!("Received message: {:?}", parsed_msg);
+                for sink in sinks.iter_mut() {
+                    debug!("Dispatching message to sink {}", sink.name());
+                    sink.handle(&parsed_msg);
+                }
+            },
+            Err(err_recv)  => {
+                // Every producer is gone, e.g. a replay reached the end of its capture
+                info!("No more messages to receive: {:?}", err_recv);
+                break;
+            }
         }
     }
 }
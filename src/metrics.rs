@@ -0,0 +1,110 @@
+use std::collections::BTreeMap;
+use std::sync::{Arc, Mutex};
+
+#[derive(Debug, Eq, PartialEq, Clone, Copy)]
+enum MetricKind {
+    Counter,
+    Gauge
+}
+
+impl MetricKind {
+    fn name(&self) -> &'static str {
+        match *self {
+            MetricKind::Counter => "counter",
+            MetricKind::Gauge   => "gauge"
+        }
+    }
+}
+
+#[derive(Debug)]
+struct Family {
+    help:    String,
+    kind:    MetricKind,
+    samples: BTreeMap<String, f64>
+}
+
+/// Registry of counters and gauges, rendered in the Prometheus text format on `/metrics`
+#[derive(Debug, Default)]
+pub struct Metrics {
+    families: Mutex<BTreeMap<String, Family>>
+}
+
+pub type SharedMetrics = Arc<Metrics>;
+
+fn escape_label(s: &str) -> String {
+    s.replace("\\", "\\\\").replace("\"", "\\\"").replace("\n", "\\n")
+}
+
+fn render_labels(labels: &[(&str, &str)]) -> String {
+    if labels.is_empty() {
+        return String::new();
+    }
+
+    let rendered: Vec<String> = labels.iter().map(|&(k, v)| format!("{}=\"{}\"", k, escape_label(v))).collect();
+    format!("{{{}}}", rendered.join(","))
+}
+
+impl Metrics {
+    pub fn new() -> SharedMetrics {
+        Arc::new(Metrics::default())
+    }
+
+    fn update<F>(&self, name: &str, help: &str, kind: MetricKind, labels: &[(&str, &str)], f: F)
+        where F: FnOnce(&mut f64) {
+        let mut families = self.families.lock().unwrap();
+        let family = families.entry(String::from(name)).or_insert(Family {
+            help:    String::from(help),
+            kind:    kind,
+            samples: BTreeMap::new()
+        });
+
+        if family.kind != kind {
+            error!("Metric {} registered as {:?}, not {:?}", name, family.kind, kind);
+            return;
+        }
+
+        f(family.samples.entry(render_labels(labels)).or_insert(0.0));
+    }
+
+    pub fn gauge(&self, name: &str, help: &str, labels: &[(&str, &str)], value: f64) {
+        self.update(name, help, MetricKind::Gauge, labels, |v| *v = value);
+    }
+
+    pub fn counter_add(&self, name: &str, help: &str, labels: &[(&str, &str)], by: f64) {
+        self.update(name, help, MetricKind::Counter, labels, |v| *v += by);
+    }
+
+    pub fn render(&self) -> String {
+        let families = self.families.lock().unwrap();
+        let mut rv = String::new();
+
+        for (name, family) in families.iter() {
+            rv.push_str(&format!("# HELP {} {}\n", name, family.help));
+            rv.push_str(&format!("# TYPE {} {}\n", name, family.kind.name()));
+            for (labels, value) in family.samples.iter() {
+                rv.push_str(&format!("{}{} {}\n", name, labels, value));
+            }
+        }
+
+        rv
+    }
+}
+
+#[test]
+fn test_render() {
+    let m = Metrics::new();
+    m.counter_add("sensorweb_messages_total", "Messages received", &[("host", "ESP_1"), ("type", "ntp")], 1.0);
+    m.counter_add("sensorweb_messages_total", "Messages received", &[("host", "ESP_1"), ("type", "ntp")], 2.0);
+    m.gauge("sensorweb_pm25", "Last PM2.5 reading", &[("host", "ESP_\"2\"")], 12.5);
+    m.gauge("sensorweb_pm25", "Last PM2.5 reading", &[("host", "ESP_\"2\"")], 13.0);
+
+    // A name cannot change kind once registered
+    m.gauge("sensorweb_messages_total", "Messages received", &[("host", "ESP_1"), ("type", "ntp")], 42.0);
+
+    assert_eq!(m.render(), "# HELP sensorweb_messages_total Messages received\n\
+                            # TYPE sensorweb_messages_total counter\n\
+                            sensorweb_messages_total{host=\"ESP_1\",type=\"ntp\"} 3\n\
+                            # HELP sensorweb_pm25 Last PM2.5 reading\n\
+                            # TYPE sensorweb_pm25 gauge\n\
+                            sensorweb_pm25{host=\"ESP_\\\"2\\\"\"} 13\n");
+}
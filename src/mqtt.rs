@@ -0,0 +1,388 @@
+use std::cmp;
+use std::io;
+use std::io::{Read, Write};
+use std::net::TcpStream;
+use std::sync::mpsc::{sync_channel, Receiver, RecvTimeoutError, SyncSender, TrySendError};
+use std::thread;
+use std::time::Duration;
+
+use serde_json;
+
+use message::{MessageType, NetworkMsg};
+use sink::MessageSink;
+
+const CONNECT:    u8 = 0x10;
+const CONNACK:    u8 = 0x20;
+const PUBLISH:    u8 = 0x30;
+const PUBACK:     u8 = 0x40;
+const PINGREQ:    u8 = 0xC0;
+const PINGRESP:   u8 = 0xD0;
+const DISCONNECT: u8 = 0xE0;
+
+const KEEPALIVE_SECS:   u64 = 60;
+const MIN_BACKOFF_SECS: u64 = 1;
+const MAX_BACKOFF_SECS: u64 = 60;
+const QUEUE_SIZE:       usize = 1024;
+
+#[derive(Debug, Clone)]
+pub struct MqttConfig {
+    pub broker:    String,
+    pub client_id: String,
+    pub prefix:    String,
+    pub qos:       u8
+}
+
+#[derive(Debug, Clone, PartialEq)]
+struct Publication {
+    topic:   String,
+    payload: Vec<u8>,
+    retain:  bool
+}
+
+fn encode_remaining_length(mut len: usize, buf: &mut Vec<u8>) {
+    loop {
+        let mut byte = (len % 128) as u8;
+        len /= 128;
+        if len > 0 {
+            byte |= 0x80;
+        }
+        buf.push(byte);
+        if len == 0 {
+            break;
+        }
+    }
+}
+
+fn encode_string(s: &str, buf: &mut Vec<u8>) {
+    buf.push((s.len() >> 8) as u8);
+    buf.push((s.len() & 0xFF) as u8);
+    buf.extend_from_slice(s.as_bytes());
+}
+
+fn packet(header: u8, body: &[u8]) -> Vec<u8> {
+    let mut rv = vec![header];
+    encode_remaining_length(body.len(), &mut rv);
+    rv.extend_from_slice(body);
+    rv
+}
+
+fn connect_packet(client_id: &str, keepalive: u16) -> Vec<u8> {
+    let mut body = Vec::new();
+    encode_string("MQTT", &mut body);
+    body.push(4);    // protocol level 3.1.1
+    body.push(0x02); // clean session
+    body.push((keepalive >> 8) as u8);
+    body.push((keepalive & 0xFF) as u8);
+    encode_string(client_id, &mut body);
+    packet(CONNECT, &body)
+}
+
+fn publish_packet(p: &Publication, qos: u8, packet_id: u16) -> Vec<u8> {
+    let mut body = Vec::new();
+    encode_string(&p.topic, &mut body);
+    if qos > 0 {
+        body.push((packet_id >> 8) as u8);
+        body.push((packet_id & 0xFF) as u8);
+    }
+    body.extend_from_slice(&p.payload);
+
+    let header = PUBLISH | (qos << 1) | if p.retain { 1 } else { 0 };
+    packet(header, &body)
+}
+
+/// Reads one control packet, returning its first header byte and body
+fn read_packet<R: Read>(r: &mut R) -> io::Result<(u8, Vec<u8>)> {
+    let mut header = [0; 1];
+    r.read_exact(&mut header)?;
+
+    let mut len = 0usize;
+    let mut shift = 0;
+    loop {
+        let mut byte = [0; 1];
+        r.read_exact(&mut byte)?;
+        len |= ((byte[0] & 0x7F) as usize) << shift;
+        if byte[0] & 0x80 == 0 {
+            break;
+        }
+        shift += 7;
+        if shift > 21 {
+            return Err(io::Error::new(io::ErrorKind::InvalidData, "malformed remaining length"));
+        }
+    }
+
+    let mut body = vec![0; len];
+    r.read_exact(&mut body)?;
+    Ok((header[0], body))
+}
+
+/// Topic levels may not carry wildcards or separators coming from device names
+fn topic_level(s: &str) -> String {
+    s.chars().map(|c| match c {
+        '/' | '+' | '#' => '_',
+        _               => c
+    }).collect()
+}
+
+fn publications(prefix: &str, msg: &NetworkMsg) -> Vec<Publication> {
+    let mut rv = Vec::new();
+    let host = topic_level(&msg.host);
+    // `prefix//type` would not sort under any node
+    if host.is_empty() {
+        debug!("Not publishing message without a host: {}", msg.raw);
+        return rv;
+    }
+
+    match serde_json::to_vec(msg) {
+        Ok(payload) => rv.push(Publication {
+            topic:   format!("{}/{}/{}", prefix, host, msg.msg.mtype.name()),
+            payload: payload,
+            retain:  false
+        }),
+        Err(err) => error!("Unable to serialize message {:?}: {}", msg, err)
+    }
+
+    if msg.msg.mtype == MessageType::Ntp {
+        if let Some(pm25) = msg.msg.mvals.get("pm2.5") {
+            rv.push(Publication {
+                topic:   format!("{}/{}/pm2.5", prefix, host),
+                payload: pm25.clone().into_bytes(),
+                retain:  true
+            });
+        }
+    }
+
+    rv
+}
+
+struct MqttConnection {
+    stream:    TcpStream,
+    next_id:   u16
+}
+
+impl MqttConnection {
+    fn connect(broker: &str, client_id: &str) -> io::Result<MqttConnection> {
+        let mut stream = TcpStream::connect(broker)?;
+        stream.set_read_timeout(Some(Duration::from_secs(KEEPALIVE_SECS)))?;
+        stream.write_all(&connect_packet(client_id, KEEPALIVE_SECS as u16))?;
+
+        match read_packet(&mut stream)? {
+            (CONNACK, ref body) if body.len() == 2 && body[1] == 0 => {},
+            (header, body) => {
+                return Err(io::Error::new(io::ErrorKind::ConnectionRefused,
+                                          format!("unexpected CONNACK: {:#x} {:?}", header, body)));
+            }
+        }
+
+        Ok(MqttConnection {
+            stream:  stream,
+            next_id: 1
+        })
+    }
+
+    fn publish(&mut self, p: &Publication, qos: u8) -> io::Result<()> {
+        let packet_id = self.next_id;
+        self.next_id = self.next_id.wrapping_add(1);
+        if self.next_id == 0 {
+            self.next_id = 1;
+        }
+
+        self.stream.write_all(&publish_packet(p, qos, packet_id))?;
+        if qos == 0 {
+            return Ok(());
+        }
+
+        loop {
+            match read_packet(&mut self.stream)? {
+                (PUBACK, ref body) if body.len() == 2 => {
+                    if ((body[0] as u16) << 8 | body[1] as u16) == packet_id {
+                        return Ok(());
+                    }
+                },
+                (PINGRESP, _) => {},
+                (header, _)   => debug!("Ignoring MQTT packet {:#x} while waiting for PUBACK", header)
+            }
+        }
+    }
+
+    fn ping(&mut self) -> io::Result<()> {
+        self.stream.write_all(&[PINGREQ, 0])?;
+        match read_packet(&mut self.stream)? {
+            (PINGRESP, _) => Ok(()),
+            (header, _)   => Err(io::Error::new(io::ErrorKind::InvalidData,
+                                                format!("unexpected reply to PINGREQ: {:#x}", header)))
+        }
+    }
+
+    fn disconnect(mut self) {
+        let _ = self.stream.write_all(&[DISCONNECT, 0]);
+    }
+}
+
+fn connect_with_backoff(config: &MqttConfig) -> MqttConnection {
+    let mut backoff = MIN_BACKOFF_SECS;
+    loop {
+        match MqttConnection::connect(&config.broker, &config.client_id) {
+            Ok(conn) => {
+                info!("Connected to MQTT broker {}", config.broker);
+                return conn;
+            },
+            Err(err) => {
+                warn!("Unable to connect to MQTT broker {}: {}, retrying in {}s", config.broker, err, backoff);
+                thread::sleep(Duration::from_secs(backoff));
+                backoff = cmp::min(backoff * 2, MAX_BACKOFF_SECS);
+            }
+        }
+    }
+}
+
+fn th_mqtt_publisher(config: MqttConfig, rx: Receiver<Publication>) {
+    info!("MQTT publisher thread started: {}", config.broker);
+
+    let mut conn = connect_with_backoff(&config);
+    let mut pending: Option<Publication> = None;
+
+    loop {
+        let publication = match pending.take() {
+            Some(p) => p,
+            None    => match rx.recv_timeout(Duration::from_secs(KEEPALIVE_SECS / 2)) {
+                Ok(p) => p,
+                Err(RecvTimeoutError::Timeout) => {
+                    if let Err(err) = conn.ping() {
+                        warn!("MQTT keepalive failed: {}", err);
+                        conn = connect_with_backoff(&config);
+                    }
+                    continue;
+                },
+                Err(RecvTimeoutError::Disconnected) => break
+            }
+        };
+
+        if let Err(err) = conn.publish(&publication, config.qos) {
+            warn!("MQTT publish to {} failed: {}", publication.topic, err);
+            pending = Some(publication);
+            conn = connect_with_backoff(&config);
+        } else {
+            debug!("Published MQTT message to {}", publication.topic);
+        }
+    }
+
+    conn.disconnect();
+}
+
+/// Sink publishing every message to `{prefix}/{host}/{type}` on an MQTT broker
+pub struct MqttSink {
+    prefix: String,
+    tx:     SyncSender<Publication>
+}
+
+impl MqttSink {
+    pub fn new(config: MqttConfig) -> MqttSink {
+        let (tx, rx) = sync_channel(QUEUE_SIZE);
+        let prefix = config.prefix.clone();
+
+        let _ = thread::Builder::new().name("MqttPublisher".to_string()).spawn(move || {
+            th_mqtt_publisher(config, rx);
+        });
+
+        MqttSink {
+            prefix: prefix,
+            tx:     tx
+        }
+    }
+}
+
+impl MessageSink for MqttSink {
+    fn name(&self) -> &str {
+        "mqtt"
+    }
+
+    fn handle(&mut self, msg: &NetworkMsg) {
+        for publication in publications(&self.prefix, msg) {
+            match self.tx.try_send(publication) {
+                Ok(_)                         => {},
+                Err(TrySendError::Full(p))    => warn!("MQTT queue full, dropping message for {}", p.topic),
+                Err(TrySendError::Disconnected(p)) => error!("MQTT publisher is gone, dropping message for {}", p.topic)
+            }
+        }
+    }
+}
+
+#[test]
+fn test_encode_remaining_length() {
+    let cases: Vec<(usize, Vec<u8>)> = vec![
+        (0,         vec![0x00]),
+        (127,       vec![0x7F]),
+        (128,       vec![0x80, 0x01]),
+        (16383,     vec![0xFF, 0x7F]),
+        (16384,     vec![0x80, 0x80, 0x01]),
+        (268435455, vec![0xFF, 0xFF, 0xFF, 0x7F]),
+    ];
+
+    for (len, expected) in cases {
+        let mut buf = Vec::new();
+        encode_remaining_length(len, &mut buf);
+        assert_eq!(buf, expected);
+    }
+
+    let (header, body) = read_packet(&mut io::Cursor::new(packet(PUBLISH, &[1, 2, 3]))).unwrap();
+    assert_eq!(header, PUBLISH);
+    assert_eq!(body, vec![1, 2, 3]);
+}
+
+#[test]
+fn test_publications() {
+    use message::parse_from_string;
+
+    let msg = parse_from_string(String::from("ESP_D427A9: [11.06500] NTP: 2017-05-26T15:27:53.000+01:00 PM2.5: 12 UUID:d687fe3f-2d30-352d-0c21-ff3f2cea2040 sent:1"));
+    let p = publications("sensorweb", &msg);
+    assert_eq!(p.len(), 2);
+    assert_eq!(p[0].topic, "sensorweb/ESP_D427A9/ntp");
+    assert!(!p[0].retain);
+    assert_eq!(p[1].topic, "sensorweb/ESP_D427A9/pm2.5");
+    assert_eq!(p[1].payload, b"12".to_vec());
+    assert!(p[1].retain);
+
+    let msg = parse_from_string(String::from("ESP/#+: [11.06000] AC:push: Code 200"));
+    let p = publications("sensorweb", &msg);
+    assert_eq!(p.len(), 1);
+    assert_eq!(p[0].topic, "sensorweb/ESP___/aircasting");
+
+    assert!(publications("sensorweb", &parse_from_string(String::from(": [11.06000] AC:push: Code 200"))).is_empty());
+}
+
+#[test]
+fn test_publish_qos1() {
+    use std::net::TcpListener;
+
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let broker = listener.local_addr().unwrap().to_string();
+
+    let stand_in = thread::spawn(move || {
+        let (mut stream, _) = listener.accept().unwrap();
+
+        let (header, body) = read_packet(&mut stream).unwrap();
+        assert_eq!(header, CONNECT);
+        assert_eq!(&body[0..6], b"\x00\x04MQTT");
+        stream.write_all(&[CONNACK, 2, 0, 0]).unwrap();
+
+        let (header, body) = read_packet(&mut stream).unwrap();
+        assert_eq!(header, PUBLISH | (1 << 1) | 1);
+        assert_eq!(&body[0..2], b"\x00\x15");
+        assert_eq!(&body[2..23], b"sensorweb/ESP_1/pm2.5");
+        assert_eq!(&body[25..], b"42");
+        stream.write_all(&[PUBACK, 2, body[23], body[24]]).unwrap();
+
+        let (header, _) = read_packet(&mut stream).unwrap();
+        assert_eq!(header, DISCONNECT);
+    });
+
+    let mut conn = MqttConnection::connect(&broker, "test").unwrap();
+    conn.publish(&Publication {
+        topic:   String::from("sensorweb/ESP_1/pm2.5"),
+        payload: b"42".to_vec(),
+        retain:  true
+    }, 1).unwrap();
+    conn.disconnect();
+
+    stand_in.join().unwrap();
+}
@@ -0,0 +1,241 @@
+use std::collections::HashMap;
+use std::fs::File;
+use std::io::Read;
+use std::path::Path;
+
+use regex::{self, Regex};
+
+use message::{MessageParser, MessageType, ParserRegistry};
+
+/// How a declared parser reads its payload
+#[derive(Debug)]
+enum Format {
+    /// Named capture groups become values
+    Pattern(Regex),
+    /// Every `key=value` token becomes a value
+    Pairs
+}
+
+/// Parser declared in the parsers file, for firmware lines simple enough not to need code
+#[derive(Debug)]
+pub struct PatternParser {
+    kind:   String,
+    format: Format
+}
+
+/// Turns `{voltage}V {state}` into an anchored regex, the last placeholder taking the rest of the line
+fn template_to_regex(template: &str) -> Result<Regex, String> {
+    let mut rv = String::from("^");
+    let mut rest = template.trim();
+
+    while let Some(open) = rest.find('{') {
+        let close = rest[open..].find('}').map(|c| open + c).ok_or(format!("unclosed placeholder in {:?}", template))?;
+        rv.push_str(&literal(&rest[..open]));
+
+        let name = rest[open + 1..close].trim();
+        rest = &rest[close + 1..];
+        if rest.is_empty() {
+            rv.push_str(&format!("(?P<{}>.*)", name));
+        } else {
+            rv.push_str(&format!("(?P<{}>\\S+)", name));
+        }
+    }
+    rv.push_str(&literal(rest));
+    rv.push('$');
+
+    Regex::new(&rv).map_err(|e| format!("{:?}: {}", template, e))
+}
+
+/// Escapes template text, any run of whitespace matching any other
+fn literal(s: &str) -> String {
+    let mut rv = String::new();
+    let mut in_space = false;
+    for c in s.chars() {
+        if c.is_whitespace() {
+            if !in_space {
+                rv.push_str("\\s+");
+            }
+            in_space = true;
+        } else {
+            rv.push_str(&regex::escape(&c.to_string()));
+            in_space = false;
+        }
+    }
+    rv
+}
+
+impl PatternParser {
+    pub fn new(kind: &str, pattern: Option<&str>, template: Option<&str>) -> Result<PatternParser, String> {
+        let format = match (pattern, template) {
+            (Some(_), Some(_)) => return Err(format!("{}: both a pattern and a template", kind)),
+            (Some(p), None)    => Format::Pattern(Regex::new(p).map_err(|e| format!("{}: {}", kind, e))?),
+            (None, Some(t))    => Format::Pattern(template_to_regex(t).map_err(|e| format!("{}: {}", kind, e))?),
+            (None, None)       => Format::Pairs
+        };
+
+        Ok(PatternParser {
+            kind:   String::from(kind),
+            format: format
+        })
+    }
+
+    /// Reads `[kind]` sections of `identifier`, `pattern` or `template` settings, returning
+    /// every identifier along with the parser handling it
+    pub fn parse_config(s: &str) -> Result<Vec<(String, PatternParser)>, String> {
+        let mut sections: Vec<(String, Vec<String>, Option<String>, Option<String>)> = Vec::new();
+
+        for (lineno, raw) in s.lines().enumerate() {
+            let line = raw.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+
+            if line.starts_with('[') && line.ends_with(']') {
+                sections.push((String::from(line[1..line.len() - 1].trim()), Vec::new(), None, None));
+                continue;
+            }
+
+            let eq = line.find('=').ok_or(format!("line {}: expected key = value", lineno + 1))?;
+            let (key, value) = (line[..eq].trim(), line[eq + 1..].trim());
+            let section = sections.last_mut().ok_or(format!("line {}: {} outside of a [parser] section", lineno + 1, key))?;
+
+            match key {
+                "identifier" => section.1.push(String::from(value)),
+                "pattern"    => section.2 = Some(String::from(value)),
+                "template"   => section.3 = Some(String::from(value)),
+                _            => return Err(format!("line {}: unknown setting {:?}", lineno + 1, key))
+            }
+        }
+
+        let mut rv = Vec::new();
+        for (kind, identifiers, pattern, template) in sections {
+            if identifiers.is_empty() {
+                return Err(format!("{}: no identifier", kind));
+            }
+            for identifier in identifiers {
+                rv.push((identifier, PatternParser::new(&kind, pattern.as_ref().map(|p| p.as_str()),
+                                                        template.as_ref().map(|t| t.as_str()))?));
+            }
+        }
+        Ok(rv)
+    }
+
+    pub fn load(path: &Path) -> Result<Vec<(String, PatternParser)>, String> {
+        let mut contents = String::new();
+        File::open(path).and_then(|mut f| f.read_to_string(&mut contents))
+                        .map_err(|e| format!("{:?}: {}", path, e))?;
+        PatternParser::parse_config(&contents).map_err(|e| format!("{:?}: {}", path, e))
+    }
+
+    /// Adds the parsers declared in `path` to `registry`, declared ones winning over built in ones
+    pub fn register_all(path: &Path, registry: &mut ParserRegistry) -> Result<(), String> {
+        for (identifier, parser) in PatternParser::load(path)? {
+            info!("Parsing {:?} messages as {}", identifier, parser.kind);
+            registry.register(&identifier, Box::new(parser));
+        }
+        Ok(())
+    }
+}
+
+impl MessageParser for PatternParser {
+    fn mtype(&self) -> MessageType {
+        MessageType::Custom
+    }
+
+    fn parse(&self, payload: &str, h: &mut HashMap<String, String>) {
+        h.insert(String::from("kind"), self.kind.clone());
+
+        match self.format {
+            Format::Pattern(ref re) => {
+                let caps = match re.captures(payload) {
+                    Some(c) => c,
+                    None    => {
+                        warn!("{} payload {:?} does not match {}", self.kind, payload, re.as_str());
+                        return;
+                    }
+                };
+
+                for name in re.capture_names().filter_map(|n| n) {
+                    if let Some(m) = caps.name(name) {
+                        debug!("read {}={}", name, m.as_str());
+                        h.insert(String::from(name), String::from(m.as_str()));
+                    }
+                }
+            },
+            Format::Pairs => {
+                for token in payload.split(|c: char| c.is_whitespace() || c == ';' || c == ',') {
+                    if let Some(eq) = token.find('=') {
+                        let (key, value) = (token[..eq].trim().to_lowercase(), token[eq + 1..].trim());
+                        if !key.is_empty() {
+                            debug!("read {}={}", key, value);
+                            h.insert(key, String::from(value));
+                        }
+                    }
+                }
+            }
+        }
+    }
+}
+
+#[test]
+fn test_template() {
+    let re = template_to_regex("{voltage}V  {state}").unwrap();
+    assert_eq!(re.as_str(), "^(?P<voltage>\\S+)V\\s+(?P<state>.*)$");
+
+    let caps = re.captures("3.71V low, charging").unwrap();
+    assert_eq!(&caps["voltage"], "3.71");
+    assert_eq!(&caps["state"], "low, charging");
+
+    assert!(template_to_regex("{voltage").is_err());
+    assert!(template_to_regex("a.b {x}").unwrap().is_match("a.b 1"));
+    assert!(!template_to_regex("a.b {x}").unwrap().is_match("axb 1"));
+}
+
+#[test]
+fn test_declared_parsers() {
+    use message::parse_with;
+
+    let parsers = PatternParser::parse_config("# custom firmware lines\n\
+                                               [battery]\n\
+                                               identifier = BATT\n\
+                                               template = {voltage}V {state}\n\
+                                               \n\
+                                               [ota]\n\
+                                               identifier = OTA\n\
+                                               pattern = ^(?P<state>\\w+)( v(?P<version>\\S+))?$\n\
+                                               \n\
+                                               [status]\n\
+                                               identifier = STATUS\n\
+                                               identifier = STAT\n").unwrap();
+    assert_eq!(parsers.iter().map(|p| p.0.as_str()).collect::<Vec<_>>(), vec!["BATT", "OTA", "STATUS", "STAT"]);
+
+    let mut registry = ParserRegistry::builtin();
+    for (identifier, parser) in parsers {
+        registry.register(&identifier, Box::new(parser));
+    }
+
+    let n = parse_with(&registry, String::from("ESP_1: [12.50000] BATT: 3.71V low"));
+    assert_eq!(n.msg.mtype, MessageType::Custom);
+    assert_eq!(n.msg.mvals["kind"], "battery");
+    assert_eq!(n.msg.mvals["voltage"], "3.71");
+    assert_eq!(n.msg.mvals["state"], "low");
+
+    let n = parse_with(&registry, String::from("ESP_1: [12.50000] OTA: checking"));
+    assert_eq!(n.msg.mvals["state"], "checking");
+    assert!(!n.msg.mvals.contains_key("version"));
+
+    let n = parse_with(&registry, String::from("ESP_1: [12.50000] stat: heap=20480; rssi=-67, Reset=WDT"));
+    assert_eq!(n.msg.mvals["kind"], "status");
+    assert_eq!(n.msg.mvals["heap"], "20480");
+    assert_eq!(n.msg.mvals["rssi"], "-67");
+    assert_eq!(n.msg.mvals["reset"], "WDT");
+
+    // Built in kinds are still there
+    let n = parse_with(&registry, String::from("ESP_1: [11.06000] AC:push: Code 200"));
+    assert_eq!(n.msg.mtype, MessageType::AirCasting);
+
+    assert!(PatternParser::parse_config("identifier = X\n").is_err());
+    assert!(PatternParser::parse_config("[x]\npattern = .*\n").is_err());
+    assert!(PatternParser::parse_config("[x]\nidentifier = X\npattern = (\n").is_err());
+    assert!(PatternParser::parse_config("[x]\nidentifier = X\nformat = csv\n").is_err());
+}
@@ -0,0 +1,655 @@
+use std::cmp;
+use std::collections::{HashMap, VecDeque};
+use std::fs::File;
+use std::io;
+use std::io::{BufReader, Read, Write};
+use std::net::{TcpListener, TcpStream};
+use std::path::Path;
+use std::sync::{Arc, Mutex};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::mpsc::{sync_channel, Receiver, RecvTimeoutError, SyncSender, TrySendError};
+use std::thread;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+use rand::RngCore;
+use rand::rngs::OsRng;
+use serde_json::{self, Value};
+
+use auth::{AuthKeys, mac_hex, to_hex, verify_mac};
+use message::NetworkMsg;
+use metrics::SharedMetrics;
+use sink::MessageSink;
+use unicast::read_line;
+
+const QUEUE_SIZE:       usize = 1024;
+const MIN_BACKOFF_SECS: u64 = 1;
+const MAX_BACKOFF_SECS: u64 = 60;
+/// Messages sent before waiting for the upstream to acknowledge them
+const WINDOW:           usize = 100;
+const ACK_TIMEOUT_SECS: u64 = 30;
+/// Idle time after which the downstream checks the link is still up
+const KEEPALIVE_SECS:   u64 = 60;
+/// Longest handshake or acknowledgement line, read before the peer is authenticated
+const MAX_CONTROL_LINE: usize = 256;
+/// Longest relayed message line
+const MAX_MESSAGE_LINE: usize = 64 * 1024;
+/// Time a peer gets to authenticate before its link is dropped
+const HANDSHAKE_TIMEOUT_SECS: u64 = 10;
+/// Relay links served at once, later ones being refused until some close
+const MAX_LINKS:        usize = 64;
+
+fn proof_input(nonce: &str, collector_id: &str, run: &str) -> String {
+    format!("{} {} {}", nonce, collector_id, run)
+}
+
+/// Proof that a downstream collector holds `key`, answering the upstream's `nonce`
+pub fn proof(key: &str, nonce: &str, collector_id: &str, run: &str) -> String {
+    mac_hex(key, &proof_input(nonce, collector_id, run))
+}
+
+/// Key signing every line of a link once authenticated, bound to its challenge
+fn session_key(key: &str, nonce: &str, collector_id: &str, run: &str) -> String {
+    mac_hex(key, &format!("session {}", proof_input(nonce, collector_id, run)))
+}
+
+/// `data` followed by its MAC under `session_key`
+fn sign_line(session_key: &str, data: &str) -> String {
+    format!("{} {}", data, mac_hex(session_key, data))
+}
+
+/// What `line` carries, None unless its trailing MAC is that of `session_key`
+fn verify_line<'a>(session_key: &str, line: &'a str) -> Option<&'a str> {
+    let idx = line.rfind(' ')?;
+    if verify_mac(session_key, &line[..idx], &line[idx + 1..]) { Some(&line[..idx]) } else { None }
+}
+
+/// Reads the key a downstream collector authenticates with, the whole file trimmed
+pub fn load_key(path: &Path) -> Result<String, String> {
+    let mut contents = String::new();
+    File::open(path).and_then(|mut f| f.read_to_string(&mut contents))
+                    .map_err(|e| format!("{:?}: {}", path, e))?;
+    match contents.trim() {
+        ""  => Err(format!("{:?}: empty key", path)),
+        key => Ok(String::from(key))
+    }
+}
+
+/// Origins each downstream collector may forward messages for, besides its own ID
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct RelayOrigins {
+    allowed: HashMap<String, Vec<String>>
+}
+
+impl RelayOrigins {
+    /// Reads `collector = origin, origin` lines
+    pub fn parse(s: &str) -> Result<RelayOrigins, String> {
+        let mut rv = RelayOrigins::default();
+
+        for (lineno, raw) in s.lines().enumerate() {
+            let line = raw.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+
+            let eq = line.find('=').ok_or(format!("line {}: expected collector = origin, origin", lineno + 1))?;
+            let origins = line[eq + 1..].split(',').map(|o| String::from(o.trim())).filter(|o| !o.is_empty()).collect();
+            rv.allowed.insert(String::from(line[..eq].trim()), origins);
+        }
+
+        Ok(rv)
+    }
+
+    pub fn load(path: &Path) -> Result<RelayOrigins, String> {
+        let mut contents = String::new();
+        File::open(path).and_then(|mut f| f.read_to_string(&mut contents))
+                        .map_err(|e| format!("{:?}: {}", path, e))?;
+        RelayOrigins::parse(&contents).map_err(|e| format!("{:?}: {}", path, e))
+    }
+
+    /// Origin of a message `collector` relayed claiming `origin`, its own ID unless allowed otherwise
+    fn resolve(&self, collector: &str, origin: Option<String>) -> String {
+        match origin {
+            Some(o) => {
+                if o == collector || self.allowed.get(collector).map(|a| a.contains(&o)).unwrap_or(false) {
+                    o
+                } else {
+                    debug!("Collector {} may not relay messages of {:?}", collector, o);
+                    String::from(collector)
+                }
+            },
+            None    => String::from(collector)
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct RelayConfig {
+    /// `host:port` of the upstream collector's relay listener
+    pub upstream:     String,
+    pub collector_id: String,
+    pub key:          String,
+    /// Messages kept while the link is down, the oldest being dropped past this
+    pub buffer:       usize
+}
+
+/// Messages waiting for the upstream to acknowledge them, numbered in sending order
+struct RelayBuffer {
+    pending:  VecDeque<(u64, String)>,
+    next_seq: u64,
+    capacity: usize
+}
+
+impl RelayBuffer {
+    fn new(capacity: usize) -> RelayBuffer {
+        RelayBuffer {
+            pending:  VecDeque::new(),
+            next_seq: 1,
+            capacity: cmp::max(capacity, 1)
+        }
+    }
+
+    /// Queues `json`, false when the oldest message had to be dropped to make room
+    fn push(&mut self, json: String) -> bool {
+        let dropped = if self.pending.len() >= self.capacity {
+            self.pending.pop_front();
+            true
+        } else {
+            false
+        };
+        self.pending.push_back((self.next_seq, json));
+        self.next_seq += 1;
+        !dropped
+    }
+
+    fn window(&self) -> Vec<(u64, String)> {
+        self.pending.iter().take(WINDOW).cloned().collect()
+    }
+
+    /// Forgets every message up to `seq`, the upstream having them
+    fn ack(&mut self, seq: u64) {
+        while self.pending.front().map(|p| p.0 <= seq).unwrap_or(false) {
+            self.pending.pop_front();
+        }
+    }
+}
+
+struct RelayConnection {
+    reader:      BufReader<TcpStream>,
+    writer:      TcpStream,
+    session_key: String
+}
+
+impl RelayConnection {
+    fn connect(config: &RelayConfig, run: &str) -> io::Result<RelayConnection> {
+        let stream = TcpStream::connect(&config.upstream)?;
+        stream.set_read_timeout(Some(Duration::from_secs(ACK_TIMEOUT_SECS)))?;
+        let mut conn = RelayConnection {
+            writer:      stream.try_clone()?,
+            reader:      BufReader::new(stream),
+            session_key: String::new()
+        };
+
+        conn.writer.write_all(format!("HELLO {} {}\n", config.collector_id, run).as_bytes())?;
+        let challenge = conn.read_reply()?;
+        if !challenge.starts_with("CHALLENGE ") {
+            return Err(io::Error::new(io::ErrorKind::InvalidData, format!("unexpected reply to HELLO: {:?}", challenge)));
+        }
+
+        let nonce = String::from(challenge["CHALLENGE ".len()..].trim());
+        conn.writer.write_all(format!("AUTH {}\n", proof(&config.key, &nonce, &config.collector_id, run)).as_bytes())?;
+        match conn.read_reply()?.as_ref() {
+            "OK"  => {
+                conn.session_key = session_key(&config.key, &nonce, &config.collector_id, run);
+                Ok(conn)
+            },
+            reply => Err(io::Error::new(io::ErrorKind::PermissionDenied, format!("upstream refused the link: {:?}", reply)))
+        }
+    }
+
+    fn read_reply(&mut self) -> io::Result<String> {
+        match read_line(&mut self.reader, MAX_CONTROL_LINE)? {
+            Some(line) => Ok(String::from(line.trim())),
+            None       => Err(io::Error::new(io::ErrorKind::UnexpectedEof, "upstream closed the link"))
+        }
+    }
+
+    /// Sends the oldest buffered messages, forgetting them once acknowledged
+    fn send(&mut self, buffer: &mut RelayBuffer) -> io::Result<()> {
+        let window = buffer.window();
+        let last = match window.last() {
+            Some(&(seq, _)) => seq,
+            None            => return Ok(())
+        };
+
+        let mut lines = String::new();
+        for &(seq, ref json) in window.iter() {
+            lines.push_str(&sign_line(&self.session_key, &format!("{} {}", seq, json)));
+            lines.push('\n');
+        }
+        self.writer.write_all(lines.as_bytes())?;
+
+        loop {
+            let signed = self.read_reply()?;
+            let reply = match verify_line(&self.session_key, &signed) {
+                Some(r) => r,
+                None    => return Err(io::Error::new(io::ErrorKind::InvalidData, format!("unsigned reply: {:?}", signed)))
+            };
+            match reply.trim_left_matches("ACK ").parse::<u64>() {
+                Ok(seq) if reply.starts_with("ACK ") => {
+                    buffer.ack(seq);
+                    if seq >= last {
+                        return Ok(());
+                    }
+                },
+                _ => return Err(io::Error::new(io::ErrorKind::InvalidData, format!("unexpected reply: {:?}", reply)))
+            }
+        }
+    }
+
+    fn ping(&mut self) -> io::Result<()> {
+        self.writer.write_all(format!("{}\n", sign_line(&self.session_key, "PING")).as_bytes())?;
+        let signed = self.read_reply()?;
+        match verify_line(&self.session_key, &signed) {
+            Some("PONG") => Ok(()),
+            _            => Err(io::Error::new(io::ErrorKind::InvalidData, format!("unexpected reply to PING: {:?}", signed)))
+        }
+    }
+}
+
+fn th_relay_client(config: RelayConfig, rx: Receiver<String>, metrics: SharedMetrics) {
+    info!("Relay thread started: {} as {}", config.upstream, config.collector_id);
+
+    // Tells the upstream that sequence numbers started over
+    let run = SystemTime::now().duration_since(UNIX_EPOCH).map(|d| d.as_secs()).unwrap_or(0).to_string();
+    let mut buffer = RelayBuffer::new(config.buffer);
+    let mut conn: Option<RelayConnection> = None;
+    let mut backoff = MIN_BACKOFF_SECS;
+    let mut next_attempt = Instant::now();
+    let mut last_activity = Instant::now();
+
+    loop {
+        let wait = if conn.is_some() && !buffer.pending.is_empty() { 0 } else { 1 };
+        let mut received = Vec::new();
+        let disconnected = match rx.recv_timeout(Duration::from_secs(wait)) {
+            Ok(json) => {
+                received.push(json);
+                false
+            },
+            Err(RecvTimeoutError::Timeout)      => false,
+            Err(RecvTimeoutError::Disconnected) => true
+        };
+        received.extend(rx.try_iter());
+
+        for json in received {
+            if !buffer.push(json) {
+                metrics.counter_add("sensorweb_relay_dropped_total", "Messages dropped from a full relay buffer", &[], 1.0);
+            }
+        }
+
+        if conn.is_none() && Instant::now() >= next_attempt {
+            match RelayConnection::connect(&config, &run) {
+                Ok(c)    => {
+                    info!("Relaying to upstream collector {}, {} messages buffered", config.upstream, buffer.pending.len());
+                    conn = Some(c);
+                    backoff = MIN_BACKOFF_SECS;
+                    last_activity = Instant::now();
+                },
+                Err(err) => {
+                    warn!("Unable to connect to upstream collector {}: {}, retrying in {}s", config.upstream, err, backoff);
+                    next_attempt = Instant::now() + Duration::from_secs(backoff);
+                    backoff = cmp::min(backoff * 2, MAX_BACKOFF_SECS);
+                }
+            }
+        }
+
+        let result = match conn {
+            Some(ref mut c) if !buffer.pending.is_empty() => {
+                last_activity = Instant::now();
+                c.send(&mut buffer)
+            },
+            Some(ref mut c) if last_activity.elapsed() >= Duration::from_secs(KEEPALIVE_SECS) => {
+                last_activity = Instant::now();
+                c.ping()
+            },
+            _ => Ok(())
+        };
+        if let Err(err) = result {
+            warn!("Relay link to {} lost: {}, {} messages buffered", config.upstream, err, buffer.pending.len());
+            conn = None;
+            next_attempt = Instant::now();
+        }
+        metrics.gauge("sensorweb_relay_buffered", "Messages waiting for the upstream collector", &[], buffer.pending.len() as f64);
+
+        if disconnected {
+            break;
+        }
+    }
+}
+
+/// Sink forwarding every message to an upstream collector, tagged with this collector's ID
+pub struct RelaySink {
+    collector_id: String,
+    tx:           SyncSender<String>
+}
+
+impl RelaySink {
+    pub fn new(config: RelayConfig, metrics: SharedMetrics) -> RelaySink {
+        let (tx, rx) = sync_channel(QUEUE_SIZE);
+        let collector_id = config.collector_id.clone();
+
+        let _ = thread::Builder::new().name("RelayClient".to_string()).spawn(move || {
+            th_relay_client(config, rx, metrics);
+        });
+
+        RelaySink {
+            collector_id: collector_id,
+            tx:           tx
+        }
+    }
+
+    fn serialize(&self, msg: &NetworkMsg) -> Result<String, String> {
+        let mut value = serde_json::to_value(msg).map_err(|e| e.to_string())?;
+        if value["origin"].is_null() {
+            value["origin"] = Value::String(self.collector_id.clone());
+        }
+        serde_json::to_string(&value).map_err(|e| e.to_string())
+    }
+}
+
+impl MessageSink for RelaySink {
+    fn name(&self) -> &str {
+        "relay"
+    }
+
+    fn handle(&mut self, msg: &NetworkMsg) {
+        // Came back through a relay loop
+        if msg.origin.as_ref() == Some(&self.collector_id) {
+            return;
+        }
+
+        match self.serialize(msg) {
+            Ok(json) => match self.tx.try_send(json) {
+                Ok(_)                              => {},
+                Err(TrySendError::Full(_))         => warn!("Relay queue full, dropping message"),
+                Err(TrySendError::Disconnected(_)) => error!("Relay client is gone, dropping message")
+            },
+            Err(err) => error!("Unable to serialize message: {}", err)
+        }
+    }
+}
+
+/// Upstream end of relay links, merging the streams of downstream collectors
+#[derive(Clone)]
+pub struct RelayServer {
+    keys:    Arc<AuthKeys>,
+    origins: Arc<RelayOrigins>,
+    /// Run and last forwarded sequence number of every downstream collector
+    seen:    Arc<Mutex<HashMap<String, (String, u64)>>>,
+    metrics: SharedMetrics
+}
+
+impl RelayServer {
+    pub fn new(keys: AuthKeys, origins: RelayOrigins, metrics: SharedMetrics) -> RelayServer {
+        RelayServer {
+            keys:    Arc::new(keys),
+            origins: Arc::new(origins),
+            seen:    Arc::new(Mutex::new(HashMap::new())),
+            metrics: metrics
+        }
+    }
+
+    /// Whether message `seq` of `collector`'s `run` was not forwarded yet, as when resent
+    /// after a link broke before its acknowledgement got through
+    fn is_new(&self, collector: &str, run: &str, seq: u64) -> bool {
+        let mut seen = self.seen.lock().unwrap();
+        let entry = seen.entry(String::from(collector)).or_insert((String::from(run), 0));
+        if entry.0 != run {
+            *entry = (String::from(run), 0);
+        }
+        if seq <= entry.1 {
+            return false;
+        }
+        entry.1 = seq;
+        true
+    }
+
+    fn reject(&self, reason: &str, peer: &str, what: &str) -> Result<(), String> {
+        self.metrics.counter_add("sensorweb_relay_rejected_total", "Relay links refused", &[("reason", reason)], 1.0);
+        Err(format!("refused link from {} ({}): {}", peer, reason, what))
+    }
+
+    fn handle(&self, stream: TcpStream, tx: SyncSender<NetworkMsg>) -> Result<(), String> {
+        let peer = stream.peer_addr().map(|p| p.to_string()).unwrap_or(String::from("?"));
+        stream.set_read_timeout(Some(Duration::from_secs(HANDSHAKE_TIMEOUT_SECS))).map_err(|e| e.to_string())?;
+        let mut writer = stream.try_clone().map_err(|e| e.to_string())?;
+        let mut reader = BufReader::new(stream);
+        let mut next_line = |max: usize| -> Result<String, String> {
+            match read_line(&mut reader, max) {
+                Ok(Some(l)) => Ok(l),
+                Ok(None)    => Err(String::from("link closed")),
+                Err(e)      => Err(e.to_string())
+            }
+        };
+
+        let hello = next_line(MAX_CONTROL_LINE)?;
+        let tokens: Vec<&str> = hello.split_whitespace().collect();
+        if tokens.len() != 3 || tokens[0] != "HELLO" {
+            return self.reject("bad_hello", &peer, &hello);
+        }
+        let (collector, run) = (String::from(tokens[1]), String::from(tokens[2]));
+        let key = match self.keys.key(&collector) {
+            Some(k) => k.clone(),
+            None    => return self.reject("unknown_collector", &peer, &collector)
+        };
+
+        let mut challenge = [0u8; 16];
+        OsRng.fill_bytes(&mut challenge);
+        let nonce = to_hex(&challenge);
+        writer.write_all(format!("CHALLENGE {}\n", nonce).as_bytes()).map_err(|e| e.to_string())?;
+        let answer = next_line(MAX_CONTROL_LINE)?;
+        if !verify_mac(&key, &proof_input(&nonce, &collector, &run), answer.trim_left_matches("AUTH ").trim()) {
+            let _ = writer.write_all(b"DENIED\n");
+            return self.reject("bad_proof", &peer, &collector);
+        }
+        writer.write_all(b"OK\n").map_err(|e| e.to_string())?;
+        writer.set_read_timeout(Some(Duration::from_secs(3 * KEEPALIVE_SECS))).map_err(|e| e.to_string())?;
+        info!("Collector {} relaying from {}", collector, peer);
+        let session = session_key(&key, &nonce, &collector, &run);
+
+        loop {
+            let line = next_line(MAX_MESSAGE_LINE)?;
+            let line = match verify_line(&session, &line) {
+                Some(l) => l,
+                None    => return self.reject("bad_signature", &peer, &collector)
+            };
+            if line == "PING" {
+                writer.write_all(format!("{}\n", sign_line(&session, "PONG")).as_bytes()).map_err(|e| e.to_string())?;
+                continue;
+            }
+
+            let space = line.find(' ').unwrap_or(line.len());
+            let seq = line[..space].parse::<u64>().map_err(|e| format!("bad sequence number from {}: {}", collector, e))?;
+            if self.is_new(&collector, &run, seq) {
+                match serde_json::from_str::<NetworkMsg>(&line[space..]) {
+                    Ok(mut msg) => {
+                        msg.origin = Some(self.origins.resolve(&collector, msg.origin.take()));
+                        self.metrics.counter_add("sensorweb_relay_messages_total", "Messages received from downstream collectors",
+                                                 &[("origin", msg.origin.as_ref().unwrap().as_str())], 1.0);
+                        tx.send(msg).map_err(|e| e.to_string())?;
+                    },
+                    // Acknowledged anyway, resending would not make it any better
+                    Err(err) => warn!("Dropping unreadable message {} from {}: {}", seq, collector, err)
+                }
+            }
+            writer.write_all(format!("{}\n", sign_line(&session, &format!("ACK {}", seq))).as_bytes()).map_err(|e| e.to_string())?;
+        }
+    }
+}
+
+/// Accepts relay links from downstream collectors on `bind`, one thread per link up to `MAX_LINKS`
+pub fn bind_relay(bind: String, server: RelayServer, tx: SyncSender<NetworkMsg>) {
+    let listener = match TcpListener::bind(&bind) {
+        Ok(l)    => l,
+        Err(err) => {
+            error!("Unable to bind relay listener on {}: {}", bind, err);
+            return;
+        }
+    };
+    info!("Listening for relay links on {}", bind);
+
+    let links = Arc::new(AtomicUsize::new(0));
+    for stream in listener.incoming() {
+        let stream = match stream {
+            Ok(s)    => s,
+            Err(err) => {
+                warn!("Failed to accept relay link: {}", err);
+                continue;
+            }
+        };
+
+        if links.fetch_add(1, Ordering::SeqCst) >= MAX_LINKS {
+            links.fetch_sub(1, Ordering::SeqCst);
+            warn!("Refusing relay link from {:?}, {} already open", stream.peer_addr(), MAX_LINKS);
+            continue;
+        }
+
+        let (server, tx, count) = (server.clone(), tx.clone(), links.clone());
+        let spawned = thread::Builder::new().name("RelayLink".to_string()).spawn(move || {
+            if let Err(err) = server.handle(stream, tx) {
+                warn!("Relay link ended: {}", err);
+            }
+            count.fetch_sub(1, Ordering::SeqCst);
+        });
+        if let Err(err) = spawned {
+            links.fetch_sub(1, Ordering::SeqCst);
+            error!("Unable to spawn relay link thread: {}", err);
+        }
+    }
+}
+
+#[test]
+fn test_relay_buffer() {
+    let mut b = RelayBuffer::new(3);
+    assert!(b.push(String::from("a")));
+    assert!(b.push(String::from("b")));
+    assert!(b.push(String::from("c")));
+    assert!(!b.push(String::from("d")));
+    assert_eq!(b.window(), vec![(2, String::from("b")), (3, String::from("c")), (4, String::from("d"))]);
+
+    b.ack(3);
+    assert_eq!(b.window(), vec![(4, String::from("d"))]);
+    b.ack(10);
+    assert!(b.window().is_empty());
+    assert!(b.push(String::from("e")));
+    assert_eq!(b.window(), vec![(5, String::from("e"))]);
+}
+
+#[test]
+fn test_relay_origins() {
+    let origins = RelayOrigins::parse("# relayed buildings\nbuilding-b = building-c, building-d\nbuilding-e =\n").unwrap();
+    assert_eq!(origins.resolve("building-b", None), "building-b");
+    assert_eq!(origins.resolve("building-b", Some(String::from("building-d"))), "building-d");
+    assert_eq!(origins.resolve("building-b", Some(String::from("building-e"))), "building-b");
+    assert_eq!(origins.resolve("building-e", Some(String::from("building-e"))), "building-e");
+    assert_eq!(origins.resolve("building-e", Some(String::from("building-c"))), "building-e");
+    assert!(RelayOrigins::parse("building-b\n").is_err());
+}
+
+#[test]
+fn test_relay_dedup() {
+    use metrics::Metrics;
+
+    let server = RelayServer::new(AuthKeys::default(), RelayOrigins::default(), Metrics::new());
+    assert!(server.is_new("b1", "100", 1));
+    assert!(server.is_new("b1", "100", 2));
+    assert!(!server.is_new("b1", "100", 2));
+    assert!(server.is_new("b2", "100", 1));
+    // A restarted downstream numbers its messages from 1 again
+    assert!(server.is_new("b1", "200", 1));
+}
+
+#[test]
+fn test_relay_link() {
+    use std::sync::mpsc::channel;
+    use message::{Transport, parse_from_string};
+    use metrics::Metrics;
+
+    let metrics = Metrics::new();
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let config = RelayConfig {
+        upstream:     listener.local_addr().unwrap().to_string(),
+        collector_id: String::from("building-b"),
+        key:          String::from("b-secret"),
+        buffer:       10
+    };
+    let server = RelayServer::new(AuthKeys::parse("building-b = b-secret\n").unwrap(),
+                                  RelayOrigins::parse("building-b = building-c\n").unwrap(), metrics.clone());
+
+    let (tx, rx) = sync_channel(10);
+    let (done_tx, done_rx) = channel();
+    let upstream = thread::spawn(move || {
+        for _ in 0..3 {
+            let stream = listener.accept().unwrap().0;
+            done_tx.send(server.handle(stream, tx.clone())).unwrap();
+        }
+    });
+
+    // Nor is anything read past the length of a handshake line before the peer authenticates
+    let mut flood = TcpStream::connect(&config.upstream).unwrap();
+    flood.write_all(&[b'A'; MAX_CONTROL_LINE + 1]).unwrap();
+    assert!(done_rx.recv().unwrap().unwrap_err().contains("longer than"));
+
+    // A wrong key is refused
+    let mut wrong = config.clone();
+    wrong.key = String::from("guess");
+    assert!(RelayConnection::connect(&wrong, "1").is_err());
+    assert!(done_rx.recv().unwrap().is_err());
+
+    let sink = RelaySink { collector_id: config.collector_id.clone(), tx: sync_channel(1).0 };
+    let mut msg = parse_from_string(String::from("ESP_D427A9: [11.06000] AC:push: Code 200"));
+    msg.transport = Transport::Udp;
+    msg.source = Some(String::from("192.168.1.29:4210"));
+
+    let mut buffer = RelayBuffer::new(10);
+    buffer.push(sink.serialize(&msg).unwrap());
+    buffer.push(sink.serialize(&parse_from_string(String::from("ESP_D427A9: [11.07000] TEMP: 21.5C"))).unwrap());
+    // building-b forwards for building-c, but nobody else
+    for origin in ["building-c", "building-z"].iter() {
+        let mut relayed = parse_from_string(String::from("ESP_1: [11.07000] TEMP: 21.5C"));
+        relayed.origin = Some(String::from(*origin));
+        buffer.push(sink.serialize(&relayed).unwrap());
+    }
+
+    let mut conn = RelayConnection::connect(&config, "1").unwrap();
+    conn.send(&mut buffer).unwrap();
+    assert!(buffer.pending.is_empty());
+    conn.ping().unwrap();
+
+    let relayed = rx.recv().unwrap();
+    assert_eq!(relayed.host, "ESP_D427A9");
+    assert_eq!(relayed.msg.mtype, msg.msg.mtype);
+    assert_eq!(relayed.transport, Transport::Udp);
+    assert_eq!(relayed.source, Some(String::from("192.168.1.29:4210")));
+    assert_eq!(relayed.origin, Some(String::from("building-b")));
+    assert_eq!(rx.recv().unwrap().msg.mvals["temperature"], "21.5");
+    assert_eq!(rx.recv().unwrap().origin, Some(String::from("building-c")));
+    assert_eq!(rx.recv().unwrap().origin, Some(String::from("building-b")));
+
+    // Resent after a lost acknowledgement, the messages are not forwarded twice
+    buffer.push(String::from("{}"));
+    buffer.pending[0].0 = 4;
+    conn.send(&mut buffer).unwrap();
+
+    // Lines not signed with the key of the link end it, keepalives included
+    conn.writer.write_all(b"PING\n").unwrap();
+    assert!(done_rx.recv().unwrap().is_err());
+    drop(conn);
+    upstream.join().unwrap();
+    assert!(rx.try_recv().is_err());
+
+    let rendered = metrics.render();
+    assert!(rendered.contains("sensorweb_relay_rejected_total{reason=\"bad_proof\"} 1\n"));
+    assert!(rendered.contains("sensorweb_relay_rejected_total{reason=\"bad_signature\"} 1\n"));
+    assert!(rendered.contains("sensorweb_relay_messages_total{origin=\"building-b\"} 3\n"));
+    assert!(rendered.contains("sensorweb_relay_messages_total{origin=\"building-c\"} 1\n"));
+}
@@ -0,0 +1,353 @@
+use std::collections::{BTreeMap, HashMap};
+use std::fs::File;
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use message::{MessageType, NetworkMsg, is_uuid};
+use sink::MessageSink;
+
+/// Ended sessions kept in memory before the oldest get forgotten
+const MAX_ENDED_SESSIONS: usize = 1000;
+
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct SessionMeasurement {
+    pub datetime: String,
+    pub pm25:     String,
+    /// Whether the node delivered it to AirCasting, as told by its `sent` flag or the push before it
+    pub uploaded: bool
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct Session {
+    pub uuid:          String,
+    pub host:          String,
+    /// Collector time the session was first seen, seconds since the epoch
+    pub started:       u64,
+    pub ended:         Option<u64>,
+    pub measurements:  Vec<SessionMeasurement>,
+    pub pushes_ok:     u32,
+    pub pushes_failed: u32
+}
+
+/// Session without its measurements, as listed on `/api/sessions`
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct SessionSummary {
+    pub uuid:         String,
+    pub host:         String,
+    pub started:      u64,
+    pub ended:        Option<u64>,
+    pub measurements: usize,
+    pub uploaded:     bool
+}
+
+impl Session {
+    fn new(uuid: &str, host: &str, now: u64) -> Session {
+        Session {
+            uuid:          String::from(uuid),
+            host:          String::from(host),
+            started:       now,
+            ended:         None,
+            measurements:  Vec::new(),
+            pushes_ok:     0,
+            pushes_failed: 0
+        }
+    }
+
+    /// True once every measurement of the session reached AirCasting
+    pub fn uploaded(&self) -> bool {
+        self.measurements.iter().all(|m| m.uploaded)
+    }
+
+    pub fn summary(&self) -> SessionSummary {
+        SessionSummary {
+            uuid:         self.uuid.clone(),
+            host:         self.host.clone(),
+            started:      self.started,
+            ended:        self.ended,
+            measurements: self.measurements.len(),
+            uploaded:     self.uploaded()
+        }
+    }
+
+    pub fn to_csv(&self) -> String {
+        let mut rv = String::from("session,host,datetime,pm2.5,uploaded\n");
+        for m in self.measurements.iter() {
+            rv.push_str(&format!("{},{},{},{},{}\n", csv_escape(&self.uuid), csv_escape(&self.host),
+                                 csv_escape(&m.datetime), csv_escape(&m.pm25), m.uploaded));
+        }
+        rv
+    }
+
+    /// GPX-like track, one point per measurement; nodes are stationary and report no position
+    pub fn to_gpx(&self) -> String {
+        let mut rv = String::from("<?xml version=\"1.0\" encoding=\"UTF-8\"?>\n");
+        rv.push_str("<gpx version=\"1.1\" creator=\"sensorweb-collector\">\n");
+        rv.push_str(&format!("  <trk>\n    <name>{}</name>\n    <src>{}</src>\n    <trkseg>\n",
+                             xml_escape(&self.uuid), xml_escape(&self.host)));
+        for m in self.measurements.iter() {
+            rv.push_str(&format!("      <trkpt><time>{}</time><extensions><pm25>{}</pm25><uploaded>{}</uploaded></extensions></trkpt>\n",
+                                 xml_escape(&m.datetime), xml_escape(&m.pm25), m.uploaded));
+        }
+        rv.push_str("    </trkseg>\n  </trk>\n</gpx>\n");
+        rv
+    }
+}
+
+fn xml_escape(s: &str) -> String {
+    s.replace("&", "&amp;").replace("<", "&lt;").replace(">", "&gt;").replace("\"", "&quot;")
+}
+
+/// Quotes a CSV field as of RFC 4180 when it holds a separator, a quote or a line break
+fn csv_escape(s: &str) -> String {
+    if s.contains(|c| c == ',' || c == '"' || c == '\r' || c == '\n') {
+        format!("\"{}\"", s.replace("\"", "\"\""))
+    } else {
+        String::from(s)
+    }
+}
+
+/// Links SessionUUID, NTP and AC messages of every host into sessions
+#[derive(Debug, Default)]
+pub struct SessionTracker {
+    sessions: BTreeMap<String, Session>,
+    current:  HashMap<String, String>,
+    /// Outcome of the last push of each host, which the firmware logs before the reading it sent
+    pushed:   HashMap<String, bool>
+}
+
+impl SessionTracker {
+    /// Ends the running session of `host`, returning it when there was one
+    fn end(&mut self, host: &str, now: u64) -> Option<Session> {
+        let uuid = match self.current.remove(host) {
+            Some(uuid) => uuid,
+            None       => return None
+        };
+
+        self.sessions.get_mut(&uuid).map(|s| {
+            s.ended = Some(now);
+            s.clone()
+        })
+    }
+
+    /// Makes `uuid` the running session of `host`, returning the session it replaces if any
+    fn start(&mut self, uuid: &str, host: &str, now: u64) -> Option<Session> {
+        if self.current.get(host).map(|u| u == uuid).unwrap_or(false) {
+            return None;
+        }
+
+        let ended = self.end(host, now);
+        self.sessions.entry(String::from(uuid)).or_insert_with(|| Session::new(uuid, host, now));
+        self.current.insert(String::from(host), String::from(uuid));
+        self.forget_oldest();
+        ended
+    }
+
+    fn forget_oldest(&mut self) {
+        let ended = self.sessions.values().filter(|s| s.ended.is_some()).count();
+        if ended <= MAX_ENDED_SESSIONS {
+            return;
+        }
+
+        let mut by_age: Vec<(u64, String)> = self.sessions.values()
+                                                 .filter_map(|s| s.ended.map(|e| (e, s.uuid.clone())))
+                                                 .collect();
+        by_age.sort();
+        for &(_, ref uuid) in by_age.iter().take(ended - MAX_ENDED_SESSIONS) {
+            self.sessions.remove(uuid);
+        }
+    }
+
+    fn current_mut(&mut self, host: &str) -> Option<&mut Session> {
+        match self.current.get(host) {
+            Some(uuid) => self.sessions.get_mut(uuid),
+            None       => None
+        }
+    }
+
+    /// Updates sessions from a message, returning the session it ended if any
+    pub fn observe(&mut self, msg: &NetworkMsg, now: u64) -> Option<Session> {
+        let vals = &msg.msg.mvals;
+        match msg.msg.mtype {
+            MessageType::NodeUp  => self.end(&msg.host, now),
+            MessageType::Session => vals.get("UUID").and_then(|uuid| self.start(uuid, &msg.host, now)),
+            MessageType::Ntp     => {
+                // The collector may have missed the SessionUUID message, the reading still names its session
+                let pushed = self.pushed.remove(&msg.host).unwrap_or(false);
+                let ended = match vals.get("UUID") {
+                    Some(uuid) if is_uuid(uuid) => self.start(uuid, &msg.host, now),
+                    Some(uuid)                  => {
+                        warn!("Ignoring reading of {} for malformed session {:?}", msg.host, uuid);
+                        return None;
+                    },
+                    None                        => None
+                };
+
+                let uploaded = vals.get("sent").map(|s| s.trim() == "1").unwrap_or(pushed);
+                if let (Some(pm25), Some(session)) = (vals.get("pm2.5").cloned(), self.current_mut(&msg.host)) {
+                    session.measurements.push(SessionMeasurement {
+                        datetime: vals.get("datetime").cloned().unwrap_or_default(),
+                        pm25:     pm25,
+                        uploaded: uploaded
+                    });
+                }
+                ended
+            },
+            MessageType::AirCasting => {
+                if vals.get("command").map(|c| c == "push").unwrap_or(false) {
+                    let ok = vals.get("http_code")
+                                 .and_then(|c| c.trim().parse::<i32>().ok())
+                                 .map(|c| c >= 200 && c < 300)
+                                 .unwrap_or(false);
+                    self.pushed.insert(msg.host.clone(), ok);
+                    if let Some(session) = self.current_mut(&msg.host) {
+                        if ok {
+                            session.pushes_ok += 1;
+                        } else {
+                            session.pushes_failed += 1;
+                        }
+                    }
+                }
+                None
+            },
+            _ => None
+        }
+    }
+
+    pub fn summaries(&self) -> Vec<SessionSummary> {
+        self.sessions.values().map(|s| s.summary()).collect()
+    }
+
+    pub fn get(&self, uuid: &str) -> Option<&Session> {
+        self.sessions.get(uuid)
+    }
+}
+
+pub type SharedSessions = Arc<Mutex<SessionTracker>>;
+
+fn now() -> u64 {
+    SystemTime::now().duration_since(UNIX_EPOCH).map(|d| d.as_secs()).unwrap_or(0)
+}
+
+/// Where to export `uuid` with extension `ext`, None unless that lands directly inside `dir`
+fn export_path(dir: &Path, uuid: &str, ext: &str) -> Option<PathBuf> {
+    if !is_uuid(uuid) {
+        return None;
+    }
+    let path = dir.join(format!("{}.{}", uuid, ext));
+    if path.parent() == Some(dir) { Some(path) } else { None }
+}
+
+fn export(dir: &PathBuf, session: &Session) {
+    let files = [("csv", session.to_csv()), ("gpx", session.to_gpx())];
+
+    for &(ext, ref contents) in files.iter() {
+        let path = match export_path(dir, &session.uuid, ext) {
+            Some(p) => p,
+            None    => {
+                error!("Refusing to export session {:?} of {} into {:?}", session.uuid, session.host, dir);
+                return;
+            }
+        };
+        match File::create(&path).and_then(|mut f| f.write_all(contents.as_bytes())) {
+            Ok(_)    => info!("Exported session {} to {:?}", session.uuid, path),
+            Err(err) => error!("Unable to export session {} to {:?}: {}", session.uuid, path, err)
+        }
+    }
+}
+
+/// Sink maintaining sessions, exporting each one to `export_dir` when it ends
+pub struct SessionSink {
+    tracker:    SharedSessions,
+    export_dir: Option<PathBuf>
+}
+
+impl SessionSink {
+    pub fn new(export_dir: Option<PathBuf>) -> SessionSink {
+        SessionSink {
+            tracker:    Arc::new(Mutex::new(SessionTracker::default())),
+            export_dir: export_dir
+        }
+    }
+
+    pub fn tracker(&self) -> SharedSessions {
+        self.tracker.clone()
+    }
+}
+
+impl MessageSink for SessionSink {
+    fn name(&self) -> &str {
+        "session"
+    }
+
+    fn handle(&mut self, msg: &NetworkMsg) {
+        let ended = self.tracker.lock().unwrap().observe(msg, now());
+        if let (Some(session), Some(dir)) = (ended, self.export_dir.as_ref()) {
+            export(dir, &session);
+        }
+    }
+}
+
+#[test]
+fn test_tracker() {
+    use message::parse_from_string;
+
+    let mut t = SessionTracker::default();
+    let msg = |s: &str| parse_from_string(String::from(s));
+
+    assert_eq!(t.observe(&msg("ESP_1: [2.89900] UP: 1.0:May 14 2017 01:34:24@192.168.1.29"), 100), None);
+    let (a, b) = ("d687fe3f-2d30-352d-0c21-ff3f2cea2040", "f0b8e6d2-6a1f-4e5b-9c3d-2f1e0a9b8c7d");
+    assert_eq!(t.observe(&msg(&format!("ESP_1: [8.14600] SessionUUID: {}", a)), 110), None);
+    // Firmware logs the push result before the reading it sent
+    t.observe(&msg("ESP_1: [11.06000] AC:push: Code 200"), 120);
+    t.observe(&msg(&format!("ESP_1: [11.06500] NTP: 2017-05-26T15:27:53.000+01:00 PM2.5: 12 UUID:{} sent:1", a)), 121);
+    t.observe(&msg("ESP_1: [11.06000] AC:push: Code 500"), 420);
+    t.observe(&msg(&format!("ESP_1: [11.06500] NTP: 2017-05-26T15:32:53.000+01:00 PM2.5: 15 UUID:{} sent:0", a)), 421);
+    t.observe(&msg("ESP_1: [11.06000] AC:push: Code 200"), 720);
+    t.observe(&msg(&format!("ESP_1: [11.06500] NTP: 2017-05-26T15:37:53.000+01:00 PM2.5: 17 UUID:{}", a)), 721);
+
+    let s = t.get(a).unwrap().clone();
+    assert_eq!((s.host.as_str(), s.started, s.ended), ("ESP_1", 110, None));
+    assert_eq!(s.measurements.iter().map(|m| (m.pm25.as_str(), m.uploaded)).collect::<Vec<_>>(),
+               vec![("12", true), ("15", false), ("17", true)]);
+    assert_eq!((s.pushes_ok, s.pushes_failed), (2, 1));
+    assert!(!s.uploaded());
+
+    // A reading naming another session ends the previous one
+    let ended = t.observe(&msg(&format!("ESP_1: [11.06500] NTP: 2017-05-26T16:00:00.000+01:00 PM2.5: 9 UUID:{} sent:1", b)), 800).unwrap();
+    assert_eq!((ended.uuid.as_str(), ended.ended), (a, Some(800)));
+    assert_eq!(t.get(b).unwrap().measurements.len(), 1);
+
+    // A reading naming something else than a UUID is left out
+    assert_eq!(t.observe(&msg("ESP_1: [11.06500] NTP: 2017-05-26T16:05:00.000+01:00 PM2.5: 9 UUID:../x sent:1"), 850), None);
+
+    let ended = t.observe(&msg("ESP_1: [2.89900] UP: 1.0:May 14 2017 01:34:24@192.168.1.29"), 900).unwrap();
+    assert_eq!(ended.uuid, b);
+    assert!(ended.uploaded());
+
+    assert_eq!(t.summaries().len(), 2);
+    assert_eq!(t.summaries()[1].measurements, 1);
+}
+
+#[test]
+fn test_export_formats() {
+    let mut s = Session::new("d687fe3f-2d30-352d-0c21-ff3f2cea2040", "ESP_1", 0);
+    s.measurements.push(SessionMeasurement {
+        datetime: String::from("2017-05-26T15:27:53.000+01:00"),
+        pm25:     String::from("12"),
+        uploaded: true
+    });
+
+    assert_eq!(s.to_csv(), "session,host,datetime,pm2.5,uploaded\n\
+                            d687fe3f-2d30-352d-0c21-ff3f2cea2040,ESP_1,2017-05-26T15:27:53.000+01:00,12,true\n");
+    assert_eq!(csv_escape("a,\"b\"\nc"), "\"a,\"\"b\"\"\nc\"");
+    assert!(s.to_gpx().contains("<trkpt><time>2017-05-26T15:27:53.000+01:00</time><extensions><pm25>12</pm25><uploaded>true</uploaded></extensions></trkpt>"));
+    assert_eq!(xml_escape("<a&b>"), "&lt;a&amp;b&gt;");
+
+    let dir = Path::new("/var/lib/sessions");
+    assert_eq!(export_path(dir, &s.uuid, "csv"),
+               Some(PathBuf::from("/var/lib/sessions/d687fe3f-2d30-352d-0c21-ff3f2cea2040.csv")));
+    assert_eq!(export_path(dir, "../../etc/cron.d/x", "csv"), None);
+    assert_eq!(export_path(dir, "/etc/x", "csv"), None);
+}
@@ -0,0 +1,13 @@
+use message::NetworkMsg;
+
+/// Consumer of parsed messages, driven by the message manager thread
+pub trait MessageSink: Send {
+    fn name(&self) -> &str;
+    fn handle(&mut self, msg: &NetworkMsg);
+}
+
+/// Stage run on every message before the sinks, allowed to annotate it
+pub trait MessageEnricher: Send {
+    fn name(&self) -> &str;
+    fn enrich(&mut self, msg: &mut NetworkMsg);
+}
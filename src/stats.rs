@@ -0,0 +1,264 @@
+use std::collections::{BTreeMap, HashMap, VecDeque};
+use std::sync::{Arc, Mutex};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use message::{MessageType, NetworkMsg};
+use metrics::SharedMetrics;
+use sink::MessageSink;
+
+const HOUR: u64 = 3600;
+const DAY:  u64 = 24 * HOUR;
+
+/// Windows statistics are kept over, with the label they are reported under
+const WINDOWS: [(&'static str, u64); 3] = [("1h", HOUR), ("24h", DAY), ("7d", 7 * DAY)];
+
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct Summary {
+    pub count:  usize,
+    pub min:    f64,
+    pub max:    f64,
+    pub mean:   f64,
+    pub median: f64,
+    pub p95:    f64
+}
+
+/// Sliding window keeping its values sorted, so that each reading costs one insertion
+/// and one removal instead of a full sort
+#[derive(Debug)]
+struct Window {
+    span:     u64,
+    readings: VecDeque<(u64, f64)>,
+    sorted:   Vec<f64>,
+    sum:      f64
+}
+
+fn position(sorted: &[f64], value: f64) -> usize {
+    match sorted.binary_search_by(|v| v.partial_cmp(&value).unwrap()) {
+        Ok(idx) | Err(idx) => idx
+    }
+}
+
+impl Window {
+    fn new(span: u64) -> Window {
+        Window {
+            span:     span,
+            readings: VecDeque::new(),
+            sorted:   Vec::new(),
+            sum:      0.0
+        }
+    }
+
+    fn push(&mut self, ts: u64, value: f64) {
+        self.readings.push_back((ts, value));
+        let idx = position(&self.sorted, value);
+        self.sorted.insert(idx, value);
+        self.sum += value;
+        self.evict(ts);
+    }
+
+    fn evict(&mut self, now: u64) {
+        while let Some(&(ts, value)) = self.readings.front() {
+            if ts + self.span > now {
+                break;
+            }
+
+            self.readings.pop_front();
+            let idx = position(&self.sorted, value);
+            self.sorted.remove(idx);
+            self.sum -= value;
+        }
+
+        if self.sorted.is_empty() {
+            // Do not let floating point residue from evictions accumulate
+            self.sum = 0.0;
+        }
+    }
+
+    fn summary(&self) -> Option<Summary> {
+        let n = self.sorted.len();
+        if n == 0 {
+            return None;
+        }
+
+        let median = if n % 2 == 1 {
+            self.sorted[n / 2]
+        } else {
+            (self.sorted[n / 2 - 1] + self.sorted[n / 2]) / 2.0
+        };
+
+        // Nearest-rank percentile
+        let rank = ((0.95 * n as f64).ceil() as usize).max(1);
+
+        Some(Summary {
+            count:  n,
+            min:    self.sorted[0],
+            max:    self.sorted[n - 1],
+            mean:   self.sum / n as f64,
+            median: median,
+            p95:    self.sorted[rank - 1]
+        })
+    }
+}
+
+#[derive(Debug)]
+struct NodeStats {
+    windows: Vec<(&'static str, Window)>
+}
+
+impl NodeStats {
+    fn new() -> NodeStats {
+        NodeStats {
+            windows: WINDOWS.iter().map(|&(label, span)| (label, Window::new(span))).collect()
+        }
+    }
+
+    fn push(&mut self, ts: u64, value: f64) {
+        for w in self.windows.iter_mut() {
+            w.1.push(ts, value);
+        }
+    }
+
+    fn summaries(&self) -> BTreeMap<String, Summary> {
+        self.windows.iter()
+                    .filter_map(|&(label, ref w)| w.summary().map(|s| (String::from(label), s)))
+                    .collect()
+    }
+}
+
+/// Per host, per window PM2.5 statistics, shared with the HTTP API
+pub type SharedStats = Arc<Mutex<BTreeMap<String, BTreeMap<String, Summary>>>>;
+
+/// Sink keeping rolling PM2.5 statistics for every node
+pub struct StatsSink {
+    nodes:   HashMap<String, NodeStats>,
+    latest:  SharedStats,
+    metrics: SharedMetrics
+}
+
+impl StatsSink {
+    pub fn new(metrics: SharedMetrics) -> StatsSink {
+        StatsSink {
+            nodes:   HashMap::new(),
+            latest:  Arc::new(Mutex::new(BTreeMap::new())),
+            metrics: metrics
+        }
+    }
+
+    pub fn latest(&self) -> SharedStats {
+        self.latest.clone()
+    }
+
+    fn add(&mut self, host: &str, ts: u64, value: f64) {
+        let node = self.nodes.entry(String::from(host)).or_insert_with(NodeStats::new);
+        node.push(ts, value);
+
+        let summaries = node.summaries();
+        for (window, s) in summaries.iter() {
+            let stats = [("min", s.min), ("max", s.max), ("mean", s.mean), ("median", s.median), ("p95", s.p95)];
+            for &(stat, value) in stats.iter() {
+                self.metrics.gauge("sensorweb_pm25_stat", "Rolling PM2.5 statistics in µg/m³",
+                                   &[("host", host), ("window", window.as_str()), ("stat", stat)], value);
+            }
+        }
+
+        self.latest.lock().unwrap().insert(String::from(host), summaries);
+    }
+}
+
+impl MessageSink for StatsSink {
+    fn name(&self) -> &str {
+        "stats"
+    }
+
+    fn handle(&mut self, msg: &NetworkMsg) {
+        if msg.msg.mtype != MessageType::Ntp {
+            return;
+        }
+
+        // "NaN" parses too, and could not be sorted into the window
+        if let Some(value) = msg.msg.mvals.get("pm2.5").and_then(|v| v.parse::<f64>().ok()).filter(|v| v.is_finite()) {
+            let now = SystemTime::now().duration_since(UNIX_EPOCH).map(|d| d.as_secs()).unwrap_or(0);
+            self.add(&msg.host, now, value);
+        }
+    }
+}
+
+#[test]
+fn test_window_summary() {
+    let mut w = Window::new(HOUR);
+    assert_eq!(w.summary(), None);
+
+    for (i, v) in [5.0, 1.0, 4.0, 2.0, 3.0].iter().enumerate() {
+        w.push(1000 + i as u64, *v);
+    }
+    assert_eq!(w.summary(), Some(Summary {
+        count:  5,
+        min:    1.0,
+        max:    5.0,
+        mean:   3.0,
+        median: 3.0,
+        p95:    5.0
+    }));
+
+    w.push(1005, 6.0);
+    let s = w.summary().unwrap();
+    assert_eq!(s.median, 3.5);
+    assert_eq!(s.p95, 6.0);
+}
+
+#[test]
+fn test_window_eviction() {
+    let mut w = Window::new(HOUR);
+    w.push(0, 100.0);
+    w.push(HOUR / 2, 10.0);
+    w.push(HOUR, 20.0);
+
+    // The first reading is exactly one hour old and leaves the window
+    let s = w.summary().unwrap();
+    assert_eq!((s.count, s.min, s.max, s.mean), (2, 10.0, 20.0, 15.0));
+
+    w.evict(3 * HOUR);
+    assert_eq!(w.summary(), None);
+    assert_eq!(w.sum, 0.0);
+}
+
+#[test]
+fn test_p95() {
+    let mut w = Window::new(DAY);
+    for i in 0..100 {
+        w.push(i, (100 - i) as f64);
+    }
+    let s = w.summary().unwrap();
+    assert_eq!(s.p95, 95.0);
+    assert_eq!(s.median, 50.5);
+}
+
+#[test]
+fn test_stats_sink() {
+    use metrics::Metrics;
+
+    let metrics = Metrics::new();
+    let mut sink = StatsSink::new(metrics.clone());
+    sink.add("ESP_1", 10 * DAY, 12.0);
+    sink.add("ESP_1", 10 * DAY + 2 * HOUR, 20.0);
+
+    let latest = sink.latest();
+    let latest = latest.lock().unwrap();
+    assert_eq!(latest["ESP_1"]["1h"].count, 1);
+    assert_eq!(latest["ESP_1"]["24h"].mean, 16.0);
+    assert_eq!(latest["ESP_1"]["7d"].max, 20.0);
+    assert!(metrics.render().contains("sensorweb_pm25_stat{host=\"ESP_1\",window=\"24h\",stat=\"mean\"} 16\n"));
+}
+
+#[test]
+fn test_stats_sink_ignores_nan() {
+    use message::parse_from_string;
+    use metrics::Metrics;
+
+    let mut sink = StatsSink::new(Metrics::new());
+    sink.handle(&parse_from_string(String::from("ESP_1: [11.06500] NTP: 2017-05-26T15:27:53.000+01:00 PM2.5: 12 UUID:abc sent:1")));
+    sink.handle(&parse_from_string(String::from("ESP_1: [11.06500] NTP: 2017-05-26T15:32:53.000+01:00 PM2.5: NaN UUID:abc sent:1")));
+    sink.handle(&parse_from_string(String::from("ESP_1: [11.06500] NTP: 2017-05-26T15:37:53.000+01:00 PM2.5: inf UUID:abc sent:1")));
+
+    assert_eq!(sink.latest().lock().unwrap()["ESP_1"]["1h"].count, 1);
+}
@@ -0,0 +1,264 @@
+use std::collections::{BTreeMap, HashMap, VecDeque};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use message::NetworkMsg;
+use sink::MessageSink;
+
+const HOUR: u64 = 3600;
+const DAY:  u64 = 24 * HOUR;
+
+/// How often the downsampler thread runs
+const DOWNSAMPLE_INTERVAL: u64 = 600;
+
+/// Most (host, quantity) series kept, hosts being whatever senders claim
+const MAX_SERIES: usize = 4096;
+
+/// How long, in seconds, each resolution is kept
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Retention {
+    pub raw:    u64,
+    pub hourly: u64,
+    pub daily:  u64
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct Aggregate {
+    pub start: u64,
+    pub count: usize,
+    pub min:   f64,
+    pub max:   f64,
+    pub mean:  f64,
+    #[serde(skip_serializing)]
+    sum:       f64
+}
+
+impl Aggregate {
+    fn new(start: u64) -> Aggregate {
+        Aggregate {
+            start: start,
+            count: 0,
+            min:   ::std::f64::INFINITY,
+            max:   ::std::f64::NEG_INFINITY,
+            mean:  0.0,
+            sum:   0.0
+        }
+    }
+
+    fn add(&mut self, value: f64) {
+        self.count += 1;
+        self.sum += value;
+        self.min = self.min.min(value);
+        self.max = self.max.max(value);
+        self.mean = self.sum / self.count as f64;
+    }
+
+    fn merge(&mut self, other: &Aggregate) {
+        self.count += other.count;
+        self.sum += other.sum;
+        self.min = self.min.min(other.min);
+        self.max = self.max.max(other.max);
+        self.mean = self.sum / self.count as f64;
+    }
+}
+
+#[derive(Debug, Default)]
+struct Series {
+    raw:    VecDeque<(u64, f64)>,
+    hourly: BTreeMap<u64, Aggregate>,
+    daily:  BTreeMap<u64, Aggregate>
+}
+
+/// Readings of one node at every resolution, as served on `/api/readings/<host>[/<quantity>]`
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct Readings {
+    pub raw:    Vec<(u64, f64)>,
+    pub hourly: Vec<Aggregate>,
+    pub daily:  Vec<Aggregate>
+}
+
+/// Moves `BTreeMap` entries older than `cutoff` out of `from` and into `to`, bucketed by `span`
+fn roll_up(from: &mut BTreeMap<u64, Aggregate>, to: &mut BTreeMap<u64, Aggregate>, cutoff: u64, span: u64) {
+    let kept = from.split_off(&cutoff);
+    for (_, agg) in ::std::mem::replace(from, kept) {
+        let start = agg.start - agg.start % span;
+        to.entry(start).or_insert_with(|| Aggregate::new(start)).merge(&agg);
+    }
+}
+
+/// PM2.5 and environmental readings, downsampled to hourly then daily aggregates as they age
+///
+/// Everything lives in memory only and is lost on restart; at most `MAX_SERIES` series are kept,
+/// readings for new series being dropped until downsampling expires old ones.
+#[derive(Debug)]
+pub struct ReadingStore {
+    retention: Retention,
+    /// Keyed by host then quantity, e.g. `pm2.5` or `temperature`
+    series:    HashMap<(String, String), Series>
+}
+
+impl ReadingStore {
+    pub fn new(retention: Retention) -> ReadingStore {
+        ReadingStore {
+            retention: retention,
+            series:    HashMap::new()
+        }
+    }
+
+    pub fn add(&mut self, host: &str, quantity: &str, ts: u64, value: f64) {
+        if !value.is_finite() {
+            return;
+        }
+        let key = (String::from(host), String::from(quantity));
+        if !self.series.contains_key(&key) && self.series.len() >= MAX_SERIES {
+            warn!("Reading store full, dropping {} reading of {}", quantity, host);
+            return;
+        }
+        self.series.entry(key).or_insert_with(Series::default).raw.push_back((ts, value));
+    }
+
+    pub fn readings(&self, host: &str, quantity: &str) -> Option<Readings> {
+        self.series.get(&(String::from(host), String::from(quantity))).map(|s| Readings {
+            raw:    s.raw.iter().cloned().collect(),
+            hourly: s.hourly.values().cloned().collect(),
+            daily:  s.daily.values().cloned().collect()
+        })
+    }
+
+    /// Folds data past its retention into the next coarser resolution, dropping expired daily aggregates
+    pub fn downsample(&mut self, now: u64) {
+        let raw_cutoff = now.saturating_sub(self.retention.raw);
+        let hourly_cutoff = now.saturating_sub(self.retention.hourly);
+        let daily_cutoff = now.saturating_sub(self.retention.daily);
+
+        for series in self.series.values_mut() {
+            while let Some(&(ts, value)) = series.raw.front() {
+                if ts >= raw_cutoff {
+                    break;
+                }
+
+                series.raw.pop_front();
+                let start = ts - ts % HOUR;
+                series.hourly.entry(start).or_insert_with(|| Aggregate::new(start)).add(value);
+            }
+
+            roll_up(&mut series.hourly, &mut series.daily, hourly_cutoff, DAY);
+
+            series.daily = series.daily.split_off(&daily_cutoff);
+        }
+
+        self.series.retain(|_, s| !s.raw.is_empty() || !s.hourly.is_empty() || !s.daily.is_empty());
+    }
+}
+
+pub type SharedStore = Arc<Mutex<ReadingStore>>;
+
+fn now() -> u64 {
+    SystemTime::now().duration_since(UNIX_EPOCH).map(|d| d.as_secs()).unwrap_or(0)
+}
+
+pub fn th_store_downsampler(store: SharedStore) {
+    loop {
+        thread::sleep(Duration::from_secs(DOWNSAMPLE_INTERVAL));
+        debug!("Downsampling stored readings");
+        store.lock().unwrap().downsample(now());
+    }
+}
+
+/// Sink recording every PM2.5 and environmental reading into the store
+pub struct StoreSink {
+    store: SharedStore
+}
+
+impl StoreSink {
+    pub fn new(retention: Retention) -> StoreSink {
+        StoreSink {
+            store: Arc::new(Mutex::new(ReadingStore::new(retention)))
+        }
+    }
+
+    pub fn store(&self) -> SharedStore {
+        self.store.clone()
+    }
+}
+
+impl MessageSink for StoreSink {
+    fn name(&self) -> &str {
+        "store"
+    }
+
+    fn handle(&mut self, msg: &NetworkMsg) {
+        let quantity = match msg.msg.mtype.measurement() {
+            Some(q) => q,
+            None    => return
+        };
+
+        if let Some(value) = msg.msg.mvals.get(quantity).and_then(|v| v.parse::<f64>().ok()) {
+            self.store.lock().unwrap().add(&msg.host, quantity, now(), value);
+        }
+    }
+}
+
+#[test]
+fn test_aggregate() {
+    let mut a = Aggregate::new(0);
+    a.add(2.0);
+    a.add(4.0);
+    let mut b = Aggregate::new(HOUR);
+    b.add(9.0);
+    a.merge(&b);
+    assert_eq!((a.count, a.min, a.max, a.mean), (3, 2.0, 9.0, 5.0));
+}
+
+#[test]
+fn test_downsample() {
+    let mut store = ReadingStore::new(Retention { raw: 2 * HOUR, hourly: 2 * DAY, daily: 10 * DAY });
+    let base = 100 * DAY;
+
+    store.add("ESP_1", "pm2.5", base + 10, 10.0);
+    store.add("ESP_1", "pm2.5", base + 20, 20.0);
+    store.add("ESP_1", "pm2.5", base + HOUR + 10, 30.0);
+    store.add("ESP_1", "pm2.5", base + 3 * HOUR, 40.0);
+
+    store.add("ESP_1", "temperature", base + 3 * HOUR, 21.5);
+
+    store.downsample(base + 4 * HOUR);
+    assert_eq!(store.readings("ESP_1", "temperature").unwrap().raw, vec![(base + 3 * HOUR, 21.5)]);
+    let r = store.readings("ESP_1", "pm2.5").unwrap();
+    assert_eq!(r.raw, vec![(base + 3 * HOUR, 40.0)]);
+    assert_eq!(r.hourly.len(), 2);
+    assert_eq!((r.hourly[0].start, r.hourly[0].count, r.hourly[0].mean), (base, 2, 15.0));
+    assert_eq!((r.hourly[1].start, r.hourly[1].count, r.hourly[1].mean), (base + HOUR, 1, 30.0));
+    assert!(r.daily.is_empty());
+
+    // Three days later, hourly aggregates are folded into their day
+    store.downsample(base + 3 * DAY);
+    let r = store.readings("ESP_1", "pm2.5").unwrap();
+    assert!(r.raw.is_empty());
+    assert!(r.hourly.is_empty());
+    assert_eq!(r.daily.len(), 1);
+    assert_eq!((r.daily[0].start, r.daily[0].count, r.daily[0].min, r.daily[0].max, r.daily[0].mean),
+               (base, 4, 10.0, 40.0, 25.0));
+
+    // Past daily retention nothing is left, not even the host
+    store.downsample(base + 20 * DAY);
+    assert_eq!(store.readings("ESP_1", "pm2.5"), None);
+}
+
+#[test]
+fn test_series_cap() {
+    let mut store = ReadingStore::new(Retention { raw: HOUR, hourly: DAY, daily: DAY });
+    for i in 0..MAX_SERIES {
+        store.add(&format!("ESP_{}", i), "pm2.5", 10, 1.0);
+    }
+    store.add("ESP_new", "pm2.5", 10, 1.0);
+    assert_eq!(store.readings("ESP_new", "pm2.5"), None);
+
+    store.add("ESP_0", "pm2.5", 20, 2.0);
+    assert_eq!(store.readings("ESP_0", "pm2.5").unwrap().raw, vec![(10, 1.0), (20, 2.0)]);
+
+    store.downsample(10 * DAY);
+    store.add("ESP_new", "pm2.5", 10 * DAY, 1.0);
+    assert!(store.readings("ESP_new", "pm2.5").is_some());
+}
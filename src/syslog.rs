@@ -0,0 +1,250 @@
+use std::collections::HashMap;
+
+use message::{NetworkMsg, ParserRegistry, Transport, parse_msg_content, parse_with};
+
+const MONTHS: [&'static str; 12] = ["Jan", "Feb", "Mar", "Apr", "May", "Jun", "Jul", "Aug", "Sep", "Oct", "Nov", "Dec"];
+
+/// What is left of a syslog message once its RFC 3164 or RFC 5424 framing is stripped
+#[derive(Debug, Clone, PartialEq)]
+pub struct SyslogFrame {
+    pub priority: u8,
+    /// Sending host, None when the header omits it or gives the `-` nil value
+    pub hostname: Option<String>,
+    /// TAG of RFC 3164, APP-NAME of RFC 5424
+    pub app_name: Option<String>,
+    pub body:     String
+}
+
+fn split_token(s: &str) -> (&str, &str) {
+    match s.find(' ') {
+        Some(idx) => (&s[..idx], &s[idx + 1..]),
+        None      => (s, "")
+    }
+}
+
+fn nil(s: &str) -> Option<String> {
+    if s.is_empty() || s == "-" {
+        None
+    } else {
+        Some(String::from(s))
+    }
+}
+
+/// `Mmm dd hh:mm:ss`, the day being padded with a space
+fn is_bsd_timestamp(s: &str) -> bool {
+    let b = s.as_bytes();
+    b.len() >= 15 && s.is_char_boundary(15) && MONTHS.iter().any(|m| s.starts_with(m))
+        && b[3] == b' ' && b[6] == b' ' && b[9] == b':' && b[12] == b':'
+}
+
+/// Skips RFC 5424 structured data, either `-` or a run of `[id param="value"]` elements
+fn skip_structured_data(s: &str) -> Option<&str> {
+    if s.starts_with('-') {
+        return Some(&s[1..]);
+    }
+    if !s.starts_with('[') {
+        return None;
+    }
+
+    let (mut in_element, mut in_quotes, mut escaped) = (false, false, false);
+    for (idx, c) in s.char_indices() {
+        if escaped {
+            escaped = false;
+            continue;
+        }
+        match c {
+            '\\' if in_quotes                => escaped = true,
+            '"' if in_element                => in_quotes = !in_quotes,
+            '[' if !in_element               => in_element = true,
+            ']' if in_element && !in_quotes  => in_element = false,
+            _ if !in_element                 => return Some(&s[idx..]),
+            _                                => {}
+        }
+    }
+
+    if in_element { None } else { Some("") }
+}
+
+/// Strips an RFC 3164 `TAG:` or `TAG[pid]:` off `body`. A bare tag reading like an identifier the
+/// registry parses, e.g. `Loop:`, is taken for the start of the message instead, and so is one not
+/// followed by an uptime or another identifier.
+fn strip_tag<'a>(registry: &ParserRegistry, body: &'a str) -> (Option<String>, &'a str) {
+    let (token, rest) = split_token(body);
+    if !token.ends_with(':') {
+        return (None, body);
+    }
+
+    let tag = &token[..token.len() - 1];
+    let (name, with_pid) = match tag.find('[') {
+        Some(idx) if tag.ends_with(']') => (&tag[..idx], true),
+        _                               => (tag, false)
+    };
+    if name.is_empty() || !name.chars().all(|c| c.is_alphanumeric() || c == '_' || c == '-' || c == '.' || c == '/') {
+        return (None, body);
+    }
+
+    let (next, _) = split_token(rest.trim_left());
+    if with_pid || (!registry.handles(name) && (next.starts_with('[') || next.ends_with(':'))) {
+        (Some(String::from(name)), rest.trim_left())
+    } else {
+        (None, body)
+    }
+}
+
+/// Strips the syslog framing off `s`, None when it does not start with a `<PRI>`
+pub fn strip_framing(registry: &ParserRegistry, s: &str) -> Option<SyslogFrame> {
+    if !s.starts_with('<') {
+        return None;
+    }
+    let close = s.find('>')?;
+    let priority = match s[1..close].parse::<u8>() {
+        Ok(p) if p <= 191 => p,
+        _                 => return None
+    };
+    let rest = &s[close + 1..];
+
+    if rest.starts_with("1 ") {
+        // RFC 5424: VERSION TIMESTAMP HOSTNAME APP-NAME PROCID MSGID STRUCTURED-DATA [MSG]
+        let (_, rest) = split_token(&rest[2..]);
+        let (hostname, rest) = split_token(rest);
+        let (app_name, rest) = split_token(rest);
+        let (_, rest) = split_token(rest);
+        let (_, rest) = split_token(rest);
+        let body = skip_structured_data(rest)?.trim_left_matches(' ').trim_left_matches('\u{feff}');
+        // Firmware logging its usual lines puts its name in front of them, more telling than APP-NAME
+        let (tag, body) = strip_tag(registry, body);
+
+        Some(SyslogFrame {
+            priority: priority,
+            hostname: nil(hostname),
+            app_name: tag.or(nil(app_name)),
+            body:     String::from(body.trim())
+        })
+    } else {
+        // RFC 3164: [TIMESTAMP HOSTNAME] [TAG:] MSG, small senders often leaving out the header
+        let rest = if is_bsd_timestamp(rest) { rest[15..].trim_left() } else { rest };
+        let (first, after) = split_token(rest);
+        let (hostname, rest) = if first.ends_with(':') || first.contains('[') {
+            (None, rest)
+        } else {
+            (nil(first), after)
+        };
+        let (app_name, body) = strip_tag(registry, rest);
+
+        Some(SyslogFrame {
+            priority: priority,
+            hostname: hostname,
+            app_name: app_name,
+            body:     String::from(body.trim())
+        })
+    }
+}
+
+/// Reads the `[uptime]` the firmware puts before its messages, -1 when there is none
+fn split_uptime(body: &str) -> (f32, &str) {
+    if body.starts_with('[') {
+        if let Some(close) = body.find(']') {
+            if let Ok(time) = body[1..close].parse::<f32>() {
+                return (time, body[close + 1..].trim_left());
+            }
+        }
+    }
+    (-1.0, body)
+}
+
+/// Parses a syslog message, its host defaulting to `source` when the header does not name one and
+/// no `HOST: [uptime]` firmware prefix stands in for it. Messages without framing are parsed as plain lines.
+pub fn parse_syslog(registry: &ParserRegistry, s: &str, source: &str) -> NetworkMsg {
+    let frame = match strip_framing(registry, s) {
+        Some(f) => f,
+        None    => {
+            debug!("no syslog framing in {:?}", s);
+            let mut rv = parse_with(registry, String::from(s));
+            rv.transport = Transport::Syslog;
+            return rv;
+        }
+    };
+    debug!("syslog frame: {:?}", frame);
+
+    let (time, body) = split_uptime(&frame.body);
+    // Firmware logging without a header starts its lines with its name, read as the tag
+    let host = match (frame.hostname, frame.app_name) {
+        (Some(hostname), _)              => hostname,
+        (None, Some(tag)) if time >= 0.0 => tag,
+        _                                => String::from(source)
+    };
+    NetworkMsg {
+        host: host,
+        time: time,
+        msg:  parse_msg_content(registry, body),
+        annotations: HashMap::new(),
+        raw:  String::from(s),
+        transport: Transport::Syslog,
+        source: None,
+        origin: None
+    }
+}
+
+#[test]
+fn test_strip_framing() {
+    let r = ParserRegistry::builtin();
+
+    let f = strip_framing(&r, "<134>1 2017-05-26T14:27:53.000Z ESP_D427A9 sensorweb 42 - \
+                               [meta sequenceId=\"7\" note=\"a \\\"]\\\" b\"][origin ip=\"192.168.1.29\"] \u{feff}NTP: 2017-05-26T15:27:53.000+01:00").unwrap();
+    assert_eq!(f, SyslogFrame {
+        priority: 134,
+        hostname: Some(String::from("ESP_D427A9")),
+        app_name: Some(String::from("sensorweb")),
+        body:     String::from("NTP: 2017-05-26T15:27:53.000+01:00")
+    });
+    assert_eq!(strip_framing(&r, "<134>1 - - - - - - UP: 1.0").unwrap().body, "UP: 1.0");
+    assert!(strip_framing(&r, "<134>1 - host app - - [unterminated UP: 1.0").is_none());
+
+    let f = strip_framing(&r, "<14>May 26 14:27:53 ESP_D427A9 sensorweb[42]: Loop: deepSleep: nextInterval=288.93").unwrap();
+    assert_eq!(f.hostname, Some(String::from("ESP_D427A9")));
+    assert_eq!(f.app_name, Some(String::from("sensorweb")));
+    assert_eq!(f.body, "Loop: deepSleep: nextInterval=288.93");
+
+    // A bare identifier is not a tag, unlike a name followed by one
+    let f = strip_framing(&r, "<14>May  6 04:07:53 ESP_D427A9 Loop: deepSleep: nextInterval=288.93").unwrap();
+    assert_eq!((f.app_name, f.body.as_str()), (None, "Loop: deepSleep: nextInterval=288.93"));
+    let f = strip_framing(&r, "<14>ESP_D427A9 sensorweb: AC:push: Code 200").unwrap();
+    assert_eq!((f.app_name, f.body.as_str()), (Some(String::from("sensorweb")), "AC:push: Code 200"));
+    let f = strip_framing(&r, "<14>sensorweb: BATT: 3.71V").unwrap();
+    assert_eq!((f.hostname, f.body.as_str()), (None, "BATT: 3.71V"));
+
+    assert!(strip_framing(&r, "ESP_D427A9: [11.06000] AC:push: Code 200").is_none());
+    assert!(strip_framing(&r, "<999>1 - - - - - - x").is_none());
+}
+
+#[test]
+fn test_parse_syslog() {
+    use message::MessageType;
+
+    let r = ParserRegistry::builtin();
+
+    let n = parse_syslog(&r, "<14>May 26 14:27:53 ESP_D427A9 sensorweb: [11.06500] NTP: 2017-05-26T15:27:53.000+01:00 PM2.5: 12", "192.168.1.29");
+    assert_eq!(n.host, "ESP_D427A9");
+    assert_eq!(n.time, 11.065);
+    assert_eq!(n.msg.mtype, MessageType::Ntp);
+    assert_eq!(n.msg.mvals["pm2.5"], "12");
+    assert_eq!(n.transport, Transport::Syslog);
+    assert!(n.raw.starts_with("<14>May 26"));
+
+    // Firmware forwarding its usual lines repeats its name, which reads as the tag
+    let n = parse_syslog(&r, "<14>May 26 14:27:53 ESP_D427A9 ESP_D427A9: [11.06000] AC:push: Code 200", "192.168.1.29");
+    assert_eq!((n.host.as_str(), n.time, n.msg.mtype), ("ESP_D427A9", 11.06, MessageType::AirCasting));
+
+    let n = parse_syslog(&r, "<134>1 2017-05-26T14:27:53Z - sensorweb - - - SessionUUID: d687fe3f-2d30-352d-0c21-ff3f2cea2040", "192.168.1.29");
+    assert_eq!((n.host.as_str(), n.time, n.msg.mtype), ("192.168.1.29", -1.0, MessageType::Session));
+    let n = parse_syslog(&r, "<134>1 2017-05-26T14:27:53Z - sensorweb - - - ESP_D427A9: [11.06] AC:push: Code 200", "192.168.1.29");
+    assert_eq!((n.host.as_str(), n.time, n.msg.mtype), ("ESP_D427A9", 11.06, MessageType::AirCasting));
+
+    let n = parse_syslog(&r, "<14>ESP_D427A9: [11.06] AC:push: Code 200", "192.168.1.29");
+    assert_eq!((n.host.as_str(), n.time, n.msg.mtype), ("ESP_D427A9", 11.06, MessageType::AirCasting));
+    let n = parse_syslog(&r, "<14>sensorweb: BATT: 3.71V", "192.168.1.29");
+    assert_eq!(n.host, "192.168.1.29");
+
+    let n = parse_syslog(&r, "ESP_D427A9: [11.06000] AC:push: Code 200", "192.168.1.29");
+    assert_eq!((n.host.as_str(), n.msg.mtype, n.transport), ("ESP_D427A9", MessageType::AirCasting, Transport::Syslog));
+}
@@ -0,0 +1,166 @@
+use std::io;
+use std::io::{BufRead, BufReader, Read};
+use std::net::{TcpListener, TcpStream, UdpSocket};
+use std::sync::Arc;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::mpsc::SyncSender;
+use std::thread;
+use std::time::Duration;
+
+use capture::{SharedCapture, capture};
+use ingest::{SharedIngest, forward};
+use mcast::read_to_string;
+use message::{NetworkMsg, Transport};
+
+/// Longest line accepted, as long as the datagrams nodes send
+pub const MAX_LINE: usize = 1024;
+
+/// Connections silent for this long are closed, nodes sending a few lines every wake
+const READ_TIMEOUT_SECS: u64 = 600;
+
+/// TCP connections served at once, later ones being refused until some close
+const MAX_CONNECTIONS: usize = 64;
+
+/// Listens for unicast datagrams on `bind`, for sites whose access points do not forward multicast,
+/// or for nodes logging over syslog
+pub fn bind_udp(bind: String, transport: Transport, tx: SyncSender<NetworkMsg>, ingest: SharedIngest, writer: Option<SharedCapture>) {
+    let socket = match UdpSocket::bind(&bind) {
+        Ok(s)    => s,
+        Err(err) => {
+            error!("Unable to bind UDP listener on {}: {}", bind, err);
+            return;
+        }
+    };
+    info!("Listening for {} datagrams on {}", transport.name(), bind);
+
+    loop {
+        if let Some((s, src)) = read_to_string(&socket, transport, &writer) {
+            forward(&ingest, &s, &src, transport, &tx);
+        }
+    }
+}
+
+/// Reads a line of at most `max` bytes without its line ending, None at the end of the stream
+pub fn read_line<R: BufRead>(reader: &mut R, max: usize) -> io::Result<Option<String>> {
+    let mut buf = Vec::new();
+    if reader.by_ref().take(max as u64 + 1).read_until(b'\n', &mut buf)? == 0 {
+        return Ok(None);
+    }
+    if buf.last() == Some(&b'\n') {
+        buf.pop();
+    } else if buf.len() > max {
+        return Err(io::Error::new(io::ErrorKind::InvalidData, format!("line longer than {} bytes", max)));
+    }
+    Ok(Some(String::from_utf8_lossy(&buf).into_owned()))
+}
+
+/// Reads one message per line until the node disconnects, goes quiet or sends an overlong line
+fn handle_tcp(stream: TcpStream, tx: SyncSender<NetworkMsg>, ingest: SharedIngest, writer: Option<SharedCapture>) {
+    let peer = match stream.peer_addr() {
+        Ok(p)    => p,
+        Err(err) => {
+            warn!("Dropping TCP connection without a peer address: {}", err);
+            return;
+        }
+    };
+    info!("TCP connection from {}", peer);
+    if let Err(err) = stream.set_read_timeout(Some(Duration::from_secs(READ_TIMEOUT_SECS))) {
+        warn!("Unable to set read timeout for {}: {}", peer, err);
+        return;
+    }
+
+    let mut reader = BufReader::new(stream);
+    loop {
+        match read_line(&mut reader, MAX_LINE) {
+            Ok(None)                               => break,
+            Ok(Some(ref l)) if l.trim().is_empty() => continue,
+            Ok(Some(l))                            => {
+                debug!("received line from {}: {}", peer, l);
+                capture(&writer, &peer, Transport::Tcp, l.as_bytes());
+                forward(&ingest, l.trim(), &peer, Transport::Tcp, &tx);
+            },
+            Err(err)                               => {
+                warn!("Error reading from {}: {}", peer, err);
+                break;
+            }
+        }
+    }
+
+    info!("TCP connection from {} closed", peer);
+}
+
+/// Accepts line-oriented TCP connections on `bind`, one thread per connection up to `MAX_CONNECTIONS`
+pub fn bind_tcp(bind: String, tx: SyncSender<NetworkMsg>, ingest: SharedIngest, writer: Option<SharedCapture>) {
+    let listener = match TcpListener::bind(&bind) {
+        Ok(l)    => l,
+        Err(err) => {
+            error!("Unable to bind TCP listener on {}: {}", bind, err);
+            return;
+        }
+    };
+    info!("Listening for TCP connections on {}", bind);
+
+    let connections = Arc::new(AtomicUsize::new(0));
+    for stream in listener.incoming() {
+        let stream = match stream {
+            Ok(s)    => s,
+            Err(err) => {
+                warn!("Failed to accept TCP connection: {}", err);
+                continue;
+            }
+        };
+
+        if connections.fetch_add(1, Ordering::SeqCst) >= MAX_CONNECTIONS {
+            connections.fetch_sub(1, Ordering::SeqCst);
+            warn!("Refusing TCP connection from {:?}, {} already open", stream.peer_addr(), MAX_CONNECTIONS);
+            continue;
+        }
+
+        let (tx, ingest, writer, count) = (tx.clone(), ingest.clone(), writer.clone(), connections.clone());
+        let spawned = thread::Builder::new().name("TcpClient".to_string()).spawn(move || {
+            handle_tcp(stream, tx, ingest, writer);
+            count.fetch_sub(1, Ordering::SeqCst);
+        });
+        if let Err(err) = spawned {
+            connections.fetch_sub(1, Ordering::SeqCst);
+            error!("Unable to spawn TCP client thread: {}", err);
+        }
+    }
+}
+
+#[test]
+fn test_tcp_lines() {
+    use std::io::Write;
+    use std::sync::{Arc, Mutex};
+    use std::sync::mpsc::sync_channel;
+    use ingest::Ingest;
+    use message::ParserRegistry;
+
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let mut client = TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+    client.write_all(b"ESP_1: [11.06000] AC:push: Code 200\n\nESP_1: [12.00000] LOOP: 1\n").unwrap();
+    drop(client);
+
+    let (tx, rx) = sync_channel(10);
+    let ingest = Arc::new(Mutex::new(Ingest::new(Arc::new(ParserRegistry::builtin()), None, None)));
+    handle_tcp(listener.accept().unwrap().0, tx, ingest, None);
+
+    let msgs: Vec<NetworkMsg> = rx.iter().collect();
+    assert_eq!(msgs.len(), 2);
+    assert_eq!(msgs[0].raw, "ESP_1: [11.06000] AC:push: Code 200");
+    assert!(msgs.iter().all(|m| m.transport == Transport::Tcp));
+}
+
+#[test]
+fn test_read_line() {
+    use std::io::Cursor;
+
+    let mut r = Cursor::new(b"ESP_1: [11.06000] AC:push: Code 200\r\nabcdefgh\nabc".to_vec());
+    assert_eq!(read_line(&mut r, 64).unwrap(), Some(String::from("ESP_1: [11.06000] AC:push: Code 200\r")));
+    assert_eq!(read_line(&mut r, 8).unwrap(), Some(String::from("abcdefgh")));
+    assert_eq!(read_line(&mut r, 8).unwrap(), Some(String::from("abc")));
+    assert_eq!(read_line(&mut r, 8).unwrap(), None);
+
+    let mut r = Cursor::new(b"abcdefghi\n".to_vec());
+    assert_eq!(read_line(&mut r, 8).unwrap_err().kind(), io::ErrorKind::InvalidData);
+}
@@ -0,0 +1,119 @@
+use std::collections::BTreeMap;
+use std::sync::{Arc, Mutex};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use message::{MessageType, NetworkMsg};
+use metrics::SharedMetrics;
+use sink::MessageSink;
+
+/// Distinct identifiers tracked per host, later ones being counted as `other`
+const MAX_IDENTIFIERS_PER_HOST: usize = 32;
+
+/// Where identifiers past the limit, or not looking like one, are counted
+const OTHER: &'static str = "other";
+
+/// Whether `s` reads like a firmware identifier, `[A-Za-z0-9_.]{1,32}`
+fn is_identifier(s: &str) -> bool {
+    !s.is_empty() && s.len() <= 32 && s.chars().all(|c| c.is_ascii_alphanumeric() || c == '_' || c == '.')
+}
+
+/// How often an identifier nobody parses was seen, with the last line carrying it
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct UnknownIdentifier {
+    pub count:     u64,
+    pub last_seen: u64,
+    pub last_raw:  String
+}
+
+/// Unknown identifiers per host, as served on `/api/unknown`
+pub type SharedUnknown = Arc<Mutex<BTreeMap<String, BTreeMap<String, UnknownIdentifier>>>>;
+
+/// Sink counting lines the collector does not understand, to spot new firmware output
+pub struct UnknownSink {
+    seen:    SharedUnknown,
+    metrics: SharedMetrics
+}
+
+impl UnknownSink {
+    pub fn new(metrics: SharedMetrics) -> UnknownSink {
+        UnknownSink {
+            seen:    Arc::new(Mutex::new(BTreeMap::new())),
+            metrics: metrics
+        }
+    }
+
+    pub fn seen(&self) -> SharedUnknown {
+        self.seen.clone()
+    }
+
+    fn observe(&mut self, msg: &NetworkMsg, now: u64) {
+        if msg.msg.mtype != MessageType::UnknownMessage {
+            return;
+        }
+
+        let mut seen = self.seen.lock().unwrap();
+        let identifiers = seen.entry(msg.host.clone()).or_insert_with(BTreeMap::new);
+        let identifier = match msg.msg.identifier.as_str() {
+            id if is_identifier(id) && (identifiers.contains_key(id) || identifiers.len() < MAX_IDENTIFIERS_PER_HOST) => id,
+            _ => OTHER
+        };
+        self.metrics.counter_add("sensorweb_unknown_messages_total", "Messages with an identifier the collector does not parse",
+                                 &[("host", msg.host.as_str()), ("identifier", identifier)], 1.0);
+
+        let entry = identifiers.entry(String::from(identifier))
+                               .or_insert(UnknownIdentifier { count: 0, last_seen: 0, last_raw: String::new() });
+        if entry.count == 0 {
+            info!("First unknown {:?} message from {}: {}", identifier, msg.host, msg.raw);
+        }
+        entry.count += 1;
+        entry.last_seen = now;
+        entry.last_raw = msg.raw.clone();
+    }
+}
+
+impl MessageSink for UnknownSink {
+    fn name(&self) -> &str {
+        "unknown"
+    }
+
+    fn handle(&mut self, msg: &NetworkMsg) {
+        let now = SystemTime::now().duration_since(UNIX_EPOCH).map(|d| d.as_secs()).unwrap_or(0);
+        self.observe(msg, now);
+    }
+}
+
+#[test]
+fn test_unknown_sink() {
+    use message::parse_from_string;
+    use metrics::Metrics;
+
+    let metrics = Metrics::new();
+    let mut s = UnknownSink::new(metrics.clone());
+
+    s.observe(&parse_from_string(String::from("ESP_1: [12.50000] BATT: 3.71V")), 10);
+    s.observe(&parse_from_string(String::from("ESP_1: [12.50000] BATT: 3.69V")), 20);
+    s.observe(&parse_from_string(String::from("ESP_1: [12.60000] OTA: checking")), 30);
+    s.observe(&parse_from_string(String::from("ESP_1: [11.06000] AC:push: Code 200")), 40);
+
+    let seen = s.seen().lock().unwrap()["ESP_1"].clone();
+    assert_eq!(seen.len(), 2);
+    assert_eq!(seen["BATT"], UnknownIdentifier {
+        count:     2,
+        last_seen: 20,
+        last_raw:  String::from("ESP_1: [12.50000] BATT: 3.69V")
+    });
+    assert_eq!(seen["OTA"].count, 1);
+    assert!(metrics.render().contains("sensorweb_unknown_messages_total{host=\"ESP_1\",identifier=\"BATT\"} 2\n"));
+
+    // Past the limit, and for anything not reading like an identifier, lines are counted as other
+    for i in 0..MAX_IDENTIFIERS_PER_HOST {
+        s.observe(&parse_from_string(format!("ESP_2: [1.00000] X{}: 1", i)), 50);
+    }
+    s.observe(&parse_from_string(String::from("ESP_2: [1.00000] X0: 2")), 60);
+    s.observe(&parse_from_string(String::from("ESP_2: [1.00000] Y: 1")), 60);
+    s.observe(&parse_from_string(String::from("ESP_1: [1.00000] A-B\"}: 1")), 60);
+    let seen = s.seen().lock().unwrap().clone();
+    assert_eq!((seen["ESP_2"].len(), seen["ESP_2"]["X0"].count), (MAX_IDENTIFIERS_PER_HOST + 1, 2));
+    assert_eq!(seen["ESP_2"]["other"].count, 1);
+    assert_eq!(seen["ESP_1"]["other"].count, 1);
+}
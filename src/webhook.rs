@@ -0,0 +1,555 @@
+extern crate hyper;
+use self::hyper::client::Client;
+use self::hyper::header::{ContentType, Headers};
+
+use std::cmp;
+use std::collections::VecDeque;
+use std::fs::{self, File};
+use std::io::{Read, Write};
+use std::path::{Path, PathBuf};
+use std::sync::mpsc::{sync_channel, Receiver, RecvTimeoutError, SyncSender, TrySendError};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use serde_json;
+
+use auth::mac_hex;
+use message::NetworkMsg;
+use sink::MessageSink;
+
+const FLUSH_INTERVAL_SECS: u64 = 5;
+const POLL_INTERVAL_SECS:  u64 = 1;
+const MAX_BACKOFF_SECS:    u64 = 600;
+const QUEUE_SIZE:          usize = 1024;
+const MAX_QUEUED:          usize = 10000;
+const HTTP_TIMEOUT_SECS:   u64 = 30;
+
+/// Header carrying the hex HMAC-SHA256 of the body, when a secret is configured
+pub const SIGNATURE_HEADER: &'static str = "X-Sensorweb-Signature";
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Op {
+    Eq,
+    Ne,
+    Lt,
+    Le,
+    Gt,
+    Ge
+}
+
+#[derive(Debug, Clone, PartialEq)]
+struct Predicate {
+    field: String,
+    op:    Op,
+    value: String
+}
+
+impl Predicate {
+    /// Parses `field op value`, e.g. `pm2.5 > 20` or `command = push`
+    fn parse(s: &str) -> Result<Predicate, String> {
+        let parts: Vec<&str> = s.trim().splitn(3, ' ').map(|p| p.trim()).collect();
+        if parts.len() != 3 {
+            return Err(format!("bad predicate {:?}", s));
+        }
+
+        let op = match parts[1] {
+            "="  | "==" => Op::Eq,
+            "!="        => Op::Ne,
+            "<"         => Op::Lt,
+            "<="        => Op::Le,
+            ">"         => Op::Gt,
+            ">="        => Op::Ge,
+            op          => return Err(format!("unknown operator {:?}", op))
+        };
+
+        Ok(Predicate {
+            field: String::from(parts[0]),
+            op:    op,
+            value: String::from(parts[2])
+        })
+    }
+
+    fn matches(&self, msg: &NetworkMsg) -> bool {
+        let actual = match msg.msg.mvals.get(&self.field).or(msg.annotations.get(&self.field)) {
+            Some(v) => v.trim(),
+            None    => return false
+        };
+
+        // Compare numerically whenever both sides are numbers, as strings otherwise
+        match (actual.parse::<f64>(), self.value.parse::<f64>()) {
+            (Ok(a), Ok(b)) => match self.op {
+                Op::Eq => a == b,
+                Op::Ne => a != b,
+                Op::Lt => a < b,
+                Op::Le => a <= b,
+                Op::Gt => a > b,
+                Op::Ge => a >= b
+            },
+            _ => match self.op {
+                Op::Eq => actual == self.value,
+                Op::Ne => actual != self.value,
+                _      => false
+            }
+        }
+    }
+}
+
+/// Selects messages by host, type name and field predicates, an empty list matching anything
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Filter {
+    hosts:      Vec<String>,
+    types:      Vec<String>,
+    predicates: Vec<Predicate>
+}
+
+impl Filter {
+    pub fn matches(&self, msg: &NetworkMsg) -> bool {
+        (self.hosts.is_empty() || self.hosts.iter().any(|h| *h == msg.host))
+            && (self.types.is_empty() || self.types.iter().any(|t| t == msg.msg.mtype.name()))
+            && self.predicates.iter().all(|p| p.matches(msg))
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct WebhookConfig {
+    pub name:    String,
+    pub urls:    Vec<String>,
+    pub headers: Vec<(String, String)>,
+    pub secret:  Option<String>,
+    pub batch:   usize,
+    pub retries: u32,
+    pub queue:   Option<PathBuf>,
+    pub filter:  Filter
+}
+
+impl WebhookConfig {
+    fn new(name: &str) -> WebhookConfig {
+        WebhookConfig {
+            name:    String::from(name),
+            urls:    Vec::new(),
+            headers: Vec::new(),
+            secret:  None,
+            batch:   1,
+            retries: 5,
+            queue:   None,
+            filter:  Filter::default()
+        }
+    }
+
+    /// Parses `[name]` sections of `key = value` lines, `url`, `header`, `host`, `type` and `where` being repeatable
+    pub fn parse(s: &str) -> Result<Vec<WebhookConfig>, String> {
+        let mut rv: Vec<WebhookConfig> = Vec::new();
+
+        for (lineno, raw) in s.lines().enumerate() {
+            let line = raw.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+
+            if line.starts_with('[') && line.ends_with(']') {
+                rv.push(WebhookConfig::new(line[1..line.len() - 1].trim()));
+                continue;
+            }
+
+            let eq = line.find('=').ok_or(format!("line {}: expected key = value", lineno + 1))?;
+            let (key, value) = (line[..eq].trim(), line[eq + 1..].trim());
+            let config = rv.last_mut().ok_or(format!("line {}: {} outside of a [webhook] section", lineno + 1, key))?;
+
+            match key {
+                "url"     => config.urls.push(String::from(value)),
+                "header"  => {
+                    let colon = value.find(':').ok_or(format!("line {}: expected Name: value header", lineno + 1))?;
+                    config.headers.push((String::from(value[..colon].trim()), String::from(value[colon + 1..].trim())));
+                },
+                "secret"  => config.secret = Some(String::from(value)),
+                "batch"   => config.batch = cmp::max(1, value.parse::<usize>().map_err(|e| format!("line {}: {}", lineno + 1, e))?),
+                "retries" => config.retries = value.parse::<u32>().map_err(|e| format!("line {}: {}", lineno + 1, e))?,
+                "queue"   => config.queue = Some(PathBuf::from(value)),
+                "host"    => config.filter.hosts.push(String::from(value)),
+                "type"    => config.filter.types.push(value.to_lowercase()),
+                "where"   => config.filter.predicates.push(Predicate::parse(value).map_err(|e| format!("line {}: {}", lineno + 1, e))?),
+                key       => return Err(format!("line {}: unknown key {:?}", lineno + 1, key))
+            }
+        }
+
+        for config in rv.iter() {
+            if config.urls.is_empty() {
+                return Err(format!("[{}]: no url", config.name));
+            }
+        }
+
+        Ok(rv)
+    }
+
+    pub fn load(path: &Path) -> Result<Vec<WebhookConfig>, String> {
+        let mut contents = String::new();
+        File::open(path).and_then(|mut f| f.read_to_string(&mut contents))
+                        .map_err(|e| format!("{:?}: {}", path, e))?;
+        WebhookConfig::parse(&contents).map_err(|e| format!("{:?}: {}", path, e))
+    }
+}
+
+pub fn signature(secret: &str, body: &str) -> String {
+    format!("sha256={}", mac_hex(secret, body))
+}
+
+fn post(client: &Client, url: &str, headers: &[(String, String)], secret: &Option<String>, body: &str) -> Result<(), String> {
+    let mut req_headers = Headers::new();
+    req_headers.set(ContentType::json());
+    for &(ref name, ref value) in headers {
+        req_headers.set_raw(name.clone(), vec![value.clone().into_bytes()]);
+    }
+    if let Some(ref secret) = *secret {
+        req_headers.set_raw(SIGNATURE_HEADER, vec![signature(secret, body).into_bytes()]);
+    }
+
+    match client.post(url).headers(req_headers).body(body).send() {
+        Ok(ref res) if res.status.is_success() => Ok(()),
+        Ok(res)  => Err(format!("{} answered {}", url, res.status)),
+        Err(err) => Err(format!("{}: {}", url, err))
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+struct Delivery {
+    url:          String,
+    body:         String,
+    attempts:     u32,
+    next_attempt: Instant
+}
+
+fn backoff(attempts: u32) -> Duration {
+    Duration::from_secs(cmp::min(2u64.pow(cmp::min(attempts, 16)), MAX_BACKOFF_SECS))
+}
+
+/// Deliveries still to be made, mirrored to a file when configured so they survive restarts
+#[derive(Debug)]
+struct RetryQueue {
+    path:       Option<PathBuf>,
+    deliveries: VecDeque<Delivery>
+}
+
+impl RetryQueue {
+    /// Restores the deliveries a previous run left, stored as `attempts\turl\tbody` lines
+    fn open(path: Option<PathBuf>) -> RetryQueue {
+        let mut deliveries = VecDeque::new();
+
+        if let Some(ref p) = path {
+            let mut contents = String::new();
+            if File::open(p).and_then(|mut f| f.read_to_string(&mut contents)).is_ok() {
+                for l in contents.lines() {
+                    let fields: Vec<&str> = l.splitn(3, '\t').collect();
+                    if fields.len() != 3 {
+                        warn!("Ignoring malformed webhook queue entry in {:?}", p);
+                        continue;
+                    }
+                    deliveries.push_back(Delivery {
+                        url:          String::from(fields[1]),
+                        body:         String::from(fields[2]),
+                        attempts:     fields[0].parse::<u32>().unwrap_or(0),
+                        next_attempt: Instant::now()
+                    });
+                }
+                info!("Restored {} webhook deliveries from {:?}", deliveries.len(), p);
+            }
+        }
+
+        RetryQueue {
+            path:       path,
+            deliveries: deliveries
+        }
+    }
+
+    /// Writes the queue next to its path then renames it over, so a crash never leaves it half written
+    fn persist(&self) {
+        if let Some(ref p) = self.path {
+            let mut buf = String::new();
+            for d in self.deliveries.iter() {
+                // Bodies are compact JSON, which never holds a raw newline nor tab
+                buf.push_str(&format!("{}\t{}\t{}\n", d.attempts, d.url, d.body));
+            }
+            let mut tmp = p.clone().into_os_string();
+            tmp.push(".tmp");
+            let tmp = PathBuf::from(tmp);
+            let written = File::create(&tmp)
+                .and_then(|mut f| f.write_all(buf.as_bytes()).and_then(|_| f.sync_all()))
+                .and_then(|_| fs::rename(&tmp, p));
+            if let Err(err) = written {
+                error!("Unable to write webhook queue {:?}: {}", p, err);
+            }
+        }
+    }
+
+    fn push(&mut self, url: &str, body: &str) {
+        self.deliveries.push_back(Delivery {
+            url:          String::from(url),
+            body:         String::from(body),
+            attempts:     0,
+            next_attempt: Instant::now()
+        });
+
+        if self.deliveries.len() > MAX_QUEUED {
+            warn!("Webhook queue full, dropping oldest delivery");
+            self.deliveries.pop_front();
+        }
+    }
+
+    /// Attempts every due delivery, giving up on those failing more than `retries` times
+    fn process<F>(&mut self, now: Instant, retries: u32, mut send: F)
+        where F: FnMut(&Delivery) -> Result<(), String> {
+        let mut changed = false;
+        let mut kept = VecDeque::new();
+        while let Some(mut d) = self.deliveries.pop_front() {
+            if d.next_attempt > now {
+                kept.push_back(d);
+                continue;
+            }
+
+            changed = true;
+            match send(&d) {
+                Ok(_)    => debug!("Delivered webhook to {}", d.url),
+                Err(err) => {
+                    d.attempts += 1;
+                    if d.attempts > retries {
+                        error!("Giving up on webhook delivery to {} after {} attempts: {}", d.url, d.attempts, err);
+                    } else {
+                        let delay = backoff(d.attempts);
+                        warn!("Webhook delivery failed: {}, retrying in {:?}", err, delay);
+                        d.next_attempt = now + delay;
+                        kept.push_back(d);
+                    }
+                }
+            }
+        }
+        self.deliveries = kept;
+
+        if changed {
+            self.persist();
+        }
+    }
+}
+
+/// Payload for buffered messages, an array once batching is enabled
+fn payload(messages: &[String], batch: usize) -> String {
+    if batch == 1 && messages.len() == 1 {
+        messages[0].clone()
+    } else {
+        format!("[{}]", messages.join(","))
+    }
+}
+
+fn th_webhook_sender(config: WebhookConfig, rx: Receiver<String>) {
+    info!("Webhook {} sender thread started: {:?}", config.name, config.urls);
+
+    let mut client = Client::new();
+    client.set_read_timeout(Some(Duration::from_secs(HTTP_TIMEOUT_SECS)));
+    client.set_write_timeout(Some(Duration::from_secs(HTTP_TIMEOUT_SECS)));
+    let mut queue = RetryQueue::open(config.queue.clone());
+    let interval = Duration::from_secs(FLUSH_INTERVAL_SECS);
+    let mut buffered: Vec<String> = Vec::new();
+    let mut last_flush = Instant::now();
+
+    loop {
+        let disconnected = match rx.recv_timeout(Duration::from_secs(POLL_INTERVAL_SECS)) {
+            Ok(m) => {
+                buffered.push(m);
+                false
+            },
+            Err(RecvTimeoutError::Timeout)      => false,
+            Err(RecvTimeoutError::Disconnected) => true
+        };
+
+        if !buffered.is_empty() && (disconnected || buffered.len() >= config.batch || last_flush.elapsed() >= interval) {
+            let body = payload(&buffered, config.batch);
+            for url in config.urls.iter() {
+                queue.push(url, &body);
+            }
+            buffered.clear();
+            last_flush = Instant::now();
+        }
+
+        queue.process(Instant::now(), config.retries, |d| post(&client, &d.url, &config.headers, &config.secret, &d.body));
+
+        if disconnected {
+            break;
+        }
+    }
+}
+
+/// Sink POSTing matching messages as JSON to the configured URLs
+pub struct WebhookSink {
+    name:   String,
+    filter: Filter,
+    tx:     SyncSender<String>
+}
+
+impl WebhookSink {
+    pub fn new(config: WebhookConfig) -> WebhookSink {
+        let (tx, rx) = sync_channel(QUEUE_SIZE);
+        let name = config.name.clone();
+        let filter = config.filter.clone();
+
+        let _ = thread::Builder::new().name(format!("Webhook-{}", name)).spawn(move || {
+            th_webhook_sender(config, rx);
+        });
+
+        WebhookSink {
+            name:   name,
+            filter: filter,
+            tx:     tx
+        }
+    }
+}
+
+impl MessageSink for WebhookSink {
+    fn name(&self) -> &str {
+        &self.name
+    }
+
+    fn handle(&mut self, msg: &NetworkMsg) {
+        if !self.filter.matches(msg) {
+            return;
+        }
+
+        match serde_json::to_string(msg) {
+            Ok(json) => match self.tx.try_send(json) {
+                Ok(_)                              => {},
+                Err(TrySendError::Full(_))         => warn!("Webhook {} queue full, dropping message", self.name),
+                Err(TrySendError::Disconnected(_)) => error!("Webhook {} sender is gone, dropping message", self.name)
+            },
+            Err(err) => error!("Unable to serialize message: {}", err)
+        }
+    }
+}
+
+#[test]
+fn test_parse_config() {
+    let configs = WebhookConfig::parse("# Bridges\n\
+                                        [pm]\n\
+                                        url = http://localhost:9000/hook\n\
+                                        url = http://localhost:9001/hook\n\
+                                        header = Authorization: Bearer abc\n\
+                                        secret = s3cr3t\n\
+                                        batch = 10\n\
+                                        queue = /tmp/pm.queue\n\
+                                        type = NTP\n\
+                                        where = pm2.5 > 20\n\
+                                        [all]\n\
+                                        url = http://localhost:9002/\n").unwrap();
+
+    assert_eq!(configs.len(), 2);
+    assert_eq!(configs[0].urls.len(), 2);
+    assert_eq!(configs[0].headers, vec![(String::from("Authorization"), String::from("Bearer abc"))]);
+    assert_eq!(configs[0].secret, Some(String::from("s3cr3t")));
+    assert_eq!(configs[0].batch, 10);
+    assert_eq!(configs[0].queue, Some(PathBuf::from("/tmp/pm.queue")));
+    assert_eq!(configs[0].filter.types, vec![String::from("ntp")]);
+    assert_eq!(configs[1], WebhookConfig { urls: vec![String::from("http://localhost:9002/")], ..WebhookConfig::new("all") });
+
+    assert!(WebhookConfig::parse("url = http://localhost/").is_err());
+    assert!(WebhookConfig::parse("[x]\nsecret = abc").is_err());
+    assert!(WebhookConfig::parse("[x]\nurl = http://localhost/\nwhere = pm2.5 ~ 3").is_err());
+}
+
+#[test]
+fn test_filter() {
+    use message::parse_from_string;
+
+    let ntp = parse_from_string(String::from("ESP_D427A9: [11.06500] NTP: 2017-05-26T15:27:53.000+01:00 PM2.5: 25 UUID:abc sent:1"));
+    let ac = parse_from_string(String::from("ESP_D427A9: [11.06000] AC:push: Code 500"));
+
+    assert!(Filter::default().matches(&ntp));
+
+    let high = Filter { types: vec![String::from("ntp")], predicates: vec![Predicate::parse("pm2.5 > 20").unwrap()], ..Filter::default() };
+    assert!(high.matches(&ntp));
+    assert!(!high.matches(&ac));
+
+    let failed = Filter { predicates: vec![Predicate::parse("command = push").unwrap(), Predicate::parse("http_code >= 300").unwrap()], ..Filter::default() };
+    assert!(failed.matches(&ac));
+    assert!(!failed.matches(&ntp));
+
+    let other = Filter { hosts: vec![String::from("ESP_000000")], ..Filter::default() };
+    assert!(!other.matches(&ntp));
+}
+
+#[test]
+fn test_retry_queue() {
+    use std::env;
+    use std::process;
+
+    let path = env::temp_dir().join(format!("webhook-test-{}.queue", process::id()));
+    let _ = fs::remove_file(&path);
+
+    let mut q = RetryQueue::open(Some(path.clone()));
+    q.push("http://a/", "{\"n\":1}");
+    q.push("http://b/", "{\"n\":2}");
+
+    // a fails once, b keeps failing and gets dropped after one retry
+    let mut calls = Vec::new();
+    let now = Instant::now();
+    q.process(now, 1, |d| { calls.push(d.url.clone()); Err(String::from("down")) });
+    assert_eq!(calls.len(), 2);
+    assert_eq!(q.deliveries.len(), 2);
+
+    // Nothing is due before the backoff expires, and the queue survives a restart
+    q.process(now, 1, |_| panic!("not due"));
+    let mut restored = RetryQueue::open(Some(path.clone()));
+    assert_eq!(restored.deliveries.iter().map(|d| (d.attempts, d.body.as_str())).collect::<Vec<_>>(),
+               vec![(1, "{\"n\":1}"), (1, "{\"n\":2}")]);
+
+    restored.process(Instant::now(), 1, |d| if d.url == "http://a/" { Ok(()) } else { Err(String::from("down")) });
+    assert!(restored.deliveries.is_empty());
+    assert_eq!(RetryQueue::open(Some(path.clone())).deliveries.len(), 0);
+    assert!(!restored.path.as_ref().map(|p| p.with_extension("queue.tmp").exists()).unwrap());
+
+    fs::remove_file(&path).unwrap();
+}
+
+#[test]
+fn test_payload() {
+    let m = vec![String::from("{\"a\":1}"), String::from("{\"b\":2}")];
+    assert_eq!(payload(&m[..1], 1), "{\"a\":1}");
+    assert_eq!(payload(&m[..1], 10), "[{\"a\":1}]");
+    assert_eq!(payload(&m, 10), "[{\"a\":1},{\"b\":2}]");
+}
+
+#[test]
+fn test_post_to_stub() {
+    use std::io::{BufRead, BufReader};
+    use std::net::TcpListener;
+
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let url = format!("http://{}/hook", listener.local_addr().unwrap());
+
+    let stub = thread::spawn(move || {
+        let (stream, _) = listener.accept().unwrap();
+        let mut reader = BufReader::new(stream);
+        let mut headers = Vec::new();
+        let mut content_length = 0;
+        loop {
+            let mut header = String::new();
+            reader.read_line(&mut header).unwrap();
+            if header.trim().is_empty() {
+                break;
+            }
+            if header.to_lowercase().starts_with("content-length:") {
+                content_length = header[15..].trim().parse::<usize>().unwrap();
+            }
+            headers.push(String::from(header.trim()));
+        }
+
+        let mut body = vec![0; content_length];
+        reader.read_exact(&mut body).unwrap();
+        reader.get_mut().write_all(b"HTTP/1.1 204 No Content\r\nContent-Length: 0\r\nConnection: close\r\n\r\n").unwrap();
+        (headers, String::from_utf8(body).unwrap())
+    });
+
+    let headers = vec![(String::from("X-Token"), String::from("abc"))];
+    let body = "{\"host\":\"ESP_D427A9\"}";
+    assert_eq!(post(&Client::new(), &url, &headers, &Some(String::from("s3cr3t")), body), Ok(()));
+
+    let (received, received_body) = stub.join().unwrap();
+    assert_eq!(received_body, body);
+    assert!(received.contains(&String::from("X-Token: abc")));
+    assert!(received.contains(&format!("{}: {}", SIGNATURE_HEADER, signature("s3cr3t", body))));
+}
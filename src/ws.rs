@@ -1,20 +1,80 @@
 extern crate websocket;
 
 use std::thread;
+use std::sync::{Arc, Mutex};
+use std::sync::mpsc::{sync_channel, SyncSender, TrySendError};
+use std::time::Duration;
 use self::websocket::OwnedMessage;
 use self::websocket::sync::Server;
 
-fn ws_handler() {
+use serde_json;
 
+use message::NetworkMsg;
+use sink::MessageSink;
+
+/// Messages queued for a client before it is deemed too slow and dropped
+const CLIENT_QUEUE: usize = 256;
+
+/// Time a client may take to accept a message before its connection is closed
+const WRITE_TIMEOUT_SECS: u64 = 10;
+
+/// Per-connection queues of JSON messages waiting to be pushed to clients
+pub type WsClients = Arc<Mutex<Vec<SyncSender<String>>>>;
+
+/// Sink broadcasting every parsed message, as JSON, to all WebSocket clients
+pub struct WsFeed {
+    clients: WsClients
+}
+
+impl WsFeed {
+    pub fn new() -> WsFeed {
+        WsFeed {
+            clients: Arc::new(Mutex::new(Vec::new()))
+        }
+    }
+
+    pub fn clients(&self) -> WsClients {
+        self.clients.clone()
+    }
 }
 
-pub fn th_ws_listener(ws_bind: String) {
+impl MessageSink for WsFeed {
+    fn name(&self) -> &str {
+        "websocket"
+    }
+
+    fn handle(&mut self, msg: &NetworkMsg) {
+        let json = match serde_json::to_string(msg) {
+            Ok(json) => json,
+            Err(err) => {
+                error!("Unable to serialize message {:?}: {}", msg, err);
+                return;
+            }
+        };
+
+        let mut clients = self.clients.lock().unwrap();
+        // Dropping the receiving end is how a connection signals it is gone, dropping the sending
+        // end how a client not reading its messages gets disconnected
+        clients.retain(|client| match client.try_send(json.clone()) {
+            Ok(_)                              => true,
+            Err(TrySendError::Full(_))         => {
+                warn!("Dropping WebSocket client with {} messages pending", CLIENT_QUEUE);
+                false
+            },
+            Err(TrySendError::Disconnected(_)) => false
+        });
+        debug!("Broadcast message to {} WebSocket clients", clients.len());
+    }
+}
+
+pub fn th_ws_listener(ws_bind: String, clients: WsClients) {
     info!("WebSocket thread started: {}", ws_bind);
 
 	let server = Server::bind(ws_bind).unwrap();
 
 	for request in server.filter_map(Result::ok) {
         debug!("Accepted one connection!");
+        let clients = clients.clone();
 		// Spawn a new thread for each connection.
 		thread::spawn(move || {
             debug!("Checking protocol");
@@ -24,34 +84,60 @@ pub fn th_ws_listener(ws_bind: String) {
 				return;
 			}
 
-			let mut client = request.use_protocol("rust-websocket").accept().unwrap();
+			let client = request.use_protocol("rust-websocket").accept().unwrap();
 
 			let ip = client.peer_addr().unwrap();
 
-			println!("Connection from {}", ip);
+			info!("Connection from {}", ip);
+            if let Err(err) = client.stream_ref().set_write_timeout(Some(Duration::from_secs(WRITE_TIMEOUT_SECS))) {
+                warn!("Unable to set write timeout for {}: {}", ip, err);
+                return;
+            }
+
+			let (mut receiver, sender) = client.split().unwrap();
+            let sender = Arc::new(Mutex::new(sender));
 
-			let message = OwnedMessage::Text("Hello".to_string());
-			client.send_message(&message).unwrap();
+            let (feed_tx, feed_rx) = sync_channel(CLIENT_QUEUE);
+            clients.lock().unwrap().push(feed_tx);
 
-			let (mut receiver, mut sender) = client.split().unwrap();
+            let feed_sender = sender.clone();
+            thread::spawn(move || {
+                for json in feed_rx.iter() {
+                    let message = OwnedMessage::Text(json);
+                    if let Err(err) = feed_sender.lock().unwrap().send_message(&message) {
+                        debug!("Stop feeding client {}: {:?}", ip, err);
+                        break;
+                    }
+                }
+                // Also ends the receiving loop, when the feed gave up on the client
+                let _ = feed_sender.lock().unwrap().shutdown_all();
+            });
 
 			for message in receiver.incoming_messages() {
-				let message = message.unwrap();
+				let message = match message {
+                    Ok(m)    => m,
+                    Err(err) => {
+                        debug!("Client {} receive error: {:?}", ip, err);
+                        break;
+                    }
+                };
 
 				match message {
 					OwnedMessage::Close(_) => {
 						let message = OwnedMessage::Close(None);
-						sender.send_message(&message).unwrap();
-						println!("Client {} disconnected", ip);
-						return;
+						let _ = sender.lock().unwrap().send_message(&message);
+						info!("Client {} disconnected", ip);
+						break;
 					}
 					OwnedMessage::Ping(ping) => {
 						let message = OwnedMessage::Pong(ping);
-						sender.send_message(&message).unwrap();
+						let _ = sender.lock().unwrap().send_message(&message);
 					}
-					_ => sender.send_message(&message).unwrap(),
+					_ => debug!("Ignoring message from client {}: {:?}", ip, message),
 				}
 			}
+
+            let _ = sender.lock().unwrap().shutdown_all();
 		});
 	}
 }